@@ -47,23 +47,40 @@ in {
 
     users.groups.wol-libvirt-gateway = {};
 
+    # systemd binds the WOL port and passes the socket to the service
+    systemd.sockets.wol-libvirt-gateway = {
+      description = "${description} socket";
+      wantedBy = ["sockets.target"];
+
+      socketConfig = {
+        ListenDatagram = cfg.address;
+        IPAddressDeny = "any";
+        IPAddressAllow = cfg.allowedSubnets;
+      };
+    };
+
     systemd.services.wol-libvirt-gateway = {
       inherit description;
       documentation = [cargoToml.package.repository];
 
-      after = ["network.target" "libvirtd.service"];
+      # Started by the socket on the first packet
+      after = ["network.target" "libvirtd.service" "wol-libvirt-gateway.socket"];
       wants = ["libvirtd.service"];
+      requires = ["wol-libvirt-gateway.socket"];
       bindsTo = ["libvirtd.service"];
 
       serviceConfig = {
-        Type = "simple";
-        ExecStart = "${cfg.package}/bin/wol-libvirt-gateway --address ${cfg.address} --libvirt-uri ${cfg.libvirtUri}";
+        Type = "notify";
+        NotifyAccess = "main";
+        ExecStart = "${cfg.package}/bin/wol-libvirt-gateway --libvirt-uri ${cfg.libvirtUri}";
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         Restart = "always";
         RestartSec = "5s";
+        RestartPreventExitStatus = "66 78";
+        WatchdogSec = "30s";
 
         ReadWritePaths = ["/var/run/libvirt/libvirt-sock" "/var/run/libvirt/libvirt-sock-ro"];
-        CapabilityBoundingSet = ["CAP_NET_BIND_SERVICE"];
-        AmbientCapabilities = ["CAP_NET_BIND_SERVICE"];
+        CapabilityBoundingSet = [""];
 
         # Security hardening
         User = "wol-libvirt-gateway";
@@ -0,0 +1,523 @@
+//! Optional HTTP API to wake VMs and query their state.
+//!
+//! Requests are handed to the processing loop like received packets, so they
+//! pass the same source filters, rate limits and wake policy before the libvirt
+//! worker carries them out. Responses are JSON, and errors carry the name of the
+//! `WolGatewayError` variant along with its message:
+//!
+//! ```text
+//! POST /wake/{mac}             Wake the VM owning a MAC address
+//! POST /domains/{name}/start   Wake a domain by name or UUID
+//! GET  /domains/{name}/state   Report the state of a domain
+//! ```
+//!
+//! Path segments are percent-decoded, so domain names containing reserved
+//! characters can be addressed. Wake requests may carry a SecureOn password as `{"password": "..."}`, and an
+//! authentication token as `{"client": "...", "timestamp": ..., "nonce": "...",
+//! "hmac": "..."}`, which is verified by the processing loop. State requests have
+//! no body and carry their token in the `X-Wol-Client`, `X-Wol-Timestamp`,
+//! `X-Wol-Nonce` and `X-Wol-Hmac` headers instead.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use log::{debug, error};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+use crate::auth::WakeToken;
+use crate::error::WolGatewayError;
+use crate::libvirt::{ResolvedVm, VmStatus};
+use crate::policy::{WakeRequest, WakeTarget};
+use crate::server::{PacketSource, Received};
+use crate::wakeonlan::{
+    mac_to_string, parse_mac_address_string, parse_secureon_password_string, SecureOnPassword,
+};
+use crate::worker::Reply;
+
+/// Maximum size of a request body.
+const MAX_BODY_SIZE: usize = 1024;
+
+/// Time a client has to send the headers of a request, which also bounds how
+/// long an idle connection is kept open.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of connections served at once, further clients wait in the
+/// listen backlog until one is closed.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Headers carrying the authentication token of a state request, in the order
+/// client, timestamp, nonce and HMAC.
+const TOKEN_HEADERS: [&str; 4] = [
+    "x-wol-client",
+    "x-wol-timestamp",
+    "x-wol-nonce",
+    "x-wol-hmac",
+];
+
+/// A request received over the HTTP API, waiting to be processed.
+#[derive(Debug)]
+pub(crate) enum ApiRequest {
+    /// Wake a VM.
+    Wake {
+        /// The wake request, including where it came from.
+        request: WakeRequest,
+        /// The token authenticating the request, if any.
+        token: Option<WakeToken>,
+        /// Where to send the VM that was woken.
+        reply: Reply<Vec<ResolvedVm>>,
+    },
+    /// Report the state of a VM.
+    Status {
+        /// The name or UUID of the domain.
+        domain: String,
+        /// Where the request was received from.
+        source: PacketSource,
+        /// The token authenticating the request, if any.
+        token: Option<WakeToken>,
+        /// Where to send the state of the VM.
+        reply: Reply<VmStatus>,
+    },
+}
+
+impl ApiRequest {
+    /// Returns where the request was received from.
+    pub(crate) fn source(&self) -> &PacketSource {
+        match self {
+            ApiRequest::Wake { request, .. } => &request.source,
+            ApiRequest::Status { source, .. } => source,
+        }
+    }
+
+    /// Answers the request with an error instead of processing it.
+    pub(crate) fn reject(self, error: WolGatewayError) {
+        // The receiver is gone if the client has already disconnected
+        match self {
+            ApiRequest::Wake { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            ApiRequest::Status { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+}
+
+/// The optional body of a wake request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WakeBody {
+    /// SecureOn password in the format "xx:xx:xx:xx:xx:xx" or "a.b.c.d".
+    password: Option<String>,
+    /// Name of the client whose key signed the request.
+    client: Option<String>,
+    /// When the request was signed, in seconds since the Unix epoch.
+    timestamp: Option<u64>,
+    /// Random value making the token unique.
+    nonce: Option<String>,
+    /// Hex encoded HMAC-SHA256 over the target, timestamp and nonce.
+    hmac: Option<String>,
+}
+
+/// Binds the API listener.
+///
+/// # Arguments
+///
+/// * `address` - The address and port to serve the API on
+///
+/// # Errors
+///
+/// Returns `SocketBindError` if the address could not be bound.
+pub(crate) async fn bind(address: SocketAddr) -> Result<TcpListener, WolGatewayError> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| WolGatewayError::SocketBindError(address, e))
+}
+
+/// Serves the API until accepting connections fails.
+///
+/// Connections are served by tasks owned by this one, so aborting it closes all
+/// of them along with their channels to the processing loop. At most
+/// `MAX_CONNECTIONS` are served at once, and clients that are slow to send a
+/// request are disconnected after `HEADER_READ_TIMEOUT`.
+///
+/// # Arguments
+///
+/// * `listener` - The bound API listener
+/// * `tx` - Channel to the processing loop
+pub(crate) async fn serve(listener: TcpListener, tx: mpsc::Sender<Received>) {
+    let mut connections = JoinSet::new();
+
+    loop {
+        // Leave further clients in the backlog while all connections are in use
+        let accepting = connections.len() < MAX_CONNECTIONS;
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept(), if accepting => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Critical API listener error: {}", e);
+                    return;
+                }
+            },
+            // Reap finished connections
+            Some(_) = connections.join_next() => continue,
+        };
+
+        let tx = tx.clone();
+        connections.spawn(async move {
+            let service = service_fn(move |request| handle_request(request, peer, tx.clone()));
+            let connection = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT)
+                .serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = connection.await {
+                debug!("API connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Routes a single HTTP request to the endpoint it addresses.
+async fn handle_request(
+    request: Request<Incoming>,
+    peer: SocketAddr,
+    tx: mpsc::Sender<Received>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = request.uri().path().to_string();
+    let segments = match path
+        .trim_start_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect::<Result<Vec<String>, _>>()
+    {
+        Ok(segments) => segments,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let source = PacketSource::Http(peer);
+    let method = request.method().clone();
+
+    let response = match segments.as_slice() {
+        ["wake", mac] if method == Method::POST => match parse_mac_address_string(mac) {
+            Ok(mac) => wake(request, WakeTarget::Mac(mac_to_string(&mac)), source, &tx).await,
+            Err(e) => error_response(&e),
+        },
+        ["domains", domain, "start"] if method == Method::POST => {
+            let target = WakeTarget::Domain(domain.to_string());
+            wake(request, target, source, &tx).await
+        }
+        ["domains", domain, "state"] if method == Method::GET || method == Method::HEAD => {
+            status(&request, domain.to_string(), source, &tx).await
+        }
+        ["wake", _] | ["domains", _, "start"] => method_not_allowed("POST"),
+        ["domains", _, "state"] => method_not_allowed("GET, HEAD"),
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            json!({
+                "error": "NotFound",
+                "message": format!("No such endpoint: {}", path),
+            }),
+        ),
+    };
+
+    Ok(response)
+}
+
+/// Decodes the percent-encoded characters of a path segment.
+///
+/// # Errors
+///
+/// Returns `InvalidApiRequest` if an escape is malformed or the decoded segment
+/// is not valid UTF-8.
+fn percent_decode(segment: &str) -> Result<String, WolGatewayError> {
+    let invalid =
+        || WolGatewayError::InvalidApiRequest(format!("Invalid path segment: {}", segment));
+
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let escape = tail.get(..2).ok_or_else(invalid)?;
+            decoded.extend(hex::decode(escape).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+/// Wakes a VM and reports which one was woken.
+async fn wake(
+    request: Request<Incoming>,
+    target: WakeTarget,
+    source: PacketSource,
+    tx: &mpsc::Sender<Received>,
+) -> Response<Full<Bytes>> {
+    let (password, token) = match read_wake_body(request).await {
+        Ok(body) => body,
+        Err(e) => return error_response(&e),
+    };
+
+    let (reply, result) = oneshot::channel();
+    let request = ApiRequest::Wake {
+        request: WakeRequest {
+            target,
+            password,
+            source,
+        },
+        token,
+        reply,
+    };
+
+    match submit(tx, request, result).await {
+        Ok(vms) => {
+            let vms: Vec<Value> = vms
+                .iter()
+                .map(|vm| {
+                    json!({
+                        "name": vm.name,
+                        "uuid": vm.uuid.to_string(),
+                        "state": vm.state.to_string(),
+                        "action": vm.action.to_string(),
+                    })
+                })
+                .collect();
+
+            // Several VMs are only woken if they share a MAC address, so the
+            // first one is also described at the top level
+            let mut body = vms.first().cloned().unwrap_or_else(|| json!({}));
+            body["vms"] = Value::Array(vms);
+            json_response(StatusCode::OK, body)
+        }
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Reports the state of a VM.
+async fn status(
+    request: &Request<Incoming>,
+    domain: String,
+    source: PacketSource,
+    tx: &mpsc::Sender<Received>,
+) -> Response<Full<Bytes>> {
+    let token = match read_token_headers(request.headers()) {
+        Ok(token) => token,
+        Err(e) => return error_response(&e),
+    };
+
+    let (reply, result) = oneshot::channel();
+    let request = ApiRequest::Status {
+        domain,
+        source,
+        token,
+        reply,
+    };
+
+    match submit(tx, request, result).await {
+        Ok(vm) => json_response(
+            StatusCode::OK,
+            json!({
+                "name": vm.name,
+                "uuid": vm.uuid.to_string(),
+                "state": vm.state.to_string(),
+                "reason": vm.reason.to_string(),
+            }),
+        ),
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Hands a request to the processing loop and waits for its result.
+///
+/// # Errors
+///
+/// Returns the error the request failed with, or `WorkerStopped` if the gateway
+/// shut down before answering it.
+async fn submit<T>(
+    tx: &mpsc::Sender<Received>,
+    request: ApiRequest,
+    result: oneshot::Receiver<Result<T, WolGatewayError>>,
+) -> Result<T, WolGatewayError> {
+    if tx.send(Received::Api(request)).await.is_err() {
+        return Err(WolGatewayError::WorkerStopped);
+    }
+
+    // Requests still queued on shutdown are dropped without an answer
+    result.await.unwrap_or(Err(WolGatewayError::WorkerStopped))
+}
+
+/// Reads the SecureOn password and authentication token from the body of a
+/// wake request, if any.
+///
+/// # Errors
+///
+/// Returns `InvalidApiRequest` if the body is too large, not a valid JSON object
+/// or carries an incomplete token, or `WakeOnLanParseError` if the password is
+/// malformed.
+async fn read_wake_body(
+    request: Request<Incoming>,
+) -> Result<(Option<SecureOnPassword>, Option<WakeToken>), WolGatewayError> {
+    let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|e| WolGatewayError::InvalidApiRequest(format!("Failed to read body: {}", e)))?
+        .to_bytes();
+
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok((None, None));
+    }
+
+    let body: WakeBody = serde_json::from_slice(&body)
+        .map_err(|e| WolGatewayError::InvalidApiRequest(format!("Invalid body: {}", e)))?;
+
+    let password = body
+        .password
+        .as_deref()
+        .map(parse_secureon_password_string)
+        .transpose()?;
+
+    let token = match (body.client, body.timestamp, body.nonce, body.hmac) {
+        (None, None, None, None) => None,
+        (Some(client), Some(timestamp), Some(nonce), Some(hmac)) => {
+            Some(token(client, timestamp, nonce, &hmac)?)
+        }
+        _ => {
+            return Err(WolGatewayError::InvalidApiRequest(
+                "client, timestamp, nonce and hmac must be given together".to_string(),
+            ))
+        }
+    };
+
+    Ok((password, token))
+}
+
+/// Reads the authentication token of a state request from its headers, if any.
+///
+/// # Errors
+///
+/// Returns `InvalidApiRequest` if only some of the token headers are given, or
+/// if one of them is malformed.
+fn read_token_headers(headers: &HeaderMap) -> Result<Option<WakeToken>, WolGatewayError> {
+    let values = TOKEN_HEADERS.map(|name| headers.get(name));
+    if values.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let [Some(client), Some(timestamp), Some(nonce), Some(hmac)] = values else {
+        return Err(WolGatewayError::InvalidApiRequest(format!(
+            "{} must be given together",
+            TOKEN_HEADERS.join(", ")
+        )));
+    };
+    let text = |value: &HeaderValue| {
+        value
+            .to_str()
+            .map(str::to_string)
+            .map_err(|e| WolGatewayError::InvalidApiRequest(format!("Invalid token header: {}", e)))
+    };
+    let timestamp = text(timestamp)?
+        .parse()
+        .map_err(|e| WolGatewayError::InvalidApiRequest(format!("Invalid timestamp: {}", e)))?;
+
+    token(text(client)?, timestamp, text(nonce)?, &text(hmac)?).map(Some)
+}
+
+/// Assembles an authentication token from its fields.
+///
+/// # Errors
+///
+/// Returns `InvalidApiRequest` if the HMAC is not hex encoded.
+fn token(
+    client: String,
+    timestamp: u64,
+    nonce: String,
+    hmac: &str,
+) -> Result<WakeToken, WolGatewayError> {
+    let signature = hex::decode(hmac)
+        .map_err(|e| WolGatewayError::InvalidApiRequest(format!("Invalid hmac: {}", e)))?;
+    Ok(WakeToken {
+        client,
+        timestamp,
+        nonce,
+        signature,
+    })
+}
+
+/// Returns the HTTP status an error is reported with.
+pub(crate) fn status_code(error: &WolGatewayError) -> StatusCode {
+    match error {
+        WolGatewayError::WakeOnLanParseError(_) | WolGatewayError::InvalidApiRequest(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        WolGatewayError::SourceNotAllowed(_)
+        | WolGatewayError::PasswordMismatch(_)
+        | WolGatewayError::VmDisabled(_)
+        | WolGatewayError::VmNotWakeable(_) => StatusCode::FORBIDDEN,
+        WolGatewayError::VmNotFound(_) | WolGatewayError::DomainNotFound(_) => {
+            StatusCode::NOT_FOUND
+        }
+        WolGatewayError::CrashedNotRestarted(_) | WolGatewayError::AmbiguousMac(..) => {
+            StatusCode::CONFLICT
+        }
+        WolGatewayError::AuthenticationRequired | WolGatewayError::AuthenticationFailed(_) => {
+            StatusCode::UNAUTHORIZED
+        }
+        WolGatewayError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        WolGatewayError::WakeupUnsupported(_) => StatusCode::NOT_IMPLEMENTED,
+        WolGatewayError::LibvirtConnectError(_)
+        | WolGatewayError::LibvirtUnavailable(_)
+        | WolGatewayError::WorkerQueueFull(_)
+        | WolGatewayError::WorkerStopped => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Builds the JSON response describing an error.
+fn error_response(error: &WolGatewayError) -> Response<Full<Bytes>> {
+    json_response(
+        status_code(error),
+        json!({
+            "error": error.kind(),
+            "message": error.to_string(),
+        }),
+    )
+}
+
+/// Builds the response to a request using a method the endpoint does not support.
+fn method_not_allowed(allow: &'static str) -> Response<Full<Bytes>> {
+    let mut response = json_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        json!({
+            "error": "MethodNotAllowed",
+            "message": format!("Supported methods: {}", allow),
+        }),
+    );
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+/// Builds a JSON response with the given status.
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
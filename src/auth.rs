@@ -0,0 +1,182 @@
+//! Authentication of wake requests with HMAC tokens.
+//!
+//! Magic packets carry no authentication, so anyone who can reach the gateway can
+//! wake any VM. Clients holding a shared key can instead sign their wake requests
+//! with HMAC-SHA256 over the target, a timestamp and a random nonce. Tokens are
+//! only accepted within a window around the current time, and each nonce only
+//! once within that window, so a captured request cannot be replayed. State
+//! requests are signed the same way, with an extra line telling them apart from
+//! wake requests.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::error::WolGatewayError;
+use crate::policy::WakeTarget;
+
+/// Default time a token is accepted for before and after its timestamp.
+pub(crate) const DEFAULT_WINDOW: Duration = Duration::from_secs(30);
+
+/// Maximum length of a nonce.
+const MAX_NONCE_LEN: usize = 64;
+
+/// Number of remembered nonces above which expired ones are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// HMAC-SHA256, the algorithm tokens are signed with.
+type HmacSha256 = Hmac<Sha256>;
+
+/// Settings for authenticated wake requests.
+#[derive(Debug, Clone)]
+pub(crate) struct AuthSettings {
+    /// Shared keys keyed by client name.
+    pub(crate) clients: HashMap<String, Vec<u8>>,
+    /// Whether wake and state requests without a valid token are rejected.
+    pub(crate) required: bool,
+    /// Time a token is accepted for before and after its timestamp, allowing
+    /// for clock skew between the client and the gateway.
+    pub(crate) window: Duration,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            clients: HashMap::new(),
+            required: false,
+            window: DEFAULT_WINDOW,
+        }
+    }
+}
+
+/// What an authenticated request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenAction {
+    /// Wake the target VM.
+    Wake,
+    /// Report the state of the target VM.
+    State,
+}
+
+/// A token authenticating a single wake or state request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WakeToken {
+    /// Name of the client whose key signed the request.
+    pub(crate) client: String,
+    /// When the request was signed, in seconds since the Unix epoch.
+    pub(crate) timestamp: u64,
+    /// Random value making the token unique.
+    pub(crate) nonce: String,
+    /// HMAC-SHA256 over the target, timestamp and nonce.
+    pub(crate) signature: Vec<u8>,
+}
+
+/// Returns the message a token signs, one field per line.
+///
+/// The target is prefixed with its kind, `mac:52:54:00:12:34:56` or
+/// `domain:build`, so a token for a MAC address cannot be used for a domain
+/// that happens to have the same name. State requests start with an additional
+/// `state` line, so their tokens cannot be used to wake the VM.
+fn signed_message(action: TokenAction, target: &WakeTarget, timestamp: u64, nonce: &str) -> String {
+    let target = match target {
+        WakeTarget::Mac(mac) => format!("mac:{}", mac),
+        WakeTarget::Domain(domain) => format!("domain:{}", domain),
+    };
+    match action {
+        TokenAction::Wake => format!("{}\n{}\n{}", target, timestamp, nonce),
+        TokenAction::State => format!("state\n{}\n{}\n{}", target, timestamp, nonce),
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Remembers the nonces of accepted tokens to reject replayed requests.
+#[derive(Debug, Default)]
+pub(crate) struct NonceCache {
+    /// Timestamps of accepted tokens, keyed by client name and nonce.
+    seen: HashMap<(String, String), u64>,
+}
+
+impl NonceCache {
+    /// Creates a cache without any remembered nonces.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verifies the token of a wake or state request and remembers its nonce.
+    ///
+    /// Nonces are only remembered once the signature is verified, so clients
+    /// without a key cannot fill the cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The current authentication settings
+    /// * `token` - The token carried by the request
+    /// * `action` - What the request asks for
+    /// * `target` - The VM the request is aimed at
+    /// * `now` - The current time in seconds since the Unix epoch
+    ///
+    /// # Errors
+    ///
+    /// Returns `AuthenticationFailed` if the client is unknown, the nonce is
+    /// malformed, the timestamp is outside the window, the signature does not
+    /// match, or the nonce was already used.
+    pub(crate) fn verify(
+        &mut self,
+        settings: &AuthSettings,
+        token: &WakeToken,
+        action: TokenAction,
+        target: &WakeTarget,
+        now: u64,
+    ) -> Result<(), WolGatewayError> {
+        let fail = |reason: &str| {
+            Err(WolGatewayError::AuthenticationFailed(format!(
+                "{} for client {}",
+                reason, token.client
+            )))
+        };
+
+        let Some(key) = settings.clients.get(&token.client) else {
+            return fail("Unknown key");
+        };
+
+        if token.nonce.is_empty() || token.nonce.len() > MAX_NONCE_LEN {
+            return fail("Invalid nonce");
+        }
+
+        let window = settings.window.as_secs();
+        if now.abs_diff(token.timestamp) > window {
+            return fail("Expired token");
+        }
+
+        let Ok(mut mac) = HmacSha256::new_from_slice(key) else {
+            return fail("Invalid key");
+        };
+        mac.update(signed_message(action, target, token.timestamp, &token.nonce).as_bytes());
+        if mac.verify_slice(&token.signature).is_err() {
+            return fail("Invalid signature");
+        }
+
+        if self.seen.len() >= PRUNE_THRESHOLD {
+            // Tokens outside the window are rejected before their nonce is checked
+            self.seen
+                .retain(|_, timestamp| now.abs_diff(*timestamp) <= window);
+        }
+
+        let key = (token.client.clone(), token.nonce.clone());
+        match self.seen.get(&key) {
+            Some(timestamp) if now.abs_diff(*timestamp) <= window => fail("Replayed nonce"),
+            _ => {
+                self.seen.insert(key, token.timestamp);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! The `check` subcommand, resolving a wake request without starting anything.
+//!
+//! Checking the configuration or a client integration by sending real magic
+//! packets boots VMs. This runs a magic packet for a MAC address through the
+//! same path the gateway takes, parsing, source and password checks, policy
+//! and MAC lookup, and reports what would be done to the resolved VM in its
+//! current state.
+
+use std::net::SocketAddr;
+
+use crate::config::Config;
+use crate::connection::LibvirtConnection;
+use crate::error::WolGatewayError;
+use crate::libvirt::find_and_start_vm_by_mac;
+use crate::mac_index::MacIndex;
+use crate::policy::{WakeRequest, WakeTarget};
+use crate::server::PacketSource;
+use crate::wakeonlan::{build_magic_packet, WakeOnLanPacket};
+use crate::{CheckArgs, Cli};
+
+/// Resolves a wake request for a MAC address in a dry run and prints the outcome.
+///
+/// # Arguments
+///
+/// * `args` - The MAC address, password and source of the request, and the
+///   configuration to check it against
+///
+/// # Errors
+///
+/// Returns the error the gateway would reject the request with, e.g.
+/// `SourceNotAllowed`, `PasswordMismatch` or `VmNotFound`, or any error loading
+/// the configuration or reaching libvirt.
+pub(crate) fn run(args: &CheckArgs) -> Result<(), WolGatewayError> {
+    let config = Config::load(&Cli {
+        dry_run: true,
+        ..args.libvirt.cli()
+    })?;
+    let policy = &config.policy;
+
+    // Go through the same steps as a magic packet received over UDP
+    let packet = build_magic_packet(&args.mac, args.password.as_ref());
+    let wol = WakeOnLanPacket::parse(&packet)?;
+    let source = PacketSource::Udp(SocketAddr::new(args.source, 0));
+    policy.check_source(&source)?;
+    if policy.auth.required {
+        return Err(WolGatewayError::AuthenticationRequired);
+    }
+
+    let mac = wol.target_mac_string();
+    let request = WakeRequest {
+        target: WakeTarget::Mac(mac.clone()),
+        password: wol.password().copied(),
+        source,
+    };
+
+    let mut connection = LibvirtConnection::open(&config.libvirt_uri)?;
+    let mut index = MacIndex::new(config.index_max_age);
+    let result = connection
+        .get()
+        .and_then(|conn| find_and_start_vm_by_mac(conn, &mut index, &mac, &request, policy));
+    connection.disconnect();
+    for vm in result? {
+        println!(
+            "VM {} ({}) owns MAC address {}, is {} and would {}",
+            vm.name, vm.uuid, mac, vm.state, vm.action
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,955 @@
+//! Gateway configuration loaded from an optional TOML file and the command line.
+//!
+//! Settings are resolved in order of precedence: command line flags first, then
+//! the configuration file given with `--config`, then built-in defaults.
+//!
+//! ```toml
+//! [listen]
+//! address = ["0.0.0.0:9", "[::]:9"]
+//! multicast = ["ff02::1%br0"]
+//! udp = true
+//! interfaces = ["br0"]
+//!
+//! [libvirt]
+//! uri = "qemu:///system"
+//! keepalive_interval = 5
+//! index_max_age = 300
+//!
+//! [metrics]
+//! address = "127.0.0.1:9100"
+//!
+//! [api]
+//! address = "127.0.0.1:8080"
+//!
+//! [limits]
+//! dedup_window = 5
+//! rate_limit = 5.0
+//! rate_burst = 10
+//!
+//! [start]
+//! action = "start"
+//! managed_save = "restore"
+//! restart_crashed = false
+//! dry_run = false
+//! duplicate_macs = "prefer"
+//! prefer = ["build", "web-*"]
+//!
+//! [security]
+//! password = "11:22:33:44:55:66"
+//! allowed_sources = ["192.168.1.0/24", "fd00::/8"]
+//! denied_sources = ["192.168.1.1"]
+//! opt_in = true
+//! wakeable = ["web-*", "3e3fce45-4f53-4fa7-bb32-11f34168b82b"]
+//! marker = "[wol]"
+//! require_auth = false
+//! auth_window = 30
+//!
+//! [[client]]
+//! name = "guacamole"
+//! key = "a long random shared secret"
+//!
+//! [[vm]]
+//! domain = "build"
+//! password = "10.0.0.1"
+//! allowed_sources = ["192.168.1.10"]
+//!
+//! [[vm]]
+//! domain = "3e3fce45-4f53-4fa7-bb32-11f34168b82b"
+//! enabled = false
+//!
+//! [[vm]]
+//! domain = "scratch"
+//! action = "resume"
+//! managed_save = "discard"
+//! restart_crashed = true
+//!
+//! [mappings]
+//! "52:54:00:12:34:56" = "build"
+//! ```
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use log::warn;
+use serde::Deserialize;
+
+use crate::auth::{AuthSettings, DEFAULT_WINDOW};
+use crate::error::WolGatewayError;
+use crate::policy::{
+    DomainSelector, DuplicateMacs, ManagedSave, OptIn, Policy, SourceFilter, StartOptions,
+    VmPolicy, WakeAction,
+};
+use crate::rate_limit::Limits;
+use crate::udp::MulticastGroup;
+use crate::wakeonlan::{mac_to_string, parse_mac_address_string, parse_secureon_password_string};
+use crate::Cli;
+
+/// Default address and port to bind the UDP listener to.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9";
+
+/// Default libvirt connection URI.
+const DEFAULT_LIBVIRT_URI: &str = "qemu:///system";
+
+/// Default interval in seconds between libvirt connection health checks.
+const DEFAULT_KEEPALIVE_INTERVAL: u64 = 5;
+
+/// Default maximum age in seconds of the MAC address index.
+const DEFAULT_INDEX_MAX_AGE: u64 = 300;
+
+/// Default window in seconds in which repeated requests for a MAC address are collapsed.
+const DEFAULT_DEDUP_WINDOW: u64 = 5;
+
+/// Default number of packets per second accepted from each source address.
+const DEFAULT_RATE_LIMIT: f64 = 5.0;
+
+/// Default number of packets a source address may send in a burst.
+const DEFAULT_RATE_BURST: u32 = 10;
+
+/// Default text selecting a domain in opt-in mode when found in its title or description.
+const DEFAULT_MARKER: &str = "[wol]";
+
+/// Minimum length of a client key, shorter keys are easy to guess.
+const MIN_CLIENT_KEY_LEN: usize = 16;
+
+/// Contents of the TOML configuration file.
+///
+/// Every field is optional, so an empty file is a valid configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    /// Listener settings.
+    #[serde(default)]
+    listen: ListenSection,
+    /// Libvirt connection settings.
+    #[serde(default)]
+    libvirt: LibvirtSection,
+    /// Metrics listener settings.
+    #[serde(default)]
+    metrics: MetricsSection,
+    /// HTTP API listener settings.
+    #[serde(default)]
+    api: ApiSection,
+    /// Deduplication and rate limiting settings.
+    #[serde(default)]
+    limits: LimitsSection,
+    /// Settings deciding how VMs are started.
+    #[serde(default)]
+    start: StartSection,
+    /// Global access control settings.
+    #[serde(default)]
+    security: SecuritySection,
+    /// Per-VM policies, one `[[vm]]` table each.
+    #[serde(default, rename = "vm")]
+    vms: Vec<VmSection>,
+    /// Clients allowed to send authenticated wake requests, one `[[client]]` table each.
+    #[serde(default, rename = "client")]
+    clients: Vec<ClientSection>,
+    /// Explicit MAC address to domain name or UUID mappings.
+    #[serde(default)]
+    mappings: HashMap<String, String>,
+}
+
+/// The `[listen]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenSection {
+    /// Addresses and ports to bind the UDP listeners to.
+    address: Option<Addresses>,
+    /// IPv6 multicast groups joined by the IPv6 wildcard listeners.
+    #[serde(default)]
+    multicast: Vec<String>,
+    /// Whether the UDP listener is enabled.
+    udp: Option<bool>,
+    /// Network interfaces to receive raw Ethernet WOL frames on.
+    interfaces: Option<Vec<String>>,
+}
+
+/// One or several listen addresses, so a single address can be given as a string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Addresses {
+    /// A single address.
+    One(String),
+    /// A list of addresses.
+    Many(Vec<String>),
+}
+
+impl Addresses {
+    /// Returns the addresses as a list.
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Addresses::One(address) => vec![address],
+            Addresses::Many(addresses) => addresses,
+        }
+    }
+}
+
+/// The `[libvirt]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LibvirtSection {
+    /// Libvirt connection URI.
+    uri: Option<String>,
+    /// Interval in seconds between connection health checks.
+    keepalive_interval: Option<u64>,
+    /// Maximum age in seconds of the MAC address index.
+    index_max_age: Option<u64>,
+}
+
+/// The `[metrics]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricsSection {
+    /// Address and port to serve Prometheus metrics on.
+    address: Option<String>,
+}
+
+/// The `[api]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiSection {
+    /// Address and port to serve the HTTP API on.
+    address: Option<String>,
+}
+
+/// The `[limits]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+    /// Window in seconds in which repeated requests for a MAC address are collapsed.
+    dedup_window: Option<u64>,
+    /// Packets per second accepted from each source address.
+    rate_limit: Option<f64>,
+    /// Number of packets a source address may send in a burst.
+    rate_burst: Option<u32>,
+}
+
+/// The `[start]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartSection {
+    /// What wake requests do to VMs, "start" or "resume".
+    action: Option<String>,
+    /// What to do with managed save images, "restore" or "discard".
+    managed_save: Option<String>,
+    /// Whether crashed domains are restarted.
+    restart_crashed: Option<bool>,
+    /// Whether wake requests only report what they would do.
+    dry_run: Option<bool>,
+    /// What to do when domains share a MAC address, "refuse", "prefer" or "all".
+    duplicate_macs: Option<String>,
+    /// Name globs or UUIDs of the domains woken among those sharing a MAC address.
+    #[serde(default)]
+    prefer: Vec<String>,
+}
+
+/// The `[security]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SecuritySection {
+    /// SecureOn password required to wake any VM.
+    password: Option<String>,
+    /// Networks in CIDR notation UDP wake requests may come from.
+    #[serde(default)]
+    allowed_sources: Vec<String>,
+    /// Networks in CIDR notation UDP wake requests are never accepted from.
+    #[serde(default)]
+    denied_sources: Vec<String>,
+    /// Whether only selected domains may be woken.
+    opt_in: Option<bool>,
+    /// Name globs or UUIDs of the domains selected in opt-in mode.
+    #[serde(default)]
+    wakeable: Vec<String>,
+    /// Text selecting a domain in opt-in mode when found in its title or description.
+    marker: Option<String>,
+    /// Whether wake and state requests without a valid authentication token are rejected.
+    require_auth: Option<bool>,
+    /// Seconds an authentication token is accepted for before and after its timestamp.
+    auth_window: Option<u64>,
+}
+
+/// A `[[client]]` table of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientSection {
+    /// Name the client identifies itself with.
+    name: String,
+    /// Key shared with the client to sign its wake requests.
+    key: String,
+}
+
+/// A `[[vm]]` table of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VmSection {
+    /// Libvirt domain name or UUID the policy applies to.
+    domain: String,
+    /// Whether the VM may be woken at all.
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// SecureOn password required for this VM.
+    password: Option<String>,
+    /// Networks in CIDR notation wake requests for this VM may come from.
+    #[serde(default)]
+    allowed_sources: Vec<String>,
+    /// Networks in CIDR notation wake requests for this VM are never accepted from.
+    #[serde(default)]
+    denied_sources: Vec<String>,
+    /// What wake requests do to this VM, "start" or "resume".
+    action: Option<String>,
+    /// What to do with the managed save image of this VM, "restore" or "discard".
+    managed_save: Option<String>,
+    /// Whether this VM is restarted after a crash.
+    restart_crashed: Option<bool>,
+}
+
+/// Returns `true`, for use as a serde default.
+fn default_true() -> bool {
+    true
+}
+
+/// Fully resolved and validated gateway configuration.
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// Addresses and ports to bind the UDP listeners to.
+    pub(crate) addresses: Vec<SocketAddr>,
+    /// IPv6 multicast groups joined by the IPv6 wildcard listeners.
+    pub(crate) multicast: Vec<MulticastGroup>,
+    /// Whether the UDP listeners are enabled.
+    pub(crate) udp: bool,
+    /// Network interfaces to receive raw Ethernet WOL frames on.
+    pub(crate) interfaces: Vec<String>,
+    /// Libvirt connection URI.
+    pub(crate) libvirt_uri: String,
+    /// Interval between libvirt connection health checks.
+    pub(crate) keepalive_interval: Duration,
+    /// Maximum age of the MAC address index.
+    pub(crate) index_max_age: Duration,
+    /// Address and port to serve Prometheus metrics on, if enabled.
+    pub(crate) metrics_address: Option<SocketAddr>,
+    /// Address and port to serve the HTTP API on, if enabled.
+    pub(crate) api_address: Option<SocketAddr>,
+    /// Deduplication and rate limiting settings.
+    pub(crate) limits: Limits,
+    /// Rules deciding whether a wake request may start a VM.
+    ///
+    /// Wake requests queued for the libvirt worker keep the policy that was live
+    /// when they were received, so a reload swaps the policy as a whole.
+    pub(crate) policy: Arc<Policy>,
+}
+
+impl Config {
+    /// Loads the configuration file given on the command line, if any, and
+    /// applies the command line flags on top of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - CLI arguments, including the optional configuration file path
+    ///
+    /// # Errors
+    ///
+    /// Returns `ConfigReadError` or `ConfigParseError` if the file cannot be read
+    /// or parsed, `AddressParseError` for an invalid listen address, and
+    /// `ConfigError` or `WakeOnLanParseError` for any other invalid setting.
+    pub(crate) fn load(args: &Cli) -> Result<Self, WolGatewayError> {
+        let contents = match &args.config {
+            Some(path) => read_file(path)?,
+            None => String::new(),
+        };
+
+        Self::from_toml(args, &contents)
+    }
+
+    /// Parses the contents of a configuration file and applies the command line
+    /// flags on top of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - CLI arguments overriding the file settings
+    /// * `contents` - The TOML configuration, which may be empty
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `Config::load`, except for `ConfigReadError`.
+    pub(crate) fn from_toml(args: &Cli, contents: &str) -> Result<Self, WolGatewayError> {
+        let file: FileConfig =
+            toml::from_str(contents).map_err(WolGatewayError::ConfigParseError)?;
+
+        let addresses = if args.addresses.is_empty() {
+            file.listen
+                .address
+                .map(Addresses::into_vec)
+                .unwrap_or_else(|| vec![DEFAULT_ADDRESS.to_string()])
+        } else {
+            args.addresses.clone()
+        };
+        let addresses = addresses
+            .iter()
+            .map(|address| address.parse())
+            .collect::<Result<Vec<SocketAddr>, _>>()?;
+        if addresses.is_empty() {
+            return Err(WolGatewayError::ConfigError(
+                "At least one listen address is required".to_string(),
+            ));
+        }
+        if let Some(address) = addresses
+            .iter()
+            .enumerate()
+            .find_map(|(i, address)| addresses[..i].contains(address).then_some(address))
+        {
+            return Err(WolGatewayError::ConfigError(format!(
+                "Duplicate listen address {}",
+                address
+            )));
+        }
+
+        let multicast = if args.multicast.is_empty() {
+            &file.listen.multicast
+        } else {
+            &args.multicast
+        };
+        let multicast = multicast
+            .iter()
+            .map(|group| parse_multicast_group(group))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !multicast.is_empty()
+            && !addresses
+                .iter()
+                .any(|address| address.ip() == Ipv6Addr::UNSPECIFIED)
+        {
+            return Err(WolGatewayError::ConfigError(
+                "Joining multicast groups requires a listen address on [::]".to_string(),
+            ));
+        }
+
+        let udp = cli_flag(args.udp, args.no_udp)
+            .or(file.listen.udp)
+            .unwrap_or(true);
+
+        let interfaces = if args.interfaces.is_empty() {
+            file.listen.interfaces.unwrap_or_default()
+        } else {
+            args.interfaces.clone()
+        };
+
+        let api_address = args
+            .api_address
+            .as_deref()
+            .or(file.api.address.as_deref())
+            .map(str::parse)
+            .transpose()?;
+
+        if !udp && interfaces.is_empty() && api_address.is_none() {
+            return Err(WolGatewayError::ConfigError(
+                "UDP is disabled and neither an Ethernet interface nor the HTTP API is configured"
+                    .to_string(),
+            ));
+        }
+
+        let libvirt_uri = args
+            .libvirt_uri
+            .clone()
+            .or(file.libvirt.uri)
+            .unwrap_or_else(|| DEFAULT_LIBVIRT_URI.to_string());
+
+        let keepalive_interval = args
+            .keepalive_interval
+            .or(file.libvirt.keepalive_interval)
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL);
+        if keepalive_interval == 0 {
+            return Err(WolGatewayError::ConfigError(
+                "keepalive_interval must be at least 1 second".to_string(),
+            ));
+        }
+
+        let index_max_age = args
+            .index_max_age
+            .or(file.libvirt.index_max_age)
+            .unwrap_or(DEFAULT_INDEX_MAX_AGE);
+
+        let metrics_address = args
+            .metrics_address
+            .as_deref()
+            .or(file.metrics.address.as_deref())
+            .map(str::parse)
+            .transpose()?;
+
+        let limits = resolve_limits(args, file.limits)?;
+
+        let policy = resolve_policy(
+            args,
+            file.start,
+            file.security,
+            file.vms,
+            file.clients,
+            file.mappings,
+        )?;
+
+        Ok(Config {
+            addresses,
+            multicast,
+            udp,
+            interfaces,
+            libvirt_uri,
+            keepalive_interval: Duration::from_secs(keepalive_interval),
+            index_max_age: Duration::from_secs(index_max_age),
+            metrics_address,
+            api_address,
+            limits,
+            policy: Arc::new(policy),
+        })
+    }
+
+    /// Replaces the live policy and limits with the ones from a freshly loaded
+    /// configuration.
+    ///
+    /// Listener, libvirt, metrics and API settings are bound at startup, so changes to them
+    /// are reported and otherwise ignored until the gateway is restarted.
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - The freshly loaded and validated configuration
+    pub(crate) fn apply_reload(&mut self, new: Config) {
+        let restart_only = [
+            ("listen.address", self.addresses != new.addresses),
+            ("listen.multicast", self.multicast != new.multicast),
+            ("listen.udp", self.udp != new.udp),
+            ("listen.interfaces", self.interfaces != new.interfaces),
+            ("libvirt.uri", self.libvirt_uri != new.libvirt_uri),
+            (
+                "libvirt.keepalive_interval",
+                self.keepalive_interval != new.keepalive_interval,
+            ),
+            (
+                "libvirt.index_max_age",
+                self.index_max_age != new.index_max_age,
+            ),
+            (
+                "metrics.address",
+                self.metrics_address != new.metrics_address,
+            ),
+            ("api.address", self.api_address != new.api_address),
+        ];
+
+        for (setting, changed) in restart_only {
+            if changed {
+                warn!(
+                    "Changing {} requires a restart, keeping the current value",
+                    setting
+                );
+            }
+        }
+
+        if self.policy.dry_run != new.policy.dry_run {
+            warn!(
+                "Dry run {}",
+                if new.policy.dry_run {
+                    "enabled, no VM is started anymore"
+                } else {
+                    "disabled, VMs are started again"
+                }
+            );
+        }
+
+        self.limits = new.limits;
+        self.policy = new.policy;
+    }
+}
+
+/// Resolves a pair of `--flag` and `--no-flag` command line options.
+///
+/// # Returns
+///
+/// The value given on the command line, or `None` if neither option was given
+/// and the configuration file or the default applies. If both are given, clap
+/// only keeps the last one.
+fn cli_flag(enable: bool, disable: bool) -> Option<bool> {
+    match (enable, disable) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads a configuration file into a string.
+fn read_file(path: &Path) -> Result<String, WolGatewayError> {
+    std::fs::read_to_string(path).map_err(WolGatewayError::ConfigReadError)
+}
+
+/// Resolves the deduplication and rate limiting settings.
+///
+/// # Errors
+///
+/// Returns `ConfigError` if the rate limit is negative or not a number, or the
+/// burst size is zero.
+fn resolve_limits(args: &Cli, limits: LimitsSection) -> Result<Limits, WolGatewayError> {
+    let dedup_window = args
+        .dedup_window
+        .or(limits.dedup_window)
+        .unwrap_or(DEFAULT_DEDUP_WINDOW);
+    let rate = args
+        .rate_limit
+        .or(limits.rate_limit)
+        .unwrap_or(DEFAULT_RATE_LIMIT);
+    let burst = args
+        .rate_burst
+        .or(limits.rate_burst)
+        .unwrap_or(DEFAULT_RATE_BURST);
+
+    if !rate.is_finite() || rate < 0.0 {
+        return Err(WolGatewayError::ConfigError(format!(
+            "Invalid rate_limit {}, expected a non-negative number",
+            rate
+        )));
+    }
+    if burst == 0 {
+        return Err(WolGatewayError::ConfigError(
+            "rate_burst must be at least 1".to_string(),
+        ));
+    }
+
+    Ok(Limits {
+        dedup_window: Duration::from_secs(dedup_window),
+        rate,
+        burst,
+    })
+}
+
+/// Builds the wake policy from the security settings, per-VM tables and mappings.
+///
+/// Passwords given on the command line override the ones from the file.
+fn resolve_policy(
+    args: &Cli,
+    start: StartSection,
+    security: SecuritySection,
+    vm_sections: Vec<VmSection>,
+    client_sections: Vec<ClientSection>,
+    mapping_entries: HashMap<String, String>,
+) -> Result<Policy, WolGatewayError> {
+    let password = args
+        .password
+        .as_deref()
+        .or(security.password.as_deref())
+        .map(parse_secureon_password_string)
+        .transpose()?;
+
+    // Source lists given on the command line replace the ones from the file
+    let sources = SourceFilter {
+        allow: parse_networks(if args.allow.is_empty() {
+            &security.allowed_sources
+        } else {
+            &args.allow
+        })?,
+        deny: parse_networks(if args.deny.is_empty() {
+            &security.denied_sources
+        } else {
+            &args.deny
+        })?,
+    };
+
+    let dry_run = cli_flag(args.dry_run, args.no_dry_run)
+        .or(start.dry_run)
+        .unwrap_or(false);
+
+    let prefer = if args.prefer.is_empty() {
+        start.prefer
+    } else {
+        args.prefer.clone()
+    };
+    let duplicate_macs = resolve_duplicate_macs(
+        args.duplicate_macs
+            .as_deref()
+            .or(start.duplicate_macs.as_deref()),
+        &prefer,
+    )?;
+
+    let start = StartOptions {
+        action: start
+            .action
+            .as_deref()
+            .map(parse_wake_action)
+            .transpose()?
+            .unwrap_or_default(),
+        managed_save: args
+            .managed_save
+            .as_deref()
+            .or(start.managed_save.as_deref())
+            .map(parse_managed_save)
+            .transpose()?
+            .unwrap_or_default(),
+        restart_crashed: cli_flag(args.restart_crashed, args.no_restart_crashed)
+            .or(start.restart_crashed)
+            .unwrap_or(false),
+    };
+
+    let mut vms = HashMap::new();
+    for section in vm_sections {
+        if section.domain.is_empty() {
+            return Err(WolGatewayError::ConfigError(
+                "[[vm]] entries need a non-empty domain".to_string(),
+            ));
+        }
+        let vm = VmPolicy {
+            enabled: section.enabled,
+            password: section
+                .password
+                .as_deref()
+                .map(parse_secureon_password_string)
+                .transpose()?,
+            sources: SourceFilter {
+                allow: parse_networks(&section.allowed_sources)?,
+                deny: parse_networks(&section.denied_sources)?,
+            },
+            action: section
+                .action
+                .as_deref()
+                .map(parse_wake_action)
+                .transpose()?,
+            managed_save: section
+                .managed_save
+                .as_deref()
+                .map(parse_managed_save)
+                .transpose()?,
+            restart_crashed: section.restart_crashed,
+        };
+        if vms.insert(section.domain.clone(), vm).is_some() {
+            return Err(WolGatewayError::ConfigError(format!(
+                "Duplicate [[vm]] entry for domain '{}'",
+                section.domain
+            )));
+        }
+    }
+
+    for entry in &args.vm_passwords {
+        let (domain, password) = entry.split_once('=').ok_or_else(|| {
+            WolGatewayError::ConfigError(format!(
+                "Invalid VM password '{}': expected DOMAIN=PASSWORD",
+                entry
+            ))
+        })?;
+        vms.entry(domain.to_string()).or_default().password =
+            Some(parse_secureon_password_string(password)?);
+    }
+
+    let mut mappings = HashMap::new();
+    for (mac, domain) in mapping_entries {
+        let mac = parse_mac_address_string(&mac.to_lowercase())?;
+        mappings.insert(mac_to_string(&mac), domain);
+    }
+
+    // Listing wakeable domains implies opt-in mode
+    let wakeable = if args.wakeable.is_empty() {
+        security.wakeable
+    } else {
+        args.wakeable.clone()
+    };
+    let opt_in = cli_flag(args.opt_in, args.no_opt_in)
+        .unwrap_or_else(|| security.opt_in.unwrap_or(false) || !wakeable.is_empty());
+    let opt_in = if opt_in {
+        let marker = security
+            .marker
+            .unwrap_or_else(|| DEFAULT_MARKER.to_string());
+        if marker.is_empty() {
+            return Err(WolGatewayError::ConfigError(
+                "The opt-in marker must not be empty".to_string(),
+            ));
+        }
+        if wakeable.iter().any(String::is_empty) {
+            return Err(WolGatewayError::ConfigError(
+                "Wakeable domains need a non-empty name or UUID".to_string(),
+            ));
+        }
+        Some(OptIn {
+            domains: wakeable.iter().map(|d| DomainSelector::parse(d)).collect(),
+            marker,
+        })
+    } else {
+        None
+    };
+
+    let auth = resolve_auth(
+        security.require_auth.unwrap_or(false),
+        security.auth_window,
+        client_sections,
+    )?;
+
+    Ok(Policy {
+        password,
+        sources,
+        start,
+        vms,
+        mappings,
+        opt_in,
+        auth,
+        dry_run,
+        duplicate_macs,
+    })
+}
+
+/// Resolves the client keys and settings for authenticated wake requests.
+///
+/// # Errors
+///
+/// Returns `ConfigError` if a client name is empty or repeated, a key is too
+/// short, the window is zero, or authentication is required without any client.
+fn resolve_auth(
+    required: bool,
+    window: Option<u64>,
+    client_sections: Vec<ClientSection>,
+) -> Result<AuthSettings, WolGatewayError> {
+    let mut clients = HashMap::new();
+    for section in client_sections {
+        if section.name.is_empty() {
+            return Err(WolGatewayError::ConfigError(
+                "[[client]] entries need a non-empty name".to_string(),
+            ));
+        }
+        if section.key.len() < MIN_CLIENT_KEY_LEN {
+            return Err(WolGatewayError::ConfigError(format!(
+                "The key of client '{}' must be at least {} bytes long",
+                section.name, MIN_CLIENT_KEY_LEN
+            )));
+        }
+        if clients
+            .insert(section.name.clone(), section.key.into_bytes())
+            .is_some()
+        {
+            return Err(WolGatewayError::ConfigError(format!(
+                "Duplicate [[client]] entry '{}'",
+                section.name
+            )));
+        }
+    }
+
+    if required && clients.is_empty() {
+        return Err(WolGatewayError::ConfigError(
+            "require_auth needs at least one [[client]] entry".to_string(),
+        ));
+    }
+
+    let window = window.map_or(DEFAULT_WINDOW, Duration::from_secs);
+    if window.is_zero() {
+        return Err(WolGatewayError::ConfigError(
+            "auth_window must be at least 1 second".to_string(),
+        ));
+    }
+
+    Ok(AuthSettings {
+        clients,
+        required,
+        window,
+    })
+}
+
+/// Parses what wake requests do to a VM.
+///
+/// # Errors
+///
+/// Returns `ConfigError` unless the action is "start" or "resume".
+fn parse_wake_action(action: &str) -> Result<WakeAction, WolGatewayError> {
+    match action {
+        "start" => Ok(WakeAction::Start),
+        "resume" => Ok(WakeAction::Resume),
+        _ => Err(WolGatewayError::ConfigError(format!(
+            "Invalid action '{}': expected \"start\" or \"resume\"",
+            action
+        ))),
+    }
+}
+
+/// Resolves what to do when domains share a MAC address.
+///
+/// Listing preferred domains implies the "prefer" mode.
+///
+/// # Errors
+///
+/// Returns `ConfigError` unless the mode is "refuse", "prefer" or "all", if
+/// the "prefer" mode lists no or empty domains, or if domains are preferred in
+/// another mode.
+fn resolve_duplicate_macs(
+    mode: Option<&str>,
+    prefer: &[String],
+) -> Result<DuplicateMacs, WolGatewayError> {
+    match mode {
+        None if prefer.is_empty() => Ok(DuplicateMacs::Refuse),
+        None | Some("prefer") => {
+            if prefer.is_empty() || prefer.iter().any(String::is_empty) {
+                return Err(WolGatewayError::ConfigError(
+                    "duplicate_macs = \"prefer\" needs non-empty names or UUIDs in prefer"
+                        .to_string(),
+                ));
+            }
+            Ok(DuplicateMacs::Prefer(
+                prefer.iter().map(|d| DomainSelector::parse(d)).collect(),
+            ))
+        }
+        Some("refuse" | "all") if !prefer.is_empty() => Err(WolGatewayError::ConfigError(
+            "Preferred domains require duplicate_macs = \"prefer\"".to_string(),
+        )),
+        Some("refuse") => Ok(DuplicateMacs::Refuse),
+        Some("all") => Ok(DuplicateMacs::All),
+        Some(mode) => Err(WolGatewayError::ConfigError(format!(
+            "Invalid duplicate_macs '{}': expected \"refuse\", \"prefer\" or \"all\"",
+            mode
+        ))),
+    }
+}
+
+/// Parses what to do with managed save images.
+///
+/// # Errors
+///
+/// Returns `ConfigError` unless the action is "restore" or "discard".
+fn parse_managed_save(action: &str) -> Result<ManagedSave, WolGatewayError> {
+    match action {
+        "restore" => Ok(ManagedSave::Restore),
+        "discard" => Ok(ManagedSave::Discard),
+        _ => Err(WolGatewayError::ConfigError(format!(
+            "Invalid managed_save '{}': expected \"restore\" or \"discard\"",
+            action
+        ))),
+    }
+}
+
+/// Parses an IPv6 multicast group, optionally followed by `%` and the interface
+/// to join it on, e.g. "ff02::1%br0".
+///
+/// # Errors
+///
+/// Returns `ConfigError` if the address is not an IPv6 multicast address.
+fn parse_multicast_group(group: &str) -> Result<MulticastGroup, WolGatewayError> {
+    let (address, interface) = match group.split_once('%') {
+        Some((address, interface)) => (address, Some(interface.to_string())),
+        None => (group, None),
+    };
+
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(address)) if address.is_multicast() => Ok(MulticastGroup {
+            group: address,
+            interface: interface.filter(|interface| !interface.is_empty()),
+        }),
+        _ => Err(WolGatewayError::ConfigError(format!(
+            "Invalid multicast group '{}': expected an IPv6 multicast address",
+            group
+        ))),
+    }
+}
+
+/// Parses a list of networks in CIDR notation.
+///
+/// Plain IP addresses are accepted as single-host networks.
+pub(crate) fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>, WolGatewayError> {
+    networks
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| WolGatewayError::ConfigError(format!("Invalid network '{}'", network)))
+        })
+        .collect()
+}
@@ -0,0 +1,147 @@
+//! Managed libvirt connection that survives libvirtd restarts.
+//!
+//! A `virt::connect::Connect` becomes unusable when libvirtd restarts, for
+//! example during package upgrades. This module wraps the connection, checks
+//! its health periodically and reconnects with exponential backoff, so the
+//! gateway keeps its listeners while libvirtd is unavailable.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use virt::connect::Connect;
+
+use crate::error::WolGatewayError;
+use crate::metrics;
+use crate::systemd;
+
+/// Delay before the first reconnection attempt after a failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the delay between two reconnection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A libvirt connection that is re-established when it dies.
+#[derive(Debug)]
+pub(crate) struct LibvirtConnection {
+    /// The libvirt connection URI.
+    uri: String,
+    /// The live connection, or `None` while disconnected.
+    conn: Option<Connect>,
+    /// Delay before the next reconnection attempt after a failure.
+    backoff: Duration,
+    /// Earliest time of the next reconnection attempt.
+    next_attempt: Instant,
+    /// Whether the connection was re-established since `take_reconnected` was
+    /// last called.
+    reconnected: bool,
+}
+
+impl LibvirtConnection {
+    /// Opens the initial connection to libvirt.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The libvirt connection URI
+    ///
+    /// # Errors
+    ///
+    /// Returns `LibvirtConnectError` if the connection could not be opened.
+    pub(crate) fn open(uri: &str) -> Result<Self, WolGatewayError> {
+        let conn = Connect::open(Some(uri)).map_err(WolGatewayError::LibvirtConnectError)?;
+        let hostname = conn.get_hostname().unwrap_or_else(|_| "N/A".to_string());
+        info!("Successfully connected to libvirt host: {}", hostname);
+        metrics::set_libvirt_connected(true);
+
+        Ok(LibvirtConnection {
+            uri: uri.to_string(),
+            conn: Some(conn),
+            backoff: INITIAL_BACKOFF,
+            next_attempt: Instant::now(),
+            reconnected: false,
+        })
+    }
+
+    /// Returns the live connection, reconnecting first if necessary.
+    ///
+    /// A reconnection is only attempted once the backoff delay since the last
+    /// failed attempt has passed, so callers fail fast while libvirtd is down.
+    ///
+    /// # Errors
+    ///
+    /// Returns `LibvirtUnavailable` if there is no live connection and none
+    /// could be established.
+    pub(crate) fn get(&mut self) -> Result<&Connect, WolGatewayError> {
+        if self.conn.is_none() && Instant::now() >= self.next_attempt {
+            self.reconnect();
+        }
+
+        self.conn
+            .as_ref()
+            .ok_or_else(|| WolGatewayError::LibvirtUnavailable(self.uri.clone()))
+    }
+
+    /// Checks that the connection is still usable and reconnects if it is not.
+    ///
+    /// This is meant to be called periodically as a keepalive. Besides asking
+    /// libvirt whether the connection is alive, it performs a cheap remote call,
+    /// since a dead daemon is otherwise only noticed on the next request.
+    pub(crate) fn check_health(&mut self) {
+        if let Some(conn) = &self.conn {
+            let alive = conn.is_alive().unwrap_or(false) && conn.num_of_domains().is_ok();
+            if alive {
+                return;
+            }
+            warn!("Lost connection to libvirt URI: {}", self.uri);
+            systemd::notify_status(&format!("Lost connection to {}, reconnecting", self.uri));
+            self.disconnect();
+            self.backoff = INITIAL_BACKOFF;
+            self.next_attempt = Instant::now();
+        }
+
+        if Instant::now() >= self.next_attempt {
+            self.reconnect();
+        }
+    }
+
+    /// Returns whether the connection was re-established since the last call.
+    ///
+    /// Anything cached from the previous connection, like the MAC index, may
+    /// be outdated after a reconnection.
+    pub(crate) fn take_reconnected(&mut self) -> bool {
+        std::mem::take(&mut self.reconnected)
+    }
+
+    /// Closes the connection, if any.
+    pub(crate) fn disconnect(&mut self) {
+        if let Some(mut conn) = self.conn.take() {
+            // The daemon may already be gone, so closing is best effort
+            let _ = conn.close();
+            metrics::set_libvirt_connected(false);
+        }
+    }
+
+    /// Attempts to open a new connection, scheduling the next attempt on failure.
+    fn reconnect(&mut self) {
+        info!("Attempting to reconnect to libvirt URI: {}", self.uri);
+
+        match Connect::open(Some(&self.uri)) {
+            Ok(conn) => {
+                info!("Reconnected to libvirt URI: {}", self.uri);
+                systemd::notify_status(&format!("Connected to {}", self.uri));
+                self.conn = Some(conn);
+                self.reconnected = true;
+                self.backoff = INITIAL_BACKOFF;
+                metrics::set_libvirt_connected(true);
+            }
+            Err(e) => {
+                warn!(
+                    "{}, retrying in {}s",
+                    WolGatewayError::LibvirtConnectError(e),
+                    self.backoff.as_secs()
+                );
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
@@ -1,13 +1,29 @@
-//! Module for parsing MAC addresses from XML domain configurations.
+//! Module for parsing MAC addresses and WOL policies from XML domain configurations.
 //!
 //! This module provides functionality to extract and validate MAC addresses
 //! from libvirt domain XML configurations, specifically targeting network
-//! interface definitions.
+//! interface definitions, and to parse the `<wol:policy>` element VMs can carry
+//! in their `<metadata>`:
+//!
+//! ```xml
+//! <metadata>
+//!   <wol:policy xmlns:wol="https://github.com/brunoproduit/wol-libvirt-gateway"
+//!               enabled="yes" password="11:22:33:44:55:66"
+//!               allow="10.0.0.0/8, fd00::/8"/>
+//! </metadata>
+//! ```
+//!
+//! Only VMs whose policy sets `enabled="yes"` are ever woken.
 
+use crate::config::parse_networks;
 use crate::error::WolGatewayError;
-use crate::wakeonlan::{mac_to_string, parse_mac_address_string};
+use crate::policy::{SourceFilter, VmPolicy};
+use crate::wakeonlan::{mac_to_string, parse_mac_address_string, parse_secureon_password_string};
 use serde::Deserialize;
 
+/// Namespace URI of the `<wol:policy>` element in the domain metadata.
+pub(crate) const POLICY_NAMESPACE: &str = "https://github.com/brunoproduit/wol-libvirt-gateway";
+
 /// Root structure for deserializing domain XML that contains MAC address information.
 ///
 /// This represents the top-level domain element from a libvirt XML configuration.
@@ -75,3 +91,82 @@ pub(crate) fn get_mac_addresses(xml: &str) -> Result<Vec<String>, WolGatewayErro
         })
         .collect()
 }
+
+/// The `<wol:policy>` element from the metadata of a domain.
+///
+/// All attributes are optional, but the VM is only woken if `enabled` is set,
+/// an element without it keeps the VM disabled.
+#[derive(Debug, Deserialize)]
+struct PolicyElement {
+    /// Whether the VM may be woken at all, "yes" or "no" ("true" or "false").
+    #[serde(rename = "@enabled")]
+    enabled: Option<String>,
+    /// SecureOn password required to wake the VM.
+    #[serde(rename = "@password")]
+    password: Option<String>,
+    /// Comma-separated networks wake requests for the VM may come from.
+    #[serde(rename = "@allow")]
+    allow: Option<String>,
+    /// Comma-separated networks wake requests for the VM are never accepted from.
+    #[serde(rename = "@deny")]
+    deny: Option<String>,
+}
+
+/// Parses the `<wol:policy>` element returned by libvirt for the gateway's
+/// metadata namespace.
+///
+/// # Arguments
+///
+/// * `vm_name` - The name of the domain, for error messages
+/// * `xml` - The metadata element, as returned by `virDomainGetMetadata`
+///
+/// # Returns
+///
+/// * `Ok(VmPolicy)` - The rules the VM asks for
+/// * `Err(WolGatewayError)` - `MetadataPolicyError` if the element or any attribute is invalid
+pub(crate) fn get_policy(vm_name: &str, xml: &str) -> Result<VmPolicy, WolGatewayError> {
+    let invalid = |e: String| WolGatewayError::MetadataPolicyError(vm_name.to_string(), e);
+
+    let element: PolicyElement = serde_xml_rs::from_str(xml).map_err(|e| invalid(e.to_string()))?;
+
+    let networks = |list: Option<String>| {
+        let list: Vec<String> = list
+            .iter()
+            .flat_map(|list| list.split(','))
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(str::to_string)
+            .collect();
+        parse_networks(&list)
+    };
+
+    let enabled = match element.enabled.as_deref() {
+        Some("yes" | "true") => true,
+        Some("no" | "false") | None => false,
+        Some(other) => {
+            return Err(invalid(format!(
+                "Invalid enabled '{}': expected \"yes\" or \"no\"",
+                other
+            )))
+        }
+    };
+
+    let policy = VmPolicy {
+        enabled,
+        password: element
+            .password
+            .as_deref()
+            .map(parse_secureon_password_string)
+            .transpose()
+            .map_err(|e| invalid(e.to_string()))?,
+        sources: SourceFilter {
+            allow: networks(element.allow).map_err(|e| invalid(e.to_string()))?,
+            deny: networks(element.deny).map_err(|e| invalid(e.to_string()))?,
+        },
+        action: None,
+        managed_save: None,
+        restart_crashed: None,
+    };
+
+    Ok(policy)
+}
@@ -1,5 +1,35 @@
 use std::error::Error;
 use std::fmt;
+use std::io::ErrorKind;
+
+use virt::error::ErrorNumber;
+
+/// Exit code for invalid input data, from sysexits.h.
+const EX_DATAERR: u8 = 65;
+
+/// Exit code for a missing or unreadable input file, from sysexits.h.
+const EX_NOINPUT: u8 = 66;
+
+/// Exit code for an unavailable service, from sysexits.h.
+const EX_UNAVAILABLE: u8 = 69;
+
+/// Exit code for an internal error, from sysexits.h.
+const EX_SOFTWARE: u8 = 70;
+
+/// Exit code for an operating system error, from sysexits.h.
+const EX_OSERR: u8 = 71;
+
+/// Exit code for an I/O error, from sysexits.h.
+const EX_IOERR: u8 = 74;
+
+/// Exit code for a temporary failure, from sysexits.h.
+const EX_TEMPFAIL: u8 = 75;
+
+/// Exit code for insufficient permissions, from sysexits.h.
+const EX_NOPERM: u8 = 77;
+
+/// Exit code for a configuration error, from sysexits.h.
+const EX_CONFIG: u8 = 78;
 
 /// Error types that can occur during Wake-on-LAN gateway operations.
 ///
@@ -15,10 +45,25 @@ pub(crate) enum WolGatewayError {
 
     /// Error occurred while binding to a socket.
     ///
-    /// This variant wraps `std::io::Error` specifically for socket binding
-    /// operations, such as when attempting to bind to an address that is
+    /// This variant contains the address and the `std::io::Error` of the failed
+    /// bind operation, such as when attempting to bind to an address that is
     /// already in use.
-    SocketBindError(std::io::Error),
+    SocketBindError(std::net::SocketAddr, std::io::Error),
+
+    /// Error occurred while joining a multicast group.
+    ///
+    /// This variant contains the group and the underlying I/O error.
+    MulticastJoinError(std::net::Ipv6Addr, std::io::Error),
+
+    /// Error occurred while taking over sockets passed by systemd.
+    ///
+    /// This variant wraps `std::io::Error` for invalid `LISTEN_FDS` sockets.
+    SocketActivationError(std::io::Error),
+
+    /// Error occurred while installing a signal handler.
+    ///
+    /// This variant wraps `std::io::Error` from registering the handler.
+    SignalHandlerError(std::io::Error),
 
     /// Error occurred while connecting to libvirt.
     ///
@@ -26,18 +71,38 @@ pub(crate) enum WolGatewayError {
     /// libvirt connection and operation errors.
     LibvirtConnectError(virt::error::Error),
 
+    /// No libvirt connection is currently available.
+    ///
+    /// This variant contains the libvirt URI that is being reconnected to.
+    LibvirtUnavailable(String),
+
     /// Error occurred during UDP receive operations.
     ///
     /// This variant wraps `std::io::Error` specifically for UDP socket
     /// receive operations.
     UdpReceiveError(std::io::Error),
 
+    /// Error occurred while sending a UDP packet.
+    ///
+    /// This variant wraps `std::io::Error` for UDP socket send operations.
+    UdpSendError(std::io::Error),
+
     /// No VM found with the specified MAC address.
     ///
     /// This variant indicates that no virtual machine was found with a network
     /// interface matching the requested MAC address.
     VmNotFound(String),
 
+    /// Several domains share the MAC address of a wake request.
+    ///
+    /// This variant contains the MAC address and the names of the domains.
+    AmbiguousMac(String, Vec<String>),
+
+    /// No domain exists with the specified name or UUID.
+    ///
+    /// This variant contains the requested name or UUID.
+    DomainNotFound(String),
+
     /// Error occurred while listing libvirt domains.
     ///
     /// This variant wraps `virt::error::Error` for domain listing operations.
@@ -53,6 +118,11 @@ pub(crate) enum WolGatewayError {
     /// This variant contains the error message from MAC address extraction failures.
     MacExtractionError(serde_xml_rs::Error),
 
+    /// The WOL policy in the metadata of a domain is invalid.
+    ///
+    /// This variant contains the name of the domain and a description of the problem.
+    MetadataPolicyError(String, String),
+
     /// Error occurred while retrieving domain UUID.
     ///
     /// This variant wraps `virt::error::Error` for domain UUID retrieval operations.
@@ -83,32 +153,367 @@ pub(crate) enum WolGatewayError {
     /// This variant wraps `virt::error::Error` for domain resume operations.
     DomainResumeError(virt::error::Error),
 
+    /// A guest suspended to RAM cannot be woken up, as PM wakeup is not supported.
+    ///
+    /// This variant contains the name of the VM.
+    WakeupUnsupported(String),
+
     /// Error occurred while parsing a wake-on-lan packet.
     ///
     /// This variant contains the specific parsing error as a string
     WakeOnLanParseError(String),
+
+    /// The SecureOn password of a magic packet did not match the required one.
+    ///
+    /// This variant contains the name of the VM whose password check failed.
+    PasswordMismatch(String),
+
+    /// Error occurred while creating or binding a raw Ethernet socket.
+    ///
+    /// This variant wraps `std::io::Error` for `AF_PACKET` socket setup, such
+    /// as when the process lacks the `CAP_NET_RAW` capability.
+    RawSocketError(std::io::Error),
+
+    /// Error occurred during raw Ethernet receive operations.
+    ///
+    /// This variant wraps `std::io::Error` specifically for `AF_PACKET` socket
+    /// receive operations.
+    EthernetReceiveError(std::io::Error),
+
+    /// No network interface exists with the specified name.
+    ///
+    /// This variant contains the name of the missing interface.
+    InterfaceNotFound(String),
+
+    /// The source of a wake request is not in the allowed networks.
+    ///
+    /// This variant contains the rejected source, an IP address or, for raw
+    /// Ethernet frames that cannot be matched against networks, the frame source.
+    SourceNotAllowed(String),
+
+    /// A sender exceeded its rate limit.
+    ///
+    /// This variant contains the limited sender, an IP address or a source MAC
+    /// address on an interface.
+    RateLimited(String),
+
+    /// A wake or state request carries no token, but authentication is required.
+    AuthenticationRequired,
+
+    /// The token of a wake or state request was rejected.
+    ///
+    /// This variant contains the reason and the client name.
+    AuthenticationFailed(String),
+
+    /// Waking the VM is disabled by its policy.
+    ///
+    /// This variant contains the name of the disabled VM.
+    VmDisabled(String),
+
+    /// The VM did not opt in through its metadata, or is not selected for waking
+    /// in opt-in mode.
+    ///
+    /// This variant contains the name of the VM.
+    VmNotWakeable(String),
+
+    /// A crashed VM is not restarted by its policy.
+    ///
+    /// This variant contains the name of the crashed VM.
+    CrashedNotRestarted(String),
+
+    /// A request to the HTTP API is malformed.
+    ///
+    /// This variant contains a description of the problem.
+    InvalidApiRequest(String),
+
+    /// Error occurred while reading the configuration file.
+    ///
+    /// This variant wraps `std::io::Error` for configuration file reads.
+    ConfigReadError(std::io::Error),
+
+    /// Error occurred while parsing the configuration file.
+    ///
+    /// This variant wraps `toml::de::Error` for malformed TOML or unknown settings.
+    ConfigParseError(toml::de::Error),
+
+    /// The gateway configuration is invalid.
+    ///
+    /// This variant contains a description of the offending setting.
+    ConfigError(String),
+
+    /// Error occurred while starting the libvirt worker thread.
+    ///
+    /// This variant wraps `std::io::Error` for thread spawning operations.
+    WorkerSpawnError(std::io::Error),
+
+    /// Error occurred while starting the async runtime.
+    ///
+    /// This variant wraps `std::io::Error` for runtime creation.
+    RuntimeError(std::io::Error),
+
+    /// The libvirt worker has too many pending wake requests.
+    ///
+    /// This variant contains the target of the dropped request.
+    WorkerQueueFull(String),
+
+    /// The libvirt worker has stopped and no longer accepts wake requests.
+    WorkerStopped,
+
+    /// All packet listeners have stopped.
+    ///
+    /// Critical receive errors stop a listener, the gateway stops once none is left.
+    ListenersStopped,
+
+    /// Queued wake requests did not finish within the shutdown timeout.
+    ///
+    /// This variant contains the timeout. A VM may still be starting.
+    ShutdownTimeout(std::time::Duration),
+}
+
+impl WolGatewayError {
+    /// Returns the name of the error variant, e.g. for use as a metrics label.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            WolGatewayError::AddressParseError(_) => "AddressParseError",
+            WolGatewayError::SocketBindError(..) => "SocketBindError",
+            WolGatewayError::MulticastJoinError(..) => "MulticastJoinError",
+            WolGatewayError::SocketActivationError(_) => "SocketActivationError",
+            WolGatewayError::SignalHandlerError(_) => "SignalHandlerError",
+            WolGatewayError::LibvirtConnectError(_) => "LibvirtConnectError",
+            WolGatewayError::LibvirtUnavailable(_) => "LibvirtUnavailable",
+            WolGatewayError::UdpReceiveError(_) => "UdpReceiveError",
+            WolGatewayError::UdpSendError(_) => "UdpSendError",
+            WolGatewayError::VmNotFound(_) => "VmNotFound",
+            WolGatewayError::AmbiguousMac(..) => "AmbiguousMac",
+            WolGatewayError::DomainNotFound(_) => "DomainNotFound",
+            WolGatewayError::DomainListError(_) => "DomainListError",
+            WolGatewayError::DomainXmlError(_) => "DomainXmlError",
+            WolGatewayError::MacExtractionError(_) => "MacExtractionError",
+            WolGatewayError::MetadataPolicyError(..) => "MetadataPolicyError",
+            WolGatewayError::DomainUuidError(_) => "DomainUuidError",
+            WolGatewayError::DomainLookupError(_) => "DomainLookupError",
+            WolGatewayError::DomainNameError(_) => "DomainNameError",
+            WolGatewayError::DomainStateError(_) => "DomainStateError",
+            WolGatewayError::DomainStartError(_) => "DomainStartError",
+            WolGatewayError::DomainResumeError(_) => "DomainResumeError",
+            WolGatewayError::WakeupUnsupported(_) => "WakeupUnsupported",
+            WolGatewayError::WakeOnLanParseError(_) => "WakeOnLanParseError",
+            WolGatewayError::PasswordMismatch(_) => "PasswordMismatch",
+            WolGatewayError::RawSocketError(_) => "RawSocketError",
+            WolGatewayError::EthernetReceiveError(_) => "EthernetReceiveError",
+            WolGatewayError::InterfaceNotFound(_) => "InterfaceNotFound",
+            WolGatewayError::SourceNotAllowed(_) => "SourceNotAllowed",
+            WolGatewayError::RateLimited(_) => "RateLimited",
+            WolGatewayError::AuthenticationRequired => "AuthenticationRequired",
+            WolGatewayError::AuthenticationFailed(_) => "AuthenticationFailed",
+            WolGatewayError::VmDisabled(_) => "VmDisabled",
+            WolGatewayError::VmNotWakeable(_) => "VmNotWakeable",
+            WolGatewayError::CrashedNotRestarted(_) => "CrashedNotRestarted",
+            WolGatewayError::InvalidApiRequest(_) => "InvalidApiRequest",
+            WolGatewayError::ConfigReadError(_) => "ConfigReadError",
+            WolGatewayError::ConfigParseError(_) => "ConfigParseError",
+            WolGatewayError::ConfigError(_) => "ConfigError",
+            WolGatewayError::WorkerSpawnError(_) => "WorkerSpawnError",
+            WolGatewayError::RuntimeError(_) => "RuntimeError",
+            WolGatewayError::WorkerQueueFull(_) => "WorkerQueueFull",
+            WolGatewayError::WorkerStopped => "WorkerStopped",
+            WolGatewayError::ListenersStopped => "ListenersStopped",
+            WolGatewayError::ShutdownTimeout(_) => "ShutdownTimeout",
+        }
+    }
+
+    /// Returns the sysexits-style exit code of the gateway when stopped by this error.
+    ///
+    /// Startup errors are grouped by cause, so a service manager or script can
+    /// tell a configuration error from an unavailable libvirt daemon. Errors
+    /// handled per wake request never stop the gateway and map to `EX_SOFTWARE`.
+    pub(crate) fn exit_code(&self) -> u8 {
+        match self {
+            WolGatewayError::AddressParseError(_)
+            | WolGatewayError::ConfigParseError(_)
+            | WolGatewayError::ConfigError(_)
+            | WolGatewayError::WakeOnLanParseError(_)
+            | WolGatewayError::InterfaceNotFound(_)
+            | WolGatewayError::MulticastJoinError(..)
+            | WolGatewayError::SocketActivationError(_) => EX_CONFIG,
+            WolGatewayError::ConfigReadError(_) => EX_NOINPUT,
+            WolGatewayError::LibvirtConnectError(_)
+            | WolGatewayError::LibvirtUnavailable(_)
+            | WolGatewayError::DomainListError(_)
+            | WolGatewayError::DomainXmlError(_) => EX_UNAVAILABLE,
+            WolGatewayError::MacExtractionError(_) => EX_DATAERR,
+            WolGatewayError::SocketBindError(_, e) | WolGatewayError::RawSocketError(e)
+                if e.kind() == ErrorKind::PermissionDenied =>
+            {
+                EX_NOPERM
+            }
+            WolGatewayError::SocketBindError(..)
+            | WolGatewayError::RawSocketError(_)
+            | WolGatewayError::SignalHandlerError(_)
+            | WolGatewayError::WorkerSpawnError(_)
+            | WolGatewayError::RuntimeError(_) => EX_OSERR,
+            WolGatewayError::UdpReceiveError(_)
+            | WolGatewayError::UdpSendError(_)
+            | WolGatewayError::EthernetReceiveError(_)
+            | WolGatewayError::ListenersStopped => EX_IOERR,
+            WolGatewayError::ShutdownTimeout(_) => EX_TEMPFAIL,
+            WolGatewayError::VmNotFound(_)
+            | WolGatewayError::AmbiguousMac(..)
+            | WolGatewayError::DomainNotFound(_)
+            | WolGatewayError::MetadataPolicyError(..)
+            | WolGatewayError::DomainUuidError(_)
+            | WolGatewayError::DomainLookupError(_)
+            | WolGatewayError::DomainNameError(_)
+            | WolGatewayError::DomainStateError(_)
+            | WolGatewayError::DomainStartError(_)
+            | WolGatewayError::DomainResumeError(_)
+            | WolGatewayError::WakeupUnsupported(_)
+            | WolGatewayError::PasswordMismatch(_)
+            | WolGatewayError::SourceNotAllowed(_)
+            | WolGatewayError::RateLimited(_)
+            | WolGatewayError::AuthenticationRequired
+            | WolGatewayError::AuthenticationFailed(_)
+            | WolGatewayError::VmDisabled(_)
+            | WolGatewayError::VmNotWakeable(_)
+            | WolGatewayError::CrashedNotRestarted(_)
+            | WolGatewayError::InvalidApiRequest(_)
+            | WolGatewayError::WorkerQueueFull(_)
+            | WolGatewayError::WorkerStopped => EX_SOFTWARE,
+        }
+    }
+
+    /// Returns whether the error means the libvirt connection itself failed,
+    /// e.g. because libvirtd restarted, rather than the requested operation.
+    ///
+    /// Only the error codes libvirt reports for a missing or broken connection
+    /// count. System errors are left out, since they are also reported when an
+    /// operation like starting a domain fails on the host, and retrying those
+    /// could start a VM twice.
+    pub(crate) fn is_connection_error(&self) -> bool {
+        let e = match self {
+            WolGatewayError::DomainListError(e)
+            | WolGatewayError::DomainXmlError(e)
+            | WolGatewayError::DomainUuidError(e)
+            | WolGatewayError::DomainLookupError(e)
+            | WolGatewayError::DomainNameError(e)
+            | WolGatewayError::DomainStateError(e)
+            | WolGatewayError::DomainStartError(e)
+            | WolGatewayError::DomainResumeError(e) => e,
+            _ => return false,
+        };
+
+        matches!(
+            e.code(),
+            ErrorNumber::Rpc | ErrorNumber::NoConnect | ErrorNumber::InvalidConn
+        )
+    }
 }
 
 impl fmt::Display for WolGatewayError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             WolGatewayError::AddressParseError(e) => write!(f, "Address parsing error: {}", e),
-            WolGatewayError::SocketBindError(e) => write!(f, "Socket bind error: {}", e),
+            WolGatewayError::SocketBindError(address, e) => {
+                write!(f, "Failed to bind {}: {}", address, e)
+            }
+            WolGatewayError::MulticastJoinError(group, e) => {
+                write!(f, "Failed to join multicast group {}: {}", group, e)
+            }
+            WolGatewayError::SocketActivationError(e) => {
+                write!(f, "Socket activation error: {}", e)
+            }
+            WolGatewayError::SignalHandlerError(e) => {
+                write!(f, "Failed to install signal handler: {}", e)
+            }
             WolGatewayError::LibvirtConnectError(e) => write!(f, "Libvirt connection error: {}", e),
             WolGatewayError::UdpReceiveError(e) => write!(f, "UDP receive error: {}", e),
+            WolGatewayError::UdpSendError(e) => write!(f, "UDP send error: {}", e),
+            WolGatewayError::LibvirtUnavailable(uri) => {
+                write!(f, "Libvirt connection unavailable: {}", uri)
+            }
             WolGatewayError::VmNotFound(mac) => write!(f, "No VM found with MAC address: {}", mac),
+            WolGatewayError::AmbiguousMac(mac, domains) => write!(
+                f,
+                "MAC address {} is used by several domains: {}",
+                mac,
+                domains.join(", ")
+            ),
+            WolGatewayError::DomainNotFound(domain) => {
+                write!(f, "No domain found with name or UUID: {}", domain)
+            }
             WolGatewayError::DomainListError(e) => write!(f, "Failed to list domains: {}", e),
             WolGatewayError::DomainXmlError(e) => write!(f, "Failed to get domain XML: {}", e),
             WolGatewayError::MacExtractionError(e) => {
                 write!(f, "Failed to extract MAC addresses: {}", e)
             }
+            WolGatewayError::MetadataPolicyError(vm, e) => {
+                write!(f, "Invalid WOL policy in metadata of VM {}: {}", vm, e)
+            }
             WolGatewayError::DomainUuidError(e) => write!(f, "Failed to get domain UUID: {}", e),
             WolGatewayError::DomainLookupError(e) => write!(f, "Failed to lookup domain: {}", e),
             WolGatewayError::DomainNameError(e) => write!(f, "Failed to get domain name: {}", e),
             WolGatewayError::DomainStateError(e) => write!(f, "Failed to get domain state: {}", e),
             WolGatewayError::DomainStartError(e) => write!(f, "Failed to start domain: {}", e),
             WolGatewayError::DomainResumeError(e) => write!(f, "Failed to resume domain: {}", e),
+            WolGatewayError::WakeupUnsupported(vm) => write!(
+                f,
+                "PM wakeup not supported: cannot wake up VM {} from suspend to RAM",
+                vm
+            ),
             WolGatewayError::WakeOnLanParseError(e) => write!(f, "Parsing error: {}", e),
+            WolGatewayError::PasswordMismatch(vm) => {
+                write!(f, "SecureOn password mismatch for VM: {}", vm)
+            }
+            WolGatewayError::RawSocketError(e) => write!(f, "Raw socket error: {}", e),
+            WolGatewayError::EthernetReceiveError(e) => {
+                write!(f, "Ethernet receive error: {}", e)
+            }
+            WolGatewayError::InterfaceNotFound(name) => {
+                write!(f, "No network interface found with name: {}", name)
+            }
+            WolGatewayError::SourceNotAllowed(source) => {
+                write!(f, "Source address not allowed: {}", source)
+            }
+            WolGatewayError::RateLimited(sender) => {
+                write!(f, "Rate limit exceeded by source: {}", sender)
+            }
+            WolGatewayError::AuthenticationRequired => {
+                write!(f, "Request carries no authentication token")
+            }
+            WolGatewayError::AuthenticationFailed(e) => write!(f, "Authentication failed: {}", e),
+            WolGatewayError::VmDisabled(vm) => write!(f, "Waking VM is disabled: {}", vm),
+            WolGatewayError::VmNotWakeable(vm) => {
+                write!(f, "VM {} has not opted in to waking", vm)
+            }
+            WolGatewayError::CrashedNotRestarted(vm) => {
+                write!(f, "Not restarting crashed VM: {}", vm)
+            }
+            WolGatewayError::InvalidApiRequest(e) => write!(f, "Invalid API request: {}", e),
+            WolGatewayError::ConfigReadError(e) => {
+                write!(f, "Failed to read configuration file: {}", e)
+            }
+            WolGatewayError::ConfigParseError(e) => {
+                write!(f, "Failed to parse configuration file: {}", e)
+            }
+            WolGatewayError::ConfigError(e) => write!(f, "Configuration error: {}", e),
+            WolGatewayError::WorkerSpawnError(e) => {
+                write!(f, "Failed to start libvirt worker: {}", e)
+            }
+            WolGatewayError::RuntimeError(e) => write!(f, "Failed to start async runtime: {}", e),
+            WolGatewayError::WorkerQueueFull(target) => {
+                write!(
+                    f,
+                    "Libvirt worker queue full, dropping request for {}",
+                    target
+                )
+            }
+            WolGatewayError::WorkerStopped => write!(f, "Libvirt worker has stopped"),
+            WolGatewayError::ListenersStopped => write!(f, "All listeners have stopped"),
+            WolGatewayError::ShutdownTimeout(timeout) => write!(
+                f,
+                "Queued wake requests did not finish within {}s, a VM may still be starting",
+                timeout.as_secs()
+            ),
         }
     }
 }
@@ -117,22 +522,107 @@ impl fmt::Debug for WolGatewayError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             WolGatewayError::AddressParseError(e) => write!(f, "Address parsing error: {}", e),
-            WolGatewayError::SocketBindError(e) => write!(f, "Socket bind error: {}", e),
+            WolGatewayError::SocketBindError(address, e) => {
+                write!(f, "Failed to bind {}: {}", address, e)
+            }
+            WolGatewayError::MulticastJoinError(group, e) => {
+                write!(f, "Failed to join multicast group {}: {}", group, e)
+            }
+            WolGatewayError::SocketActivationError(e) => {
+                write!(f, "Socket activation error: {}", e)
+            }
+            WolGatewayError::SignalHandlerError(e) => {
+                write!(f, "Failed to install signal handler: {}", e)
+            }
             WolGatewayError::LibvirtConnectError(e) => write!(f, "Libvirt connection error: {}", e),
             WolGatewayError::UdpReceiveError(e) => write!(f, "UDP receive error: {}", e),
+            WolGatewayError::UdpSendError(e) => write!(f, "UDP send error: {}", e),
+            WolGatewayError::LibvirtUnavailable(uri) => {
+                write!(f, "Libvirt connection unavailable: {}", uri)
+            }
             WolGatewayError::VmNotFound(mac) => write!(f, "No VM found with MAC address: {}", mac),
+            WolGatewayError::AmbiguousMac(mac, domains) => write!(
+                f,
+                "MAC address {} is used by several domains: {}",
+                mac,
+                domains.join(", ")
+            ),
+            WolGatewayError::DomainNotFound(domain) => {
+                write!(f, "No domain found with name or UUID: {}", domain)
+            }
             WolGatewayError::DomainListError(e) => write!(f, "Failed to list domains: {}", e),
             WolGatewayError::DomainXmlError(e) => write!(f, "Failed to get domain XML: {}", e),
             WolGatewayError::MacExtractionError(e) => {
                 write!(f, "Failed to extract MAC addresses: {}", e)
             }
+            WolGatewayError::MetadataPolicyError(vm, e) => {
+                write!(f, "Invalid WOL policy in metadata of VM {}: {}", vm, e)
+            }
             WolGatewayError::DomainUuidError(e) => write!(f, "Failed to get domain UUID: {}", e),
             WolGatewayError::DomainLookupError(e) => write!(f, "Failed to lookup domain: {}", e),
             WolGatewayError::DomainNameError(e) => write!(f, "Failed to get domain name: {}", e),
             WolGatewayError::DomainStateError(e) => write!(f, "Failed to get domain state: {}", e),
             WolGatewayError::DomainStartError(e) => write!(f, "Failed to start domain: {}", e),
             WolGatewayError::DomainResumeError(e) => write!(f, "Failed to resume domain: {}", e),
+            WolGatewayError::WakeupUnsupported(vm) => write!(
+                f,
+                "PM wakeup not supported: cannot wake up VM {} from suspend to RAM",
+                vm
+            ),
             WolGatewayError::WakeOnLanParseError(e) => write!(f, "Parsing error: {}", e),
+            WolGatewayError::PasswordMismatch(vm) => {
+                write!(f, "SecureOn password mismatch for VM: {}", vm)
+            }
+            WolGatewayError::RawSocketError(e) => write!(f, "Raw socket error: {}", e),
+            WolGatewayError::EthernetReceiveError(e) => {
+                write!(f, "Ethernet receive error: {}", e)
+            }
+            WolGatewayError::InterfaceNotFound(name) => {
+                write!(f, "No network interface found with name: {}", name)
+            }
+            WolGatewayError::SourceNotAllowed(source) => {
+                write!(f, "Source address not allowed: {}", source)
+            }
+            WolGatewayError::RateLimited(sender) => {
+                write!(f, "Rate limit exceeded by source: {}", sender)
+            }
+            WolGatewayError::AuthenticationRequired => {
+                write!(f, "Request carries no authentication token")
+            }
+            WolGatewayError::AuthenticationFailed(e) => write!(f, "Authentication failed: {}", e),
+            WolGatewayError::VmDisabled(vm) => write!(f, "Waking VM is disabled: {}", vm),
+            WolGatewayError::VmNotWakeable(vm) => {
+                write!(f, "VM {} has not opted in to waking", vm)
+            }
+            WolGatewayError::CrashedNotRestarted(vm) => {
+                write!(f, "Not restarting crashed VM: {}", vm)
+            }
+            WolGatewayError::InvalidApiRequest(e) => write!(f, "Invalid API request: {}", e),
+            WolGatewayError::ConfigReadError(e) => {
+                write!(f, "Failed to read configuration file: {}", e)
+            }
+            WolGatewayError::ConfigParseError(e) => {
+                write!(f, "Failed to parse configuration file: {}", e)
+            }
+            WolGatewayError::ConfigError(e) => write!(f, "Configuration error: {}", e),
+            WolGatewayError::WorkerSpawnError(e) => {
+                write!(f, "Failed to start libvirt worker: {}", e)
+            }
+            WolGatewayError::RuntimeError(e) => write!(f, "Failed to start async runtime: {}", e),
+            WolGatewayError::WorkerQueueFull(target) => {
+                write!(
+                    f,
+                    "Libvirt worker queue full, dropping request for {}",
+                    target
+                )
+            }
+            WolGatewayError::WorkerStopped => write!(f, "Libvirt worker has stopped"),
+            WolGatewayError::ListenersStopped => write!(f, "All listeners have stopped"),
+            WolGatewayError::ShutdownTimeout(timeout) => write!(
+                f,
+                "Queued wake requests did not finish within {}s, a VM may still be starting",
+                timeout.as_secs()
+            ),
         }
     }
 }
@@ -145,18 +635,6 @@ impl From<std::net::AddrParseError> for WolGatewayError {
     }
 }
 
-impl From<std::io::Error> for WolGatewayError {
-    fn from(err: std::io::Error) -> Self {
-        // Differentiate between bind and receive errors
-        // A more robust way might be needed depending on io::Error variants
-        if err.to_string().contains("address already in use") {
-            WolGatewayError::SocketBindError(err)
-        } else {
-            WolGatewayError::UdpReceiveError(err)
-        }
-    }
-}
-
 impl From<virt::error::Error> for WolGatewayError {
     fn from(err: virt::error::Error) -> Self {
         WolGatewayError::LibvirtConnectError(err)
@@ -0,0 +1,121 @@
+//! Raw Ethernet listener for Wake-on-LAN frames.
+//!
+//! Tools such as `etherwake` and many router firmwares send magic packets as raw
+//! Ethernet frames with EtherType 0x0842 instead of UDP datagrams. This module
+//! provides an `AF_PACKET` listener bound to a single network interface (usually
+//! the bridge the VMs are attached to) that receives those frames.
+
+use std::os::fd::AsRawFd;
+
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::{bind, recvfrom, LinkAddr};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+use crate::error::WolGatewayError;
+use crate::wakeonlan::MacAddress;
+
+/// EtherType reserved for Wake-on-LAN magic packets.
+const ETHERTYPE_WOL: u16 = 0x0842;
+
+/// Listener receiving Wake-on-LAN frames from a single network interface.
+///
+/// The socket is opened in cooked (`SOCK_DGRAM`) mode, so the kernel strips the
+/// Ethernet header and only the magic packet payload is returned.
+pub(crate) struct EthernetListener {
+    /// The non-blocking `AF_PACKET` socket registered with the tokio reactor.
+    socket: AsyncFd<Socket>,
+    /// Name of the interface the socket is bound to.
+    interface: String,
+}
+
+impl EthernetListener {
+    /// Opens a packet socket for Wake-on-LAN frames and binds it to an interface.
+    ///
+    /// This requires the `CAP_NET_RAW` capability.
+    ///
+    /// # Arguments
+    ///
+    /// * `interface` - Name of the network interface to listen on (e.g. "br0")
+    ///
+    /// # Errors
+    ///
+    /// Returns `InterfaceNotFound` if the interface does not exist, or
+    /// `RawSocketError` if the socket could not be created or bound.
+    pub(crate) fn bind(interface: &str) -> Result<Self, WolGatewayError> {
+        let link_addr = find_link_addr(interface)?;
+
+        let protocol = Protocol::from(i32::from(ETHERTYPE_WOL.to_be()));
+        let socket = Socket::new(Domain::PACKET, Type::DGRAM, Some(protocol))
+            .map_err(WolGatewayError::RawSocketError)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(WolGatewayError::RawSocketError)?;
+
+        // The link address from getifaddrs carries no protocol, so the kernel
+        // keeps the EtherType the socket was opened with.
+        bind(socket.as_raw_fd(), &link_addr)
+            .map_err(|e| WolGatewayError::RawSocketError(e.into()))?;
+
+        let socket = AsyncFd::new(socket).map_err(WolGatewayError::RawSocketError)?;
+
+        Ok(EthernetListener {
+            socket,
+            interface: interface.to_string(),
+        })
+    }
+
+    /// Returns the name of the interface the listener is bound to.
+    pub(crate) fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Receives a single Wake-on-LAN frame payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to store the payload in; longer payloads are truncated
+    ///
+    /// # Returns
+    ///
+    /// The payload length and the source MAC address of the frame, if known
+    pub(crate) async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Option<MacAddress>), WolGatewayError> {
+        loop {
+            let mut guard = self
+                .socket
+                .readable()
+                .await
+                .map_err(WolGatewayError::EthernetReceiveError)?;
+
+            let result = guard.try_io(|socket| {
+                recvfrom::<LinkAddr>(socket.as_raw_fd(), buf).map_err(std::io::Error::from)
+            });
+
+            if let Ok(result) = result {
+                let (len, addr) = result.map_err(WolGatewayError::EthernetReceiveError)?;
+                return Ok((len, addr.and_then(|addr| addr.addr())));
+            }
+        }
+    }
+}
+
+/// Looks up the link-layer address of a network interface by name.
+///
+/// # Arguments
+///
+/// * `interface` - Name of the network interface
+///
+/// # Returns
+///
+/// `Result<LinkAddr, WolGatewayError>` if the interface exists, error otherwise
+fn find_link_addr(interface: &str) -> Result<LinkAddr, WolGatewayError> {
+    let addrs = getifaddrs().map_err(|e| WolGatewayError::RawSocketError(e.into()))?;
+
+    addrs
+        .filter(|ifaddr| ifaddr.interface_name == interface)
+        .find_map(|ifaddr| ifaddr.address?.as_link_addr().copied())
+        .ok_or_else(|| WolGatewayError::InterfaceNotFound(interface.to_string()))
+}
@@ -3,20 +3,58 @@
 //! This module provides functionality to interact with libvirt domains (VMs),
 //! including starting VMs by UUID or MAC address and managing domain states.
 
-use log::{debug, error, info};
+use std::fmt;
+use std::time::Instant;
+
+use log::{debug, error, info, warn};
 use uuid::Uuid;
 use virt::connect::Connect;
 use virt::domain::Domain;
+use virt::error::ErrorNumber;
+use virt::sys::{
+    VIR_DOMAIN_METADATA_DESCRIPTION, VIR_DOMAIN_METADATA_ELEMENT, VIR_DOMAIN_METADATA_TITLE,
+    VIR_DOMAIN_START_FORCE_BOOT,
+};
 
+use crate::domain_xml::{get_policy, POLICY_NAMESPACE};
 use crate::error::WolGatewayError;
+use crate::mac_index::MacIndex;
+use crate::metrics;
+use crate::policy::{ManagedSave, Policy, StartOptions, VmPolicy, WakeAction, WakeRequest};
+
+/// A VM a wake request was carried out for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResolvedVm {
+    /// The name of the domain.
+    pub(crate) name: String,
+    /// The UUID of the domain.
+    pub(crate) uuid: Uuid,
+    /// The state the domain was in when the request was received.
+    pub(crate) state: DomainState,
+    /// What was done to the domain, or would have been done in a dry run.
+    pub(crate) action: StartAction,
+}
+
+/// The current state of a VM, as reported over the HTTP API.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct VmStatus {
+    /// The name of the domain.
+    pub(crate) name: String,
+    /// The UUID of the domain.
+    pub(crate) uuid: Uuid,
+    /// The state the domain is in.
+    pub(crate) state: DomainState,
+    /// Why the domain is in its state.
+    pub(crate) reason: StateReason,
+}
 
 /// Represents the various states a libvirt domain (VM) can be in.
 ///
 /// This enum maps to the libvirt domain state codes and provides
 /// a type-safe way to handle VM state information.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
-enum DomainState {
+pub(crate) enum DomainState {
     /// Domain state is unknown or not set
     NoState = 0,
     /// Domain is running and active
@@ -31,7 +69,7 @@ enum DomainState {
     Shutoff = 5,
     /// Domain has crashed
     Crashed = 6,
-    /// Domain is suspended to disk (power management)
+    /// Domain is suspended by guest power management (to RAM or disk)
     PmSuspended = 7,
     /// Last state marker
     Last = 8,
@@ -62,21 +100,371 @@ impl From<u32> for DomainState {
     }
 }
 
+impl fmt::Display for DomainState {
+    /// Formats the state the way `virsh domstate` reports it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DomainState::NoState | DomainState::Last => "no state",
+            DomainState::Running => "running",
+            DomainState::Blocked => "idle",
+            DomainState::Paused => "paused",
+            DomainState::Shutdown => "in shutdown",
+            DomainState::Shutoff => "shut off",
+            DomainState::Crashed => "crashed",
+            DomainState::PmSuspended => "pmsuspended",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reasons a domain is shut off, from libvirt's `virDomainShutoffReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShutoffReason {
+    /// The reason is unknown
+    Unknown,
+    /// The guest shut down normally
+    Shutdown,
+    /// The domain was destroyed
+    Destroyed,
+    /// The guest crashed
+    Crashed,
+    /// The domain was migrated to another host
+    Migrated,
+    /// The domain was saved to a file
+    Saved,
+    /// The domain failed to start
+    Failed,
+    /// The domain was restored from a snapshot taken while shut off
+    FromSnapshot,
+    /// The daemon decided to kill the domain during reconnection
+    Daemon,
+}
+
+impl From<i32> for ShutoffReason {
+    fn from(reason: i32) -> Self {
+        match reason {
+            1 => ShutoffReason::Shutdown,
+            2 => ShutoffReason::Destroyed,
+            3 => ShutoffReason::Crashed,
+            4 => ShutoffReason::Migrated,
+            5 => ShutoffReason::Saved,
+            6 => ShutoffReason::Failed,
+            7 => ShutoffReason::FromSnapshot,
+            8 => ShutoffReason::Daemon,
+            _ => ShutoffReason::Unknown,
+        }
+    }
+}
+
+/// Reasons a domain is paused, from libvirt's `virDomainPausedReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PausedReason {
+    /// The reason is unknown
+    Unknown,
+    /// The domain was paused by the user
+    User,
+    /// The domain is paused for offline migration
+    Migration,
+    /// The domain is paused while being saved
+    Save,
+    /// The domain is paused while its core is dumped
+    Dump,
+    /// The domain is paused after a disk I/O error
+    IoError,
+    /// The domain is paused by a watchdog action
+    Watchdog,
+    /// The domain was restored from a snapshot
+    FromSnapshot,
+    /// The domain is paused while shutting down
+    ShuttingDown,
+    /// The domain is paused while a snapshot is taken
+    Snapshot,
+    /// The guest crashed and the domain is kept for inspection
+    Crashed,
+    /// The domain is paused while starting up
+    StartingUp,
+    /// The domain is paused during post-copy migration
+    Postcopy,
+    /// The domain is paused after a failed post-copy migration
+    PostcopyFailed,
+    /// The domain is paused after a failed API call
+    ApiError,
+}
+
+impl From<i32> for PausedReason {
+    fn from(reason: i32) -> Self {
+        match reason {
+            1 => PausedReason::User,
+            2 => PausedReason::Migration,
+            3 => PausedReason::Save,
+            4 => PausedReason::Dump,
+            5 => PausedReason::IoError,
+            6 => PausedReason::Watchdog,
+            7 => PausedReason::FromSnapshot,
+            8 => PausedReason::ShuttingDown,
+            9 => PausedReason::Snapshot,
+            10 => PausedReason::Crashed,
+            11 => PausedReason::StartingUp,
+            12 => PausedReason::Postcopy,
+            13 => PausedReason::PostcopyFailed,
+            14 => PausedReason::ApiError,
+            _ => PausedReason::Unknown,
+        }
+    }
+}
+
+/// Reasons a domain is crashed, from libvirt's `virDomainCrashedReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CrashedReason {
+    /// The reason is unknown
+    Unknown,
+    /// The guest kernel panicked
+    Panicked,
+}
+
+impl From<i32> for CrashedReason {
+    fn from(reason: i32) -> Self {
+        match reason {
+            1 => CrashedReason::Panicked,
+            _ => CrashedReason::Unknown,
+        }
+    }
+}
+
+/// The reason a domain is in its current state.
+///
+/// Reason codes only have a meaning together with the state they were reported
+/// for, so they are decoded for the states whose reason affects the action taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StateReason {
+    /// Reason of a shut off domain
+    Shutoff(ShutoffReason),
+    /// Reason of a paused domain
+    Paused(PausedReason),
+    /// Reason of a crashed domain
+    Crashed(CrashedReason),
+    /// Undecoded reason code of any other state
+    Other(i32),
+}
+
+impl StateReason {
+    /// Decodes a libvirt reason code for the state it was reported with.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The state of the domain
+    /// * `reason` - The reason code reported together with the state
+    pub(crate) fn new(state: &DomainState, reason: i32) -> Self {
+        match state {
+            DomainState::Shutoff => StateReason::Shutoff(ShutoffReason::from(reason)),
+            DomainState::Paused => StateReason::Paused(PausedReason::from(reason)),
+            DomainState::Crashed => StateReason::Crashed(CrashedReason::from(reason)),
+            _ => StateReason::Other(reason),
+        }
+    }
+
+    /// Returns whether the domain is in its state because the guest crashed.
+    pub(crate) fn is_crash(&self) -> bool {
+        matches!(
+            self,
+            StateReason::Shutoff(ShutoffReason::Crashed)
+                | StateReason::Paused(PausedReason::Crashed)
+                | StateReason::Crashed(_)
+        )
+    }
+}
+
+impl fmt::Display for StateReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            StateReason::Shutoff(ShutoffReason::Unknown)
+            | StateReason::Paused(PausedReason::Unknown)
+            | StateReason::Crashed(CrashedReason::Unknown) => "unknown reason",
+            StateReason::Shutoff(ShutoffReason::Shutdown) => "guest shut down normally",
+            StateReason::Shutoff(ShutoffReason::Destroyed) => "domain was destroyed",
+            StateReason::Shutoff(ShutoffReason::Crashed) => "guest crashed",
+            StateReason::Shutoff(ShutoffReason::Migrated) => "migrated to another host",
+            StateReason::Shutoff(ShutoffReason::Saved) => "saved to a file",
+            StateReason::Shutoff(ShutoffReason::Failed) => "failed to start",
+            StateReason::Shutoff(ShutoffReason::FromSnapshot) => "restored from a snapshot",
+            StateReason::Shutoff(ShutoffReason::Daemon) => "killed by libvirtd",
+            StateReason::Paused(PausedReason::User) => "paused by the user",
+            StateReason::Paused(PausedReason::Migration) => "migrating",
+            StateReason::Paused(PausedReason::Save) => "being saved",
+            StateReason::Paused(PausedReason::Dump) => "dumping core",
+            StateReason::Paused(PausedReason::IoError) => "disk I/O error",
+            StateReason::Paused(PausedReason::Watchdog) => "watchdog fired",
+            StateReason::Paused(PausedReason::FromSnapshot) => "restored from a snapshot",
+            StateReason::Paused(PausedReason::ShuttingDown) => "shutting down",
+            StateReason::Paused(PausedReason::Snapshot) => "taking a snapshot",
+            StateReason::Paused(PausedReason::Crashed) => "guest crashed",
+            StateReason::Paused(PausedReason::StartingUp) => "starting up",
+            StateReason::Paused(PausedReason::Postcopy) => "post-copy migration",
+            StateReason::Paused(PausedReason::PostcopyFailed) => "post-copy migration failed",
+            StateReason::Paused(PausedReason::ApiError) => "libvirt API call failed",
+            StateReason::Crashed(CrashedReason::Panicked) => "guest kernel panicked",
+            StateReason::Other(reason) => return write!(f, "reason code {}", reason),
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// How an inactive domain is booted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BootMode {
+    /// Start the domain, which has no managed save image
+    Start,
+    /// Start the domain from its managed save image
+    RestoreManagedSave,
+    /// Discard the managed save image of the domain and boot it from scratch
+    DiscardManagedSave,
+}
+
+impl fmt::Display for BootMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            BootMode::Start => "start",
+            BootMode::RestoreManagedSave => "restore its managed save image",
+            BootMode::DiscardManagedSave => "discard its managed save image and boot",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// What a wake request does to a domain, depending on the state it is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StartAction {
+    /// Boot the inactive domain
+    Boot(BootMode),
+    /// Destroy the domain kept for inspection after a crash, then boot it
+    DestroyAndBoot(BootMode),
+    /// Resume the paused domain
+    Resume,
+    /// Leave the inactive or crashed domain alone, it is only resumed
+    ResumeOnly,
+    /// Leave the domain alone, it is running or in a non-startable state
+    Nothing,
+}
+
+impl fmt::Display for StartAction {
+    /// Describes the action, completing "the VM would ...".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartAction::Boot(mode) => write!(f, "{}", mode),
+            StartAction::DestroyAndBoot(mode) => {
+                write!(f, "be destroyed after its crash and {}", mode)
+            }
+            StartAction::Resume => write!(f, "resume"),
+            StartAction::ResumeOnly => {
+                write!(f, "be left alone, it is only resumed by wake requests")
+            }
+            StartAction::Nothing => write!(f, "be left alone, it is not in a startable state"),
+        }
+    }
+}
+
+/// Decides what a wake request does to a domain in the given state.
+///
+/// The decision follows the state of the domain:
+/// - For shut off or shutdown VMs: start them, restoring or discarding a managed
+///   save image as configured
+/// - For paused VMs: resume them
+/// - For crashed VMs: restart them if configured, and refuse otherwise
+/// - For power management suspended VMs: start them if suspended to disk, and
+///   refuse otherwise
+/// - For other states: take no action
+///
+/// libvirt reports the same state and reason (`VIR_DOMAIN_PMSUSPENDED_UNKNOWN`
+/// and `VIR_DOMAIN_PMSUSPENDED_DISK_UNKNOWN` are both 0) for guests suspended to
+/// RAM and to disk, so the two are told apart by whether QEMU is still running:
+/// - Suspended to RAM: QEMU keeps the guest in memory. Waking it up in place
+///   needs `virDomainPMWakeup`, which the `virt` bindings do not wrap, so the
+///   request is refused
+/// - Suspended to disk: QEMU exits once the hibernation image is written, the
+///   domain is started again and the guest resumes from the image
+///
+/// Nothing is changed here, which is what dry runs rely on.
+///
+/// # Arguments
+///
+/// * `vm_name` - The name of the domain
+/// * `state` - The state the domain is in
+/// * `reason` - Why the domain is in its state
+/// * `options` - How the VM is started, depending on its state
+/// * `has_managed_save` - Checks whether the domain has a managed save image
+/// * `is_active` - Checks whether the domain is still running in QEMU
+///
+/// # Errors
+///
+/// Returns `CrashedNotRestarted` if the domain crashed and restarting it is not
+/// enabled, `WakeupUnsupported` if the guest is suspended to RAM, or any error
+/// returned by `has_managed_save` or `is_active`.
+pub(crate) fn plan_start(
+    vm_name: &str,
+    state: DomainState,
+    reason: StateReason,
+    options: &StartOptions,
+    has_managed_save: impl FnOnce() -> Result<bool, WolGatewayError>,
+    is_active: impl FnOnce() -> Result<bool, WolGatewayError>,
+) -> Result<StartAction, WolGatewayError> {
+    // Starting a domain restores its managed save image unless forced to boot
+    let boot_mode = || -> Result<BootMode, WolGatewayError> {
+        Ok(match (has_managed_save()?, options.managed_save) {
+            (false, _) => BootMode::Start,
+            (true, ManagedSave::Restore) => BootMode::RestoreManagedSave,
+            (true, ManagedSave::Discard) => BootMode::DiscardManagedSave,
+        })
+    };
+
+    // Only paused and suspended domains are woken up, the guest may still
+    // boot from suspend to disk as that resumes its previous session
+    let inactive = matches!(state, DomainState::Shutoff | DomainState::Shutdown);
+    if options.action == WakeAction::Resume && (inactive || reason.is_crash()) {
+        return Ok(StartAction::ResumeOnly);
+    }
+
+    if reason.is_crash() {
+        if !options.restart_crashed {
+            return Err(WolGatewayError::CrashedNotRestarted(vm_name.to_string()));
+        }
+
+        // A crashed domain kept for inspection is still active and has to be
+        // destroyed before it can be started again
+        return Ok(if state == DomainState::Shutoff {
+            StartAction::Boot(boot_mode()?)
+        } else {
+            StartAction::DestroyAndBoot(boot_mode()?)
+        });
+    }
+
+    Ok(match state {
+        DomainState::Shutoff | DomainState::Shutdown => StartAction::Boot(boot_mode()?),
+        DomainState::Paused => StartAction::Resume,
+        DomainState::PmSuspended if is_active()? => {
+            return Err(WolGatewayError::WakeupUnsupported(vm_name.to_string()));
+        }
+        DomainState::PmSuspended => StartAction::Boot(boot_mode()?),
+        _ => StartAction::Nothing,
+    })
+}
+
 /// Attempts to start a libvirt domain (VM) by its UUID.
 ///
-/// This function handles different VM states appropriately:
-/// - For shut off, shutdown, or crashed VMs: attempts to start them
-/// - For paused VMs: attempts to resume them
-/// - For other states: logs the current state and takes no action
+/// The action taken depends on the state of the VM, as decided by `plan_start`.
+/// In a dry run, the action is only logged.
 ///
 /// # Arguments
 ///
 /// * `conn` - The libvirt connection handle
 /// * `vm_uuid` - The UUID of the VM to start
+/// * `options` - How the VM is started, depending on its state
+/// * `dry_run` - Whether to only report the action instead of taking it
 ///
 /// # Returns
 ///
-/// * `Ok(())` - VM was successfully started, resumed, or was already in a non-startable state
+/// * `Ok((DomainState, StartAction))` - The state the VM was in, and the action
+///   that was taken, which may be none if the VM was already running or is only
+///   resumed by wake requests
 /// * `Err(WolGatewayError)` - An error occurred during the operation
 ///
 /// # Errors
@@ -84,10 +472,17 @@ impl From<u32> for DomainState {
 /// Returns various `WolGatewayError` variants for different failure modes:
 /// - `DomainLookupError` - Failed to find domain with the given UUID
 /// - `DomainNameError` - Failed to retrieve domain name
-/// - `DomainStateError` - Failed to retrieve domain state
+/// - `DomainStateError` - Failed to retrieve domain state or managed save image
 /// - `DomainStartError` - Failed to start the domain
 /// - `DomainResumeError` - Failed to resume a paused domain
-async fn start_vm_libvirt(conn: &Connect, vm_uuid: Uuid) -> Result<(), WolGatewayError> {
+/// - `WakeupUnsupported` - The guest is suspended to RAM and cannot be woken up
+/// - `CrashedNotRestarted` - The domain crashed and restarting it is not enabled
+fn start_vm_libvirt(
+    conn: &Connect,
+    vm_uuid: Uuid,
+    options: &StartOptions,
+    dry_run: bool,
+) -> Result<(DomainState, StartAction), WolGatewayError> {
     let domain = Domain::lookup_by_uuid(conn, vm_uuid).map_err(|e| {
         error!("Failed to lookup VM with UUID {}: {:?}", vm_uuid, e);
         WolGatewayError::DomainLookupError(e)
@@ -107,19 +502,63 @@ async fn start_vm_libvirt(conn: &Connect, vm_uuid: Uuid) -> Result<(), WolGatewa
         WolGatewayError::DomainStateError(e)
     })?;
     let state = DomainState::from(state_tuple.0);
+    let reason = StateReason::new(&state, state_tuple.1);
+    info!("VM {} is in state {:?} ({})", vm_name, state, reason);
+
+    let action = plan_start(
+        &vm_name,
+        state,
+        reason,
+        options,
+        || {
+            domain.has_managed_save(0).map_err(|e| {
+                error!("Failed to check managed save of VM {}: {:?}", vm_name, e);
+                WolGatewayError::DomainStateError(e)
+            })
+        },
+        || {
+            domain.is_active().map_err(|e| {
+                error!("Failed to get liveness of VM {}: {:?}", vm_name, e);
+                WolGatewayError::DomainStateError(e)
+            })
+        },
+    )?;
+
+    if dry_run {
+        info!("Dry run, VM {} would {}", vm_name, action);
+    } else {
+        take_action(&domain, &vm_name, action)?;
+    }
 
-    match state {
-        DomainState::Shutoff | DomainState::Shutdown | DomainState::Crashed => {
-            domain.create().map_err(|e| {
-                error!("Failed to start VM {} via libvirt: {:?}", vm_name, e);
+    Ok((state, action))
+}
+
+/// Carries out the action decided for a domain.
+///
+/// # Arguments
+///
+/// * `domain` - The domain to act on
+/// * `vm_name` - The name of the domain, for logging
+/// * `action` - What to do with the domain
+///
+/// # Errors
+///
+/// Returns `DomainStartError` if the domain could not be destroyed or started,
+/// or `DomainResumeError` if it could not be resumed.
+fn take_action(domain: &Domain, vm_name: &str, action: StartAction) -> Result<(), WolGatewayError> {
+    match action {
+        StartAction::Boot(mode) => boot(domain, vm_name, mode)?,
+        StartAction::DestroyAndBoot(mode) => {
+            domain.destroy().map_err(|e| {
+                error!(
+                    "Failed to destroy crashed VM {} via libvirt: {:?}",
+                    vm_name, e
+                );
                 WolGatewayError::DomainStartError(e)
             })?;
-            info!(
-                "Successfully commanded VM {} to start via libvirt.",
-                vm_name
-            );
+            boot(domain, vm_name, mode)?;
         }
-        DomainState::Paused => {
+        StartAction::Resume => {
             domain.resume().map_err(|e| {
                 error!("Failed to resume VM {} via libvirt: {:?}", vm_name, e);
                 WolGatewayError::DomainResumeError(e)
@@ -129,10 +568,16 @@ async fn start_vm_libvirt(conn: &Connect, vm_uuid: Uuid) -> Result<(), WolGatewa
                 vm_name
             );
         }
-        _ => {
+        StartAction::ResumeOnly => {
             info!(
-                "VM {} is not in a startable state (current: {:?}). No action taken.",
-                vm_name, state
+                "VM {} is only resumed by wake requests, it is not booted. No action taken.",
+                vm_name
+            );
+        }
+        StartAction::Nothing => {
+            info!(
+                "VM {} is not in a startable state. No action taken.",
+                vm_name
             );
         }
     }
@@ -140,86 +585,560 @@ async fn start_vm_libvirt(conn: &Connect, vm_uuid: Uuid) -> Result<(), WolGatewa
     Ok(())
 }
 
-/// Finds a VM by its MAC address and attempts to start it if found.
+/// Starts an inactive domain, restoring or discarding its managed save image.
+///
+/// # Arguments
 ///
-/// This function searches through all libvirt domains to find one with a network
-/// interface matching the specified MAC address. If found, it attempts to start
-/// the VM using the appropriate method based on its current state.
+/// * `domain` - The domain to start
+/// * `vm_name` - The name of the domain, for logging
+/// * `mode` - How the domain is booted
+///
+/// # Errors
+///
+/// Returns `DomainStartError` if the domain could not be started.
+fn boot(domain: &Domain, vm_name: &str, mode: BootMode) -> Result<(), WolGatewayError> {
+    let flags = match mode {
+        BootMode::Start | BootMode::RestoreManagedSave => 0,
+        BootMode::DiscardManagedSave => VIR_DOMAIN_START_FORCE_BOOT,
+    };
+
+    domain.create_with_flags(flags).map_err(|e| {
+        error!("Failed to start VM {} via libvirt: {:?}", vm_name, e);
+        WolGatewayError::DomainStartError(e)
+    })?;
+    info!(
+        "Successfully commanded VM {} to {} via libvirt.",
+        vm_name, mode
+    );
+
+    Ok(())
+}
+
+/// Reads the WOL policy from the metadata of a domain.
+///
+/// # Arguments
+///
+/// * `domain` - The resolved domain
+/// * `vm_name` - The name of the domain, for logging
+///
+/// # Returns
+///
+/// * `Ok(Some(VmPolicy))` - The rules from the `<wol:policy>` element
+/// * `Ok(None)` - The domain carries no policy in its metadata
+/// * `Err(WolGatewayError)` - The metadata could not be read or the policy is invalid
+fn read_metadata_policy(
+    domain: &Domain,
+    vm_name: &str,
+) -> Result<Option<VmPolicy>, WolGatewayError> {
+    let xml = match domain.get_metadata(
+        VIR_DOMAIN_METADATA_ELEMENT as i32,
+        Some(POLICY_NAMESPACE),
+        0,
+    ) {
+        Ok(xml) => xml,
+        Err(e) if e.code() == ErrorNumber::NoDomainMetadata => return Ok(None),
+        Err(e) => {
+            error!("Failed to get metadata for VM {}: {:?}", vm_name, e);
+            return Err(WolGatewayError::DomainXmlError(e));
+        }
+    };
+
+    let policy = get_policy(vm_name, &xml)?;
+    debug!(
+        "VM {} carries a WOL policy in its metadata: {:?}",
+        vm_name, policy
+    );
+
+    Ok(Some(policy))
+}
+
+/// Reads the title and description of a domain, skipping unset ones.
+///
+/// # Errors
+///
+/// Returns `DomainXmlError` if the title or description could not be read.
+fn read_annotations(domain: &Domain, vm_name: &str) -> Result<Vec<String>, WolGatewayError> {
+    let mut annotations = Vec::new();
+
+    for kind in [VIR_DOMAIN_METADATA_TITLE, VIR_DOMAIN_METADATA_DESCRIPTION] {
+        match domain.get_metadata(kind as i32, None, 0) {
+            Ok(text) => annotations.push(text),
+            Err(e) if e.code() == ErrorNumber::NoDomainMetadata => {}
+            Err(e) => {
+                error!(
+                    "Failed to get title or description for VM {}: {:?}",
+                    vm_name, e
+                );
+                return Err(WolGatewayError::DomainXmlError(e));
+            }
+        }
+    }
+
+    Ok(annotations)
+}
+
+/// Looks up a domain by UUID, or by name if the string is not a UUID.
+///
+/// # Returns
+///
+/// * `Ok(Some(Domain))` - The domain with the given name or UUID
+/// * `Ok(None)` - No such domain is defined
+/// * `Err(WolGatewayError)` - The lookup failed
+fn lookup_domain(conn: &Connect, domain: &str) -> Result<Option<Domain>, WolGatewayError> {
+    let lookup = match Uuid::parse_str(domain) {
+        Ok(uuid) => Domain::lookup_by_uuid(conn, uuid),
+        Err(_) => Domain::lookup_by_name(conn, domain),
+    };
+
+    match lookup {
+        Ok(domain) => Ok(Some(domain)),
+        Err(e) if e.code() == ErrorNumber::NoDomain => Ok(None),
+        Err(e) => {
+            error!("Failed to lookup VM {}: {:?}", domain, e);
+            Err(WolGatewayError::DomainLookupError(e))
+        }
+    }
+}
+
+/// Resolves the domains owning a MAC address.
+///
+/// An explicit mapping from the policy takes precedence over the MAC index. If
+/// an indexed domain no longer exists, the index is invalidated and the lookup
+/// is retried once against a freshly built index.
 ///
 /// # Arguments
 ///
 /// * `conn` - The libvirt connection handle
+/// * `index` - The MAC address index
+/// * `policy` - The policy holding explicit MAC to domain mappings
+/// * `target_mac` - The MAC address to resolve (case-insensitive)
+///
+/// # Returns
+///
+/// * `Ok(Vec<Domain>)` - The domains owning the MAC address, usually one, or
+///   none if no domain owns it
+/// * `Err(WolGatewayError)` - The index could not be rebuilt, or the lookup of
+///   an indexed domain failed
+fn resolve_domains(
+    conn: &Connect,
+    index: &mut MacIndex,
+    policy: &Policy,
+    target_mac: &str,
+) -> Result<Vec<Domain>, WolGatewayError> {
+    if let Some(domain) = policy.mapped_domain(target_mac) {
+        debug!("MAC address {} is mapped to domain {}", target_mac, domain);
+        return Ok(lookup_domain(conn, domain)?.into_iter().collect());
+    }
+
+    'lookup: for _ in 0..2 {
+        let mut domains = Vec::new();
+
+        for uuid in index.lookup(conn, target_mac)? {
+            match Domain::lookup_by_uuid(conn, uuid) {
+                Ok(domain) => domains.push(domain),
+                Err(e) if e.code() == ErrorNumber::NoDomain => {
+                    debug!("Indexed domain {} no longer exists, rebuilding index", uuid);
+                    index.invalidate();
+                    continue 'lookup;
+                }
+                Err(e) => {
+                    error!("Failed to lookup VM with UUID {}: {:?}", uuid, e);
+                    return Err(WolGatewayError::DomainLookupError(e));
+                }
+            }
+        }
+
+        return Ok(domains);
+    }
+
+    Ok(Vec::new())
+}
+
+/// Finds the VMs owning a MAC address and attempts to start them if found.
+///
+/// This function resolves the MAC address to libvirt domains through an explicit
+/// mapping or the MAC index. Usually a single domain owns the MAC address; if
+/// several do, the policy selects which of them are woken. If the policy allows
+/// the request, each selected VM is started using the appropriate method based
+/// on its current state.
+///
+/// # Arguments
+///
+/// * `conn` - The libvirt connection handle
+/// * `index` - The MAC address index used to resolve the domain
 /// * `target_mac` - The MAC address to search for (case-insensitive)
+/// * `request` - The wake request targeting the MAC address
+/// * `policy` - The policy the request has to satisfy before the VM is started
 ///
 /// # Returns
 ///
-/// * `Ok(())` - VM was found and successfully started/resumed
+/// * `Ok(Vec<ResolvedVm>)` - The VMs that were found and successfully started/resumed
 /// * `Err(WolGatewayError)` - An error occurred during the operation or VM was not found
 ///
 /// # Errors
 ///
 /// Returns various `WolGatewayError` variants for different failure modes:
 /// - `VmNotFound` - No VM found with the specified MAC address
-/// - `DomainListError` - Failed to list libvirt domains
-/// - `DomainXmlError` - Failed to get domain XML description
-/// - `MacExtractionError` - Failed to extract MAC addresses from XML
+/// - `AmbiguousMac` - Several eligible VMs share the MAC address and the policy
+///   does not select among them
+/// - `DomainListError` - Failed to list libvirt domains while rebuilding the index
+/// - `DomainLookupError` - Failed to lookup the indexed domain
 /// - `DomainUuidError` - Failed to get domain UUID
-/// - Other errors propagated from `start_vm_libvirt`
+/// - `DomainNameError` - Failed to get domain name
+/// - Errors propagated from `authorize`, if the request is not authorized for
+///   any VM owning the MAC address
+/// - Errors propagated from `start_authorized`, if no selected VM was started
 ///
 /// Behavior
 ///
-/// - Searches through all domains (both active and inactive)
+/// - Resolves explicitly mapped MAC addresses by domain name or UUID
+/// - Resolves other MAC addresses with a hash map lookup in the index
+/// - Rebuilds the index when it misses after domains were defined or
+///   undefined, or points to a domain that no longer exists
 /// - Performs case-insensitive MAC address comparison
-/// - Extracts MAC addresses from domain XML descriptions
-/// - Stops searching once a matching MAC is found
+/// - Applies the duplicate MAC policy only among the VMs the request is
+///   authorized for, so a disabled or non-wakeable clone does not make the
+///   request ambiguous
 /// - Logs progress and results at appropriate levels
-pub(crate) async fn find_and_start_vm_by_mac(
+pub(crate) fn find_and_start_vm_by_mac(
     conn: &Connect,
+    index: &mut MacIndex,
     target_mac: &str,
-) -> Result<(), WolGatewayError> {
+    request: &WakeRequest,
+    policy: &Policy,
+) -> Result<Vec<ResolvedVm>, WolGatewayError> {
     info!("Searching for VM with MAC address: {}", target_mac);
 
-    let target_mac_lower = target_mac.to_lowercase();
+    let lookup_started = Instant::now();
+    let resolved = resolve_domains(conn, index, policy, target_mac);
+    metrics::observe_lookup(lookup_started.elapsed());
 
-    let domains = conn
-        .list_all_domains(0) // List all domains (both active and inactive)
-        .map_err(|e| {
-            error!("Failed to list all domains: {:?}", e);
-            WolGatewayError::DomainListError(e)
-        })?;
+    let domains = resolved?;
+    if domains.is_empty() {
+        info!("No VM found with MAC address: {}", target_mac);
+        return Err(WolGatewayError::VmNotFound(target_mac.to_string()));
+    }
+    metrics::record_match();
 
+    let mut candidates = Vec::with_capacity(domains.len());
     for dom in domains {
-        let xml_desc = dom.get_xml_desc(0).map_err(|e| {
-            let domain_name = dom.get_name().unwrap_or_else(|_| "unknown".to_string());
+        let uuid = dom.get_uuid().map_err(|e| {
             error!(
-                "Failed to get XML description for domain {}: {:?}",
-                domain_name, e
+                "Failed to get UUID for domain with matching MAC {}: {:?}",
+                target_mac, e
             );
-            WolGatewayError::DomainXmlError(e)
+            WolGatewayError::DomainUuidError(e)
         })?;
+        let name = dom.get_name().map_err(|e| {
+            error!("Failed to get name for VM with UUID {}: {:?}", uuid, e);
+            WolGatewayError::DomainNameError(e)
+        })?;
+        candidates.push((dom, name, uuid));
+    }
+    candidates.sort_by(|a, b| a.1.cmp(&b.1));
 
-        let mac_addresses = crate::domain_xml::get_mac_addresses(&xml_desc)?;
-
-        for mac in mac_addresses {
-            debug!("Checking MAC address: {}", mac);
-            if mac.to_lowercase() == target_mac_lower {
-                let uuid = dom.get_uuid().map_err(|e| {
-                    error!(
-                        "Failed to get UUID for domain with matching MAC {}: {:?}",
-                        target_mac, e
+    // Only VMs the request is authorized for compete for a shared MAC address
+    let shared = candidates.len() > 1;
+    let mut eligible = Vec::with_capacity(candidates.len());
+    let mut rejections = Vec::new();
+    for (dom, name, uuid) in &candidates {
+        match authorize(dom, *uuid, request, policy) {
+            Ok(vm) => eligible.push(vm),
+            Err(e) => {
+                if shared {
+                    info!(
+                        "Skipping VM {} sharing MAC address {}: {}",
+                        name, target_mac, e
                     );
-                    WolGatewayError::DomainUuidError(e)
-                })?;
+                }
+                rejections.push(e);
+            }
+        }
+    }
 
-                info!(
-                    "Found VM with matching MAC address: {} ({})",
-                    target_mac, uuid
-                );
-                return start_vm_libvirt(conn, uuid).await;
+    let mut rejections = rejections.into_iter();
+    if eligible.is_empty() {
+        if let Some(e) = rejections.next() {
+            rejections.for_each(|e| metrics::record_error(&e));
+            return Err(e);
+        }
+    }
+    rejections.for_each(|e| metrics::record_error(&e));
+
+    let owners: Vec<(String, Uuid)> = eligible
+        .iter()
+        .map(|vm| (vm.name.clone(), vm.uuid))
+        .collect();
+    let selected = policy.select_duplicates(target_mac, &owners)?;
+
+    let mut started = Vec::with_capacity(selected.len());
+    let mut failures = Vec::new();
+    let several = selected.len() > 1;
+    let selected = eligible
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, vm)| vm);
+    for vm in selected {
+        info!(
+            "Found VM with matching MAC address: {} ({})",
+            target_mac, vm.uuid
+        );
+
+        let name = vm.name.clone();
+        match start_authorized(conn, vm, policy) {
+            Ok(vm) => started.push(vm),
+            Err(e) => {
+                if several {
+                    warn!(
+                        "Failed to start VM {} sharing MAC address {}: {}",
+                        name, target_mac, e
+                    );
+                }
+                failures.push(e);
             }
         }
     }
 
-    info!("No VM found with MAC address: {}", target_mac);
-    Err(WolGatewayError::VmNotFound(target_mac.to_string()))
+    // The request only fails if no VM was started, its error is recorded by the caller
+    let mut failures = failures.into_iter();
+    if started.is_empty() {
+        if let Some(e) = failures.next() {
+            failures.for_each(|e| metrics::record_error(&e));
+            return Err(e);
+        }
+    }
+    failures.for_each(|e| metrics::record_error(&e));
+
+    Ok(started)
+}
+
+/// Finds a VM by its name or UUID and attempts to start it if found.
+///
+/// # Arguments
+///
+/// * `conn` - The libvirt connection handle
+/// * `domain` - The name or UUID of the domain
+/// * `request` - The wake request targeting the domain
+/// * `policy` - The policy the request has to satisfy before the VM is started
+///
+/// # Errors
+///
+/// Returns `DomainNotFound` if no such domain is defined, `DomainLookupError`
+/// or `DomainUuidError` if the domain could not be looked up, or any error
+/// propagated from `authorize` or `start_authorized`.
+pub(crate) fn find_and_start_vm_by_name(
+    conn: &Connect,
+    domain: &str,
+    request: &WakeRequest,
+    policy: &Policy,
+) -> Result<ResolvedVm, WolGatewayError> {
+    let Some(dom) = lookup_domain(conn, domain)? else {
+        info!("No VM found with name or UUID: {}", domain);
+        return Err(WolGatewayError::DomainNotFound(domain.to_string()));
+    };
+
+    let uuid = dom.get_uuid().map_err(|e| {
+        error!("Failed to get UUID for VM {}: {:?}", domain, e);
+        WolGatewayError::DomainUuidError(e)
+    })?;
+
+    let vm = authorize(&dom, uuid, request, policy)?;
+    start_authorized(conn, vm, policy)
+}
+
+/// A VM a wake request was authorized for, waiting to be started.
+#[derive(Debug)]
+struct AuthorizedVm {
+    /// The name of the domain.
+    name: String,
+    /// The UUID of the domain.
+    uuid: Uuid,
+    /// How the domain is started, from its policy.
+    options: StartOptions,
+}
+
+/// Checks a wake request against the policy of a resolved VM.
+///
+/// # Arguments
+///
+/// * `dom` - The resolved domain
+/// * `uuid` - The UUID of the resolved domain
+/// * `request` - The wake request to authorize
+/// * `policy` - The policy the request has to satisfy before the VM is started
+///
+/// # Errors
+///
+/// - `DomainNameError` - Failed to get domain name
+/// - `DomainXmlError` - Failed to read the domain metadata
+/// - `MetadataPolicyError` - The WOL policy in the domain metadata is invalid
+/// - `VmNotWakeable` - The VM did not opt in or is not selected in opt-in mode
+/// - `SourceNotAllowed` - The request came from a source outside the allowed networks
+/// - `VmDisabled` - Waking the VM is disabled by its policy
+/// - `PasswordMismatch` - The request did not carry the VM's SecureOn password
+fn authorize(
+    dom: &Domain,
+    uuid: Uuid,
+    request: &WakeRequest,
+    policy: &Policy,
+) -> Result<AuthorizedVm, WolGatewayError> {
+    let vm_name = dom.get_name().map_err(|e| {
+        error!("Failed to get name for VM with UUID {}: {:?}", uuid, e);
+        WolGatewayError::DomainNameError(e)
+    })?;
+    let metadata = read_metadata_policy(dom, &vm_name)?;
+    policy.check_wakeable(&vm_name, &uuid, metadata.as_ref(), || {
+        read_annotations(dom, &vm_name)
+    })?;
+    policy.authorize(request, &vm_name, &uuid, metadata.as_ref())?;
+
+    let options = policy.start_options(&vm_name, &uuid);
+    Ok(AuthorizedVm {
+        name: vm_name,
+        uuid,
+        options,
+    })
+}
+
+/// Starts a VM a wake request was authorized for.
+///
+/// # Arguments
+///
+/// * `conn` - The libvirt connection handle
+/// * `vm` - The authorized VM
+/// * `policy` - The policy deciding whether this is a dry run
+///
+/// # Errors
+///
+/// Returns any error propagated from `start_vm_libvirt`.
+fn start_authorized(
+    conn: &Connect,
+    vm: AuthorizedVm,
+    policy: &Policy,
+) -> Result<ResolvedVm, WolGatewayError> {
+    let start_started = Instant::now();
+    let result = start_vm_libvirt(conn, vm.uuid, &vm.options, policy.dry_run);
+    metrics::observe_start(start_started.elapsed());
+
+    result.map(|(state, action)| ResolvedVm {
+        name: vm.name,
+        uuid: vm.uuid,
+        state,
+        action,
+    })
+}
+
+/// Reads the current state of a VM by its name or UUID.
+///
+/// Only the state of VMs that may be woken is reported.
+///
+/// # Arguments
+///
+/// * `conn` - The libvirt connection handle
+/// * `domain` - The name or UUID of the domain
+/// * `policy` - The policy deciding which VMs may be woken
+///
+/// # Errors
+///
+/// Returns `DomainNotFound` if no such domain is defined, `VmNotWakeable` if
+/// the VM did not opt in or is not selected in opt-in mode, or the libvirt error
+/// that prevented reading the domain.
+pub(crate) fn get_vm_status(
+    conn: &Connect,
+    domain: &str,
+    policy: &Policy,
+) -> Result<VmStatus, WolGatewayError> {
+    let Some(dom) = lookup_domain(conn, domain)? else {
+        return Err(WolGatewayError::DomainNotFound(domain.to_string()));
+    };
+
+    let uuid = dom.get_uuid().map_err(|e| {
+        error!("Failed to get UUID for VM {}: {:?}", domain, e);
+        WolGatewayError::DomainUuidError(e)
+    })?;
+    let vm_name = dom.get_name().map_err(|e| {
+        error!("Failed to get name for VM with UUID {}: {:?}", uuid, e);
+        WolGatewayError::DomainNameError(e)
+    })?;
+    let metadata = read_metadata_policy(&dom, &vm_name)?;
+    policy.check_wakeable(&vm_name, &uuid, metadata.as_ref(), || {
+        read_annotations(&dom, &vm_name)
+    })?;
+
+    let (state, reason) = dom.get_state().map_err(|e| {
+        error!("Failed to get state for VM {}: {:?}", vm_name, e);
+        WolGatewayError::DomainStateError(e)
+    })?;
+    let state = DomainState::from(state);
+    let reason = StateReason::new(&state, reason);
+
+    Ok(VmStatus {
+        name: vm_name,
+        uuid,
+        state,
+        reason,
+    })
+}
+
+/// A defined VM and the MAC addresses of its network interfaces.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DomainSummary {
+    /// The name of the domain.
+    pub(crate) name: String,
+    /// The UUID of the domain.
+    pub(crate) uuid: Uuid,
+    /// The state the domain is in.
+    pub(crate) state: DomainState,
+    /// The MAC addresses of the domain's interfaces.
+    pub(crate) macs: Vec<String>,
+}
+
+/// Lists all defined domains with their state and MAC addresses, by name.
+///
+/// Like when building the MAC index, domains that cannot be read are skipped
+/// with a warning.
+///
+/// # Arguments
+///
+/// * `conn` - The libvirt connection handle
+///
+/// # Errors
+///
+/// Returns `DomainListError` if the domains could not be listed.
+pub(crate) fn list_domains(conn: &Connect) -> Result<Vec<DomainSummary>, WolGatewayError> {
+    let domains = conn.list_all_domains(0).map_err(|e| {
+        error!("Failed to list all domains: {:?}", e);
+        WolGatewayError::DomainListError(e)
+    })?;
+
+    let mut summaries = Vec::with_capacity(domains.len());
+
+    for dom in &domains {
+        let name = dom.get_name().unwrap_or_else(|_| "unknown".to_string());
+
+        match summarize_domain(dom, name.clone()) {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => warn!("Skipping domain {} while listing: {}", name, e),
+        }
+    }
+
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(summaries)
+}
+
+/// Reads the UUID, state and MAC addresses of a domain.
+fn summarize_domain(dom: &Domain, name: String) -> Result<DomainSummary, WolGatewayError> {
+    let uuid = dom.get_uuid().map_err(WolGatewayError::DomainUuidError)?;
+    let (state, _) = dom.get_state().map_err(WolGatewayError::DomainStateError)?;
+    let xml = dom
+        .get_xml_desc(0)
+        .map_err(WolGatewayError::DomainXmlError)?;
+    let macs = crate::domain_xml::get_mac_addresses(&xml)?;
+
+    Ok(DomainSummary {
+        name,
+        uuid,
+        state: DomainState::from(state),
+        macs,
+    })
 }
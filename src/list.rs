@@ -0,0 +1,177 @@
+//! The `list` subcommand, printing the MAC address to domain map.
+//!
+//! When a wake request does nothing, the MAC address it carries usually does not
+//! belong to the VM it was meant for. Listing every domain with the MAC
+//! addresses of its interfaces, as the MAC index sees them, saves running
+//! `virsh dumpxml` on each of them. MAC addresses shared by several domains are
+//! reported, since only one of them can be woken.
+
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::connection::LibvirtConnection;
+use crate::error::WolGatewayError;
+use crate::libvirt::{list_domains, DomainSummary};
+use crate::ListArgs;
+
+/// Output formats of the `list` subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ListFormat {
+    /// Aligned columns for reading
+    Table,
+    /// A JSON array of domains
+    Json,
+    /// Comma-separated values with a header line
+    Csv,
+}
+
+/// Lists all domains of the configured libvirt connection on stdout.
+///
+/// # Arguments
+///
+/// * `args` - The configuration, libvirt URI and output format
+///
+/// # Errors
+///
+/// Returns any error loading the configuration, `LibvirtConnectError` if
+/// libvirt could not be reached, or `DomainListError` if the domains could not
+/// be listed.
+pub(crate) fn run(args: &ListArgs) -> Result<(), WolGatewayError> {
+    let config = Config::load(&args.libvirt.cli())?;
+    let mut connection = LibvirtConnection::open(&config.libvirt_uri)?;
+    let domains = connection.get().and_then(list_domains);
+    connection.disconnect();
+    let domains = domains?;
+
+    for (mac, names) in duplicate_macs(&domains) {
+        warn!(
+            "MAC address {} is used by several domains: {}",
+            mac,
+            names.join(", ")
+        );
+    }
+
+    let output = match args.format {
+        ListFormat::Table => format_table(&domains),
+        ListFormat::Json => format_json(&domains),
+        ListFormat::Csv => format_csv(&domains),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+/// Finds MAC addresses used by more than one domain.
+///
+/// # Returns
+///
+/// The shared MAC addresses in order, each with the names of the domains using it.
+pub(crate) fn duplicate_macs(domains: &[DomainSummary]) -> Vec<(String, Vec<String>)> {
+    let mut owners: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for domain in domains {
+        for mac in &domain.macs {
+            let names = owners.entry(mac).or_default();
+            // A domain with two interfaces sharing a MAC address is not ambiguous
+            if !names.contains(&domain.name) {
+                names.push(domain.name.clone());
+            }
+        }
+    }
+
+    owners
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(mac, names)| (mac.to_string(), names))
+        .collect()
+}
+
+/// Formats domains as aligned columns, one domain per line.
+pub(crate) fn format_table(domains: &[DomainSummary]) -> String {
+    let rows: Vec<[String; 4]> = domains
+        .iter()
+        .map(|domain| {
+            let macs = if domain.macs.is_empty() {
+                "-".to_string()
+            } else {
+                domain.macs.join(", ")
+            };
+            [
+                domain.name.clone(),
+                domain.uuid.to_string(),
+                domain.state.to_string(),
+                macs,
+            ]
+        })
+        .collect();
+
+    let header = ["NAME", "UUID", "STATE", "MACS"].map(str::to_string);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for [name, uuid, state, macs] in std::iter::once(&header).chain(&rows) {
+        // The last column is not padded to avoid trailing whitespace
+        output.push_str(&format!(
+            "{:name_width$}  {:uuid_width$}  {:state_width$}  {}\n",
+            name,
+            uuid,
+            state,
+            macs,
+            name_width = widths[0],
+            uuid_width = widths[1],
+            state_width = widths[2],
+        ));
+    }
+
+    output
+}
+
+/// Formats domains as a pretty-printed JSON array.
+pub(crate) fn format_json(domains: &[DomainSummary]) -> String {
+    let domains: Vec<Value> = domains
+        .iter()
+        .map(|domain| {
+            json!({
+                "name": domain.name,
+                "uuid": domain.uuid.to_string(),
+                "state": domain.state.to_string(),
+                "macs": domain.macs,
+            })
+        })
+        .collect();
+
+    format!("{:#}\n", Value::Array(domains))
+}
+
+/// Formats domains as CSV, with the MAC addresses of a domain separated by spaces.
+pub(crate) fn format_csv(domains: &[DomainSummary]) -> String {
+    let mut output = String::from("name,uuid,state,macs\n");
+    for domain in domains {
+        output.push_str(&format!(
+            "{},{},{},{}\n",
+            csv_field(&domain.name),
+            domain.uuid,
+            csv_field(&domain.state.to_string()),
+            domain.macs.join(" "),
+        ));
+    }
+
+    output
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,223 @@
+//! In-memory index mapping MAC addresses to libvirt domains.
+//!
+//! Resolving a MAC address by listing every domain and parsing its XML is slow
+//! on hosts with many domains, so the gateway keeps an index of all known MAC
+//! addresses instead. A lookup is a hash map hit without any libvirt call.
+//! Cloned domains may share a MAC address, so every owner of a MAC address is
+//! kept and deciding between them is left to the policy.
+//!
+//! Ideally the index would follow libvirt's domain lifecycle and define events,
+//! but the `virt` 0.4.2 bindings do not expose the event API, and registering
+//! the callbacks through the raw `virt-sys` functions would need unsafe code,
+//! which this crate forbids. The index is therefore built at startup and
+//! rebuilt in the background by the libvirt worker when it gets older than its
+//! maximum age, after a reconnection, and when a domain it points to no longer
+//! exists. A lookup miss additionally counts the domains, at most every few
+//! seconds, and rebuilds the index if domains were defined or undefined.
+//! Interfaces added to or moved between existing domains are only picked up by
+//! the next background rebuild.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use uuid::Uuid;
+use virt::connect::Connect;
+
+use crate::error::WolGatewayError;
+
+/// Minimum time between two domain counts triggered by lookup misses.
+///
+/// This keeps a flood of packets for unknown MAC addresses from turning into a
+/// flood of libvirt calls.
+const MISS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Index of the MAC addresses of all libvirt domains.
+#[derive(Debug)]
+pub(crate) struct MacIndex {
+    /// UUIDs of the domains owning each lowercase MAC address.
+    macs: HashMap<String, Vec<Uuid>>,
+    /// When the index was last rebuilt, or `None` if it was never built.
+    built_at: Option<Instant>,
+    /// Number of active and inactive domains when the index was last rebuilt.
+    domains: usize,
+    /// When the domains were last counted because of a lookup miss.
+    checked_at: Option<Instant>,
+    /// Maximum age of the index before it is rebuilt in the background.
+    max_age: Duration,
+}
+
+impl MacIndex {
+    /// Creates an empty index, which is built on the first lookup.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_age` - Maximum age of the index before it is rebuilt
+    pub(crate) fn new(max_age: Duration) -> Self {
+        MacIndex {
+            macs: HashMap::new(),
+            built_at: None,
+            domains: 0,
+            checked_at: None,
+            max_age,
+        }
+    }
+
+    /// Rebuilds the index from the XML descriptions of all domains.
+    ///
+    /// Domains whose XML cannot be retrieved or parsed are skipped with a warning,
+    /// so a single broken definition does not make every other VM unreachable.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - The libvirt connection handle
+    ///
+    /// # Errors
+    ///
+    /// Returns `DomainListError` if the domains could not be listed.
+    pub(crate) fn rebuild(&mut self, conn: &Connect) -> Result<(), WolGatewayError> {
+        let domains = conn
+            .list_all_domains(0) // List all domains (both active and inactive)
+            .map_err(|e| {
+                error!("Failed to list all domains: {:?}", e);
+                WolGatewayError::DomainListError(e)
+            })?;
+
+        let mut macs: HashMap<String, Vec<Uuid>> = HashMap::new();
+        let mut names = HashMap::new();
+
+        for dom in &domains {
+            let domain_name = dom.get_name().unwrap_or_else(|_| "unknown".to_string());
+
+            let mac_addresses = dom
+                .get_xml_desc(0)
+                .map_err(WolGatewayError::DomainXmlError)
+                .and_then(|xml_desc| crate::domain_xml::get_mac_addresses(&xml_desc));
+            let uuid = dom.get_uuid().map_err(WolGatewayError::DomainUuidError);
+
+            match (mac_addresses, uuid) {
+                (Ok(mac_addresses), Ok(uuid)) => {
+                    names.insert(uuid, domain_name.clone());
+                    for mac in mac_addresses {
+                        debug!("Indexing MAC address {} of domain {}", mac, domain_name);
+                        let owners = macs.entry(mac.to_lowercase()).or_default();
+                        if !owners.contains(&uuid) {
+                            owners.push(uuid);
+                        }
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Skipping domain {} while indexing: {}", domain_name, e);
+                }
+            }
+        }
+
+        // Only report shared MAC addresses once, not on every rebuild
+        for (mac, owners) in &macs {
+            let known = self.macs.get(mac).is_some_and(|known| known == owners);
+            if owners.len() > 1 && !known {
+                warn!(
+                    "MAC address {} is used by several domains: {}",
+                    mac,
+                    owners
+                        .iter()
+                        .filter_map(|uuid| names.get(uuid).map(String::as_str))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+
+        info!(
+            "Indexed {} MAC addresses of {} domains",
+            macs.len(),
+            domains.len()
+        );
+        self.macs = macs;
+        self.built_at = Some(Instant::now());
+        self.domains = domains.len();
+
+        Ok(())
+    }
+
+    /// Forgets all indexed MAC addresses, forcing a rebuild right away.
+    pub(crate) fn invalidate(&mut self) {
+        self.macs.clear();
+        self.built_at = None;
+    }
+
+    /// Returns when the index is due to be rebuilt in the background, which is
+    /// now if it was never built or has been invalidated.
+    pub(crate) fn refresh_at(&self) -> Instant {
+        self.built_at
+            .map_or_else(Instant::now, |built_at| built_at + self.max_age)
+    }
+
+    /// Looks up the UUIDs of the domains owning a MAC address.
+    ///
+    /// A hit makes no libvirt call. The index is only built here if it was
+    /// never built or has been invalidated. On a miss, the domains are counted
+    /// unless that was done within `MISS_CHECK_INTERVAL`, and the index is
+    /// rebuilt if their number changed, which picks up newly defined domains.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - The libvirt connection handle
+    /// * `mac` - The MAC address to look up (case-insensitive)
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Uuid>)` - The UUIDs of the domains owning the MAC address,
+    ///   usually one, or none if no known domain owns it
+    /// * `Err(WolGatewayError)` - The domains could not be counted or the
+    ///   index could not be rebuilt
+    pub(crate) fn lookup(
+        &mut self,
+        conn: &Connect,
+        mac: &str,
+    ) -> Result<Vec<Uuid>, WolGatewayError> {
+        let mac = mac.to_lowercase();
+
+        if self.built_at.is_none() {
+            self.rebuild(conn)?;
+        }
+
+        if let Some(owners) = self.macs.get(&mac) {
+            return Ok(owners.clone());
+        }
+
+        let checked_recently = self
+            .checked_at
+            .is_some_and(|checked_at| checked_at.elapsed() < MISS_CHECK_INTERVAL);
+        if checked_recently {
+            return Ok(Vec::new());
+        }
+
+        self.checked_at = Some(Instant::now());
+        if count_domains(conn)? != self.domains {
+            debug!(
+                "MAC address {} not indexed and domains changed, rebuilding index",
+                mac
+            );
+            self.rebuild(conn)?;
+        }
+
+        Ok(self.macs.get(&mac).cloned().unwrap_or_default())
+    }
+}
+
+/// Counts the active and inactive domains of a connection.
+///
+/// # Errors
+///
+/// Returns `DomainListError` if the domains could not be counted.
+fn count_domains(conn: &Connect) -> Result<usize, WolGatewayError> {
+    let active = conn
+        .num_of_domains()
+        .map_err(WolGatewayError::DomainListError)?;
+    let inactive = conn
+        .num_of_defined_domains()
+        .map_err(WolGatewayError::DomainListError)?;
+
+    Ok(active as usize + inactive as usize)
+}
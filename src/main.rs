@@ -1,7 +1,7 @@
 //! # WOL Libvirt Gateway
 //!
-//! A simple Wake-on-LAN (WOL) gateway service for starting libvirt VMs. 
-//! It listens for WOL "magic packets" on UDP port 9 (configurable), identifies the target MAC address, 
+//! A simple Wake-on-LAN (WOL) gateway service for starting libvirt VMs.
+//! It listens for WOL "magic packets" on UDP port 9 (configurable), identifies the target MAC address,
 //! and if that MAC address belongs to a defined libvirt VM, it attempts to start that VM using the libvirt API.
 //!
 //! ## Usage
@@ -9,30 +9,106 @@
 //! ```bash
 //! wol-libvirt-gateway --address 0.0.0.0:9 --libvirt-uri qemu:///system
 //! ```
+//!
+//! Magic packets for testing the gateway can be sent with the `send` subcommand:
+//!
+//! ```bash
+//! wol-libvirt-gateway send 52:54:00:12:34:56
+//! ```
+//!
+//! The MAC addresses of all domains are shown by the `list` subcommand:
+//!
+//! ```bash
+//! wol-libvirt-gateway list --config /etc/wol-libvirt-gateway.toml
+//! ```
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use log::info;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::error::WolGatewayError;
 
+mod api;
+mod auth;
+mod check;
+mod config;
+mod connection;
 mod domain_xml;
 mod error;
+mod ethernet;
 mod libvirt;
+mod list;
+mod mac_index;
+mod metrics;
+mod policy;
+mod rate_limit;
+mod send;
 mod server;
+mod systemd;
 mod tests;
+mod udp;
 mod wakeonlan;
+mod worker;
+
+/// Time tasks still running on the async runtime are given when the program exits.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Command line arguments for the WOL Libvirt Gateway service.
 ///
 /// This struct defines the configuration options that can be passed to the service
-/// when starting it from the command line.
+/// when starting it from the command line. Options given here override the ones
+/// from the configuration file.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// The address and port to bind the WOL server to.
+    /// Subcommand to run instead of the gateway.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to a TOML configuration file.
     ///
-    /// Format: `IP:PORT` (e.g., "127.0.0.1:9009" or "0.0.0.0:9009")
+    /// Besides the settings available as command line options, the file can
+    /// describe allowed source networks, per-VM policies and explicit MAC to
+    /// domain mappings.
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// The address and port to bind a WOL listener to.
+    ///
+    /// Format: `IP:PORT` (e.g., "127.0.0.1:9009", "0.0.0.0:9009" or "[::]:9").
+    /// May be given multiple times or as a comma-separated list. An IPv6
+    /// wildcard address also receives IPv4 packets, unless an IPv4 address with
+    /// the same port is given as well.
     /// Default: "127.0.0.1:9"
-    #[arg(short, long, default_value = "127.0.0.1:9")]
-    address: String,
+    #[arg(short, long = "address", value_name = "ADDRESS", value_delimiter = ',')]
+    addresses: Vec<String>,
+
+    /// IPv6 multicast group to join on the `[::]` listeners, e.g. "ff02::1".
+    ///
+    /// IPv6 has no broadcast, so WOL tools send to the all-nodes group instead.
+    /// Append `%IFACE` to join the group on a specific interface (e.g.
+    /// "ff02::1%br0"). May be given multiple times.
+    #[arg(long, value_name = "GROUP")]
+    multicast: Vec<String>,
+
+    /// Enable the UDP listeners, overriding the configuration file.
+    #[arg(long, overrides_with = "no_udp")]
+    udp: bool,
+
+    /// Disable the UDP listeners, e.g. to only receive raw Ethernet frames.
+    #[arg(long, overrides_with = "udp")]
+    no_udp: bool,
+
+    /// Network interface to listen on for raw Ethernet WOL frames (EtherType 0x0842).
+    ///
+    /// Usually the bridge the VMs are attached to (e.g. "virbr0" or "br0").
+    /// May be given multiple times. Requires the `CAP_NET_RAW` capability.
+    #[arg(short, long = "interface", value_name = "IFACE")]
+    interfaces: Vec<String>,
 
     /// The libvirt connection URI to use for connecting to the hypervisor.
     ///
@@ -42,8 +118,270 @@ struct Cli {
     /// - `qemu+ssh://user@host/system` - Remote QEMU over SSH
     ///
     /// Default: "qemu:///system"
-    #[arg(short, long, default_value = "qemu:///system")]
-    libvirt_uri: String,
+    #[arg(short, long)]
+    libvirt_uri: Option<String>,
+
+    /// Interval in seconds between libvirt connection health checks.
+    ///
+    /// A dead connection (e.g. after a libvirtd restart) is re-established with
+    /// exponential backoff.
+    /// Default: 5
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_interval: Option<u64>,
+
+    /// Maximum age in seconds of the MAC address index before it is rebuilt in
+    /// the background.
+    ///
+    /// The index is also rebuilt when a packet targets an unknown MAC address
+    /// and domains were defined or undefined since it was built.
+    /// Default: 300
+    #[arg(long, value_name = "SECONDS")]
+    index_max_age: Option<u64>,
+
+    /// The address and port to serve Prometheus metrics on at `/metrics`.
+    ///
+    /// Format: `IP:PORT` (e.g., "127.0.0.1:9100")
+    /// Default: metrics are not served
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_address: Option<String>,
+
+    /// The address and port to serve the HTTP API on.
+    ///
+    /// The API wakes VMs by MAC address or domain name and reports their state.
+    /// Format: `IP:PORT` (e.g., "127.0.0.1:8080")
+    /// Default: the API is not served
+    #[arg(long, value_name = "ADDRESS")]
+    api_address: Option<String>,
+
+    /// Window in seconds in which repeated requests for the same MAC address
+    /// are collapsed into one, whoever sends them.
+    ///
+    /// WOL tools usually send several identical packets at once. A request that
+    /// failed does not suppress retries. Use 0 to act on every packet.
+    /// Default: 5
+    #[arg(long, value_name = "SECONDS")]
+    dedup_window: Option<u64>,
+
+    /// Packets per second accepted from each source IP address, or each source
+    /// MAC address on an interface for raw Ethernet frames.
+    ///
+    /// Packets above the limit are dropped before any libvirt work is done.
+    /// Use 0 to disable rate limiting.
+    /// Default: 5
+    #[arg(long, value_name = "PER_SECOND")]
+    rate_limit: Option<f64>,
+
+    /// Number of packets a source may send in a burst before the rate limit
+    /// applies.
+    /// Default: 10
+    #[arg(long, value_name = "PACKETS", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_burst: Option<u32>,
+
+    /// Network in CIDR notation that UDP wake packets are accepted from.
+    ///
+    /// Plain IP addresses are accepted as single hosts. May be given multiple
+    /// times and replaces `allowed_sources` from the configuration file.
+    /// Default: any source
+    #[arg(long, value_name = "CIDR")]
+    allow: Vec<String>,
+
+    /// Network in CIDR notation that UDP wake packets are never accepted from.
+    ///
+    /// Takes precedence over `--allow`. May be given multiple times and replaces
+    /// `denied_sources` from the configuration file.
+    #[arg(long, value_name = "CIDR")]
+    deny: Vec<String>,
+
+    /// SecureOn password that magic packets must carry to wake any VM.
+    ///
+    /// Format: six hex bytes (e.g. "11:22:33:44:55:66") or four bytes in
+    /// dotted decimal notation (e.g. "192.168.1.1").
+    /// Default: no password required
+    #[arg(long)]
+    password: Option<String>,
+
+    /// SecureOn password required to wake a specific VM, overriding `--password`.
+    ///
+    /// Format: `DOMAIN=PASSWORD`, where `DOMAIN` is the libvirt domain name or UUID.
+    /// May be given multiple times.
+    #[arg(long = "vm-password", value_name = "DOMAIN=PASSWORD")]
+    vm_passwords: Vec<String>,
+
+    /// What to do with the managed save image of a VM that is started.
+    ///
+    /// "restore" resumes the VM from its saved memory state, as `virsh start`
+    /// does, "discard" deletes the image and boots the VM from scratch.
+    /// Default: "restore"
+    #[arg(long, value_name = "ACTION")]
+    managed_save: Option<String>,
+
+    /// Restart VMs that crashed instead of leaving them for inspection.
+    #[arg(long, overrides_with = "no_restart_crashed")]
+    restart_crashed: bool,
+
+    /// Leave VMs that crashed for inspection, overriding the configuration file.
+    #[arg(long, overrides_with = "restart_crashed")]
+    no_restart_crashed: bool,
+
+    /// Resolve and authorize wake requests, but only log what would be done
+    /// to the VM instead of starting it.
+    #[arg(long, overrides_with = "no_dry_run")]
+    dry_run: bool,
+
+    /// Start VMs for wake requests, overriding a dry run enabled in the
+    /// configuration file.
+    #[arg(long, overrides_with = "dry_run")]
+    no_dry_run: bool,
+
+    /// What to do when several domains share the MAC address of a request.
+    ///
+    /// "refuse" rejects the request, "prefer" wakes the domain selected by
+    /// `--prefer`, "all" wakes every domain sharing the MAC address.
+    /// Default: "refuse"
+    #[arg(long, value_name = "MODE")]
+    duplicate_macs: Option<String>,
+
+    /// Name glob (e.g. "web-*") or UUID of a domain woken among several
+    /// sharing a MAC address.
+    ///
+    /// Implies `--duplicate-macs prefer`. May be given multiple times, the first
+    /// one matching any of the domains decides. Replaces `prefer` from the
+    /// configuration file.
+    #[arg(long, value_name = "PATTERN")]
+    prefer: Vec<String>,
+
+    /// Only wake domains that are explicitly selected.
+    ///
+    /// Domains have to opt in through a WOL policy in their metadata in any
+    /// case. This mode further requires them to be selected with `--wakeable`,
+    /// a `[[vm]]` table in the configuration file, or the "[wol]" marker in
+    /// their title or description.
+    #[arg(long, overrides_with = "no_opt_in")]
+    opt_in: bool,
+
+    /// Wake any domain that opted in through its metadata, overriding opt-in
+    /// mode enabled in the configuration file.
+    #[arg(long, overrides_with = "opt_in", conflicts_with = "wakeable")]
+    no_opt_in: bool,
+
+    /// Name glob (e.g. "web-*") or UUID of a domain that may be woken.
+    ///
+    /// Implies `--opt-in`. May be given multiple times and replaces `wakeable`
+    /// from the configuration file.
+    #[arg(long, value_name = "PATTERN")]
+    wakeable: Vec<String>,
+}
+
+/// Subcommands run instead of the gateway.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send magic packets, e.g. to test the gateway.
+    Send(SendArgs),
+    /// List all domains with their state and MAC addresses.
+    List(ListArgs),
+    /// Resolve a wake request for a MAC address without starting the VM.
+    Check(CheckArgs),
+}
+
+/// Options of the subcommands querying libvirt.
+#[derive(Args, Debug)]
+struct LibvirtArgs {
+    /// Path to the TOML configuration file of the gateway.
+    ///
+    /// The libvirt URI and policies are read from it like the gateway does.
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// The libvirt connection URI, overriding the configuration file.
+    ///
+    /// Default: "qemu:///system"
+    #[arg(short, long)]
+    libvirt_uri: Option<String>,
+}
+
+impl LibvirtArgs {
+    /// Returns the gateway arguments the configuration is loaded with.
+    fn cli(&self) -> Cli {
+        Cli {
+            command: None,
+            config: self.config.clone(),
+            libvirt_uri: self.libvirt_uri.clone(),
+            ..Cli::parse_from([env!("CARGO_PKG_NAME")])
+        }
+    }
+}
+
+/// Command line arguments of the `list` subcommand.
+#[derive(Args, Debug)]
+struct ListArgs {
+    #[command(flatten)]
+    libvirt: LibvirtArgs,
+
+    /// Output format.
+    #[arg(short, long, value_enum, default_value_t = list::ListFormat::Table)]
+    format: list::ListFormat,
+}
+
+/// Command line arguments of the `check` subcommand.
+#[derive(Args, Debug)]
+struct CheckArgs {
+    #[command(flatten)]
+    libvirt: LibvirtArgs,
+
+    /// MAC address the magic packet is sent for.
+    ///
+    /// Format: `xx:xx:xx:xx:xx:xx` (e.g., "52:54:00:12:34:56")
+    #[arg(value_name = "MAC", value_parser = wakeonlan::parse_mac_address_string)]
+    mac: wakeonlan::MacAddress,
+
+    /// SecureOn password the magic packet carries.
+    ///
+    /// Format: `xx:xx:xx:xx:xx:xx` (6 bytes) or `a.b.c.d` (4 bytes)
+    #[arg(long, value_name = "PASSWORD", value_parser = wakeonlan::parse_secureon_password_string)]
+    password: Option<wakeonlan::SecureOnPassword>,
+
+    /// IP address the magic packet is checked as coming from.
+    #[arg(short, long, value_name = "IP", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    source: IpAddr,
+}
+
+/// Command line arguments of the `send` subcommand.
+#[derive(Args, Debug)]
+struct SendArgs {
+    /// MAC address of the machine to wake.
+    ///
+    /// Format: `xx:xx:xx:xx:xx:xx` (e.g., "52:54:00:12:34:56")
+    #[arg(value_name = "MAC", value_parser = wakeonlan::parse_mac_address_string)]
+    mac: wakeonlan::MacAddress,
+
+    /// SecureOn password appended to the packet.
+    ///
+    /// Format: `xx:xx:xx:xx:xx:xx` (6 bytes) or `a.b.c.d` (4 bytes)
+    #[arg(long, value_name = "PASSWORD", value_parser = wakeonlan::parse_secureon_password_string)]
+    password: Option<wakeonlan::SecureOnPassword>,
+
+    /// IP address to send the packets to.
+    ///
+    /// Default: "127.0.0.1", or "255.255.255.255" with `--broadcast`
+    #[arg(short, long, value_name = "IP")]
+    ip: Option<IpAddr>,
+
+    /// UDP port to send the packets to.
+    #[arg(short, long, value_name = "PORT", default_value_t = 9)]
+    port: u16,
+
+    /// Allow sending to a broadcast address.
+    #[arg(short, long)]
+    broadcast: bool,
+
+    /// Number of packets to send.
+    #[arg(short = 'n', long, value_name = "COUNT", default_value_t = 1,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    count: u32,
+
+    /// Milliseconds to wait between packets.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 100)]
+    interval: u64,
 }
 
 /// Main entry point for the WOL Libvirt Gateway service.
@@ -56,6 +394,19 @@ struct Cli {
 /// - `RUST_LOG`: Controls the logging level (e.g., "debug", "info", "warn", "error")
 ///   Default: "info"
 ///
+/// # Exit Status
+///
+/// - `0`: Clean shutdown on SIGTERM or SIGINT
+/// - `2`: Invalid command line arguments
+/// - `66` (`EX_NOINPUT`): The configuration file could not be read
+/// - `69` (`EX_UNAVAILABLE`): libvirt could not be reached or queried
+/// - `71` (`EX_OSERR`): A socket could not be bound or a thread not started
+/// - `74` (`EX_IOERR`): All listeners stopped because of receive errors, or
+///   `send` could not send a packet
+/// - `75` (`EX_TEMPFAIL`): Queued wake requests did not finish on shutdown
+/// - `77` (`EX_NOPERM`): Binding a socket was not permitted
+/// - `78` (`EX_CONFIG`): The configuration is invalid
+///
 /// # Examples
 ///
 /// Start the service with default settings:
@@ -63,23 +414,85 @@ struct Cli {
 /// wol-libvirt-gateway
 /// ```
 ///
+/// Start the service with settings from a configuration file:
+/// ```bash
+/// wol-libvirt-gateway --config /etc/wol-libvirt-gateway.toml
+/// ```
+///
 /// Start the service on all interfaces with custom port:
 /// ```bash
 /// wol-libvirt-gateway --address 0.0.0.0:9009
 /// ```
 ///
+/// Also receive raw Ethernet WOL frames sent to the `br0` bridge:
+/// ```bash
+/// wol-libvirt-gateway --interface br0
+/// ```
+///
 /// Connect to a remote libvirt instance:
 /// ```bash
 /// wol-libvirt-gateway --libvirt-uri qemu+ssh://user@host/system
 /// ```
-#[tokio::main]
-async fn main() {
+///
+/// Send three magic packets to the LAN broadcast address:
+/// ```bash
+/// wol-libvirt-gateway send 52:54:00:12:34:56 -i 192.168.1.255 -b -n 3
+/// ```
+///
+/// List the MAC addresses of all domains as JSON:
+/// ```bash
+/// wol-libvirt-gateway list --format json
+/// ```
+///
+/// Check what a magic packet from the LAN would do, without starting any VM:
+/// ```bash
+/// wol-libvirt-gateway check 52:54:00:12:34:56 --source 192.168.1.20
+/// ```
+fn main() -> ExitCode {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     let args = Cli::parse();
 
-    info!(
-        "WOL Libvirt Gateway v{} starting...",
-        env!("CARGO_PKG_VERSION")
-    );
-    server::serve(args).await;
+    // The runtime is built by hand so that shutting it down does not wait for
+    // work still running on it, e.g. after the shutdown timeout expired
+    let result = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(WolGatewayError::RuntimeError)
+        .and_then(|runtime| {
+            let result = runtime.block_on(run(args));
+            runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+            result
+        });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("wol-libvirt-gateway: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// Runs the subcommand given on the command line, or the gateway service.
+///
+/// # Arguments
+///
+/// * `args` - The parsed command line arguments
+///
+/// # Errors
+///
+/// Returns the error the subcommand or service failed with.
+async fn run(args: Cli) -> Result<(), WolGatewayError> {
+    match &args.command {
+        Some(Command::Send(send)) => send::run(send).await,
+        Some(Command::List(list)) => list::run(list),
+        Some(Command::Check(check)) => check::run(check),
+        None => {
+            info!(
+                "WOL Libvirt Gateway v{} starting...",
+                env!("CARGO_PKG_VERSION")
+            );
+            server::serve(args).await
+        }
+    }
 }
@@ -0,0 +1,293 @@
+//! Prometheus metrics and the optional HTTP listener exposing them.
+//!
+//! The gateway counts received packets, the outcome of every wake request and
+//! every error by `WolGatewayError` variant, measures how long resolving and
+//! starting VMs takes, and tracks whether libvirt is connected. When a metrics
+//! address is configured, the metrics are served in the OpenMetrics text format
+//! on `GET /metrics`.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use tokio::net::TcpListener;
+
+use crate::error::WolGatewayError;
+
+/// Content type of the OpenMetrics text format.
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Label set holding a single label.
+type Labels = [(&'static str, &'static str); 1];
+
+/// The final outcome of a received packet.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Outcome {
+    /// The packet is not a valid magic packet.
+    Invalid,
+    /// The packet came from a denied source address.
+    Denied,
+    /// The request carried an invalid authentication token, or none while one
+    /// is required.
+    Unauthenticated,
+    /// The packet exceeded the rate limit of its source address.
+    RateLimited,
+    /// The packet repeated a recent request for the same MAC address.
+    Duplicate,
+    /// The packet was dropped because the libvirt worker was busy.
+    Dropped,
+    /// No VM owns the target MAC address.
+    NotFound,
+    /// The policy rejected the request, e.g. because of a wrong password or a
+    /// crashed VM that is not restarted.
+    Rejected,
+    /// The target VM was started or resumed, or was already running.
+    Started,
+    /// The target VM was resolved and authorized, but left alone in a dry run.
+    DryRun,
+    /// Resolving or starting the target VM failed.
+    Failed,
+}
+
+impl Outcome {
+    /// Returns the label value of the outcome.
+    fn label(self) -> &'static str {
+        match self {
+            Outcome::Invalid => "invalid",
+            Outcome::Denied => "denied",
+            Outcome::Unauthenticated => "unauthenticated",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Duplicate => "duplicate",
+            Outcome::Dropped => "dropped",
+            Outcome::NotFound => "not_found",
+            Outcome::Rejected => "rejected",
+            Outcome::Started => "started",
+            Outcome::DryRun => "dry_run",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// All metrics of the gateway, registered in a single registry.
+struct Metrics {
+    /// Registry the metrics are encoded from.
+    registry: Registry,
+    /// Packets received by all listeners.
+    packets: Counter,
+    /// Packets by final outcome.
+    outcomes: Family<Labels, Counter>,
+    /// Wake requests whose MAC address resolved to a VM.
+    matches: Counter,
+    /// Errors by `WolGatewayError` variant.
+    errors: Family<Labels, Counter>,
+    /// Time taken to resolve a MAC address to a VM.
+    lookup_seconds: Histogram,
+    /// Time taken to start or resume a VM.
+    start_seconds: Histogram,
+    /// Whether a libvirt connection is currently established.
+    libvirt_connected: Gauge,
+}
+
+/// The metrics of this process.
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let mut registry = Registry::with_prefix("wol");
+
+    let packets = Counter::default();
+    registry.register(
+        "packets_received",
+        "Packets received by all listeners",
+        packets.clone(),
+    );
+
+    let outcomes = Family::<Labels, Counter>::default();
+    registry.register(
+        "packets",
+        "Received packets by final outcome",
+        outcomes.clone(),
+    );
+
+    let matches = Counter::default();
+    registry.register(
+        "vm_matches",
+        "Wake requests whose MAC address resolved to a VM",
+        matches.clone(),
+    );
+
+    let errors = Family::<Labels, Counter>::default();
+    registry.register("errors", "Errors by kind", errors.clone());
+
+    // From 1ms to about 16s, starting a VM can take a few seconds
+    let lookup_seconds = Histogram::new(exponential_buckets(0.001, 2.0, 15));
+    registry.register(
+        "lookup_duration_seconds",
+        "Time taken to resolve a MAC address to a VM",
+        lookup_seconds.clone(),
+    );
+
+    let start_seconds = Histogram::new(exponential_buckets(0.001, 2.0, 15));
+    registry.register(
+        "start_duration_seconds",
+        "Time taken to start or resume a VM",
+        start_seconds.clone(),
+    );
+
+    let libvirt_connected = Gauge::default();
+    registry.register(
+        "libvirt_connected",
+        "Whether a libvirt connection is established",
+        libvirt_connected.clone(),
+    );
+
+    Metrics {
+        registry,
+        packets,
+        outcomes,
+        matches,
+        errors,
+        lookup_seconds,
+        start_seconds,
+        libvirt_connected,
+    }
+});
+
+/// Counts a packet received by one of the listeners.
+pub(crate) fn record_packet() {
+    METRICS.packets.inc();
+}
+
+/// Counts the final outcome of a received packet.
+pub(crate) fn record_outcome(outcome: Outcome) {
+    METRICS
+        .outcomes
+        .get_or_create(&[("outcome", outcome.label())])
+        .inc();
+}
+
+/// Counts a wake request whose MAC address resolved to a VM.
+pub(crate) fn record_match() {
+    METRICS.matches.inc();
+}
+
+/// Counts an error by its `WolGatewayError` variant.
+pub(crate) fn record_error(error: &WolGatewayError) {
+    METRICS
+        .errors
+        .get_or_create(&[("kind", error.kind())])
+        .inc();
+}
+
+/// Records the time taken to resolve a MAC address to a VM.
+pub(crate) fn observe_lookup(duration: Duration) {
+    METRICS.lookup_seconds.observe(duration.as_secs_f64());
+}
+
+/// Records the time taken to start or resume a VM.
+pub(crate) fn observe_start(duration: Duration) {
+    METRICS.start_seconds.observe(duration.as_secs_f64());
+}
+
+/// Sets whether a libvirt connection is currently established.
+pub(crate) fn set_libvirt_connected(connected: bool) {
+    METRICS.libvirt_connected.set(i64::from(connected));
+}
+
+/// Encodes all metrics in the OpenMetrics text format.
+pub(crate) fn encode_metrics() -> Result<String, std::fmt::Error> {
+    let mut buffer = String::new();
+    encode(&mut buffer, &METRICS.registry)?;
+    Ok(buffer)
+}
+
+/// Binds the metrics listener.
+///
+/// # Arguments
+///
+/// * `address` - The address and port to serve metrics on
+///
+/// # Errors
+///
+/// Returns `SocketBindError` if the address could not be bound.
+pub(crate) async fn bind(address: SocketAddr) -> Result<TcpListener, WolGatewayError> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| WolGatewayError::SocketBindError(address, e))
+}
+
+/// Serves the metrics on `GET /metrics` until accepting connections fails.
+///
+/// # Arguments
+///
+/// * `listener` - The bound metrics listener
+pub(crate) async fn serve(listener: TcpListener) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Critical metrics listener error: {}", e);
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let connection = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handle_request));
+            if let Err(e) = connection.await {
+                debug!("Metrics connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answers a single HTTP request to the metrics listener.
+async fn handle_request(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        return Ok(plain_response(StatusCode::NOT_FOUND, "Not Found\n"));
+    }
+
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        let mut response = plain_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed\n");
+        response
+            .headers_mut()
+            .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return Ok(response);
+    }
+
+    let response = match encode_metrics() {
+        Ok(body) => {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(OPENMETRICS_CONTENT_TYPE),
+            );
+            response
+        }
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            plain_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error\n")
+        }
+    };
+
+    Ok(response)
+}
+
+/// Builds a plain text response with the given status.
+fn plain_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+    *response.status_mut() = status;
+    response
+}
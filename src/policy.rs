@@ -0,0 +1,483 @@
+//! Wake policy applied to incoming wake requests before a VM is started.
+//!
+//! This module holds the rules that decide whether a wake request for a
+//! resolved libvirt domain may proceed, such as the SecureOn password that
+//! the magic packet has to carry or the source addresses it may come from.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use uuid::Uuid;
+
+use crate::auth::AuthSettings;
+use crate::error::WolGatewayError;
+use crate::server::PacketSource;
+use crate::wakeonlan::SecureOnPassword;
+
+/// The VM a wake request is aimed at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WakeTarget {
+    /// The VM owning a MAC address in the format "xx:xx:xx:xx:xx:xx", as
+    /// carried by magic packets.
+    Mac(String),
+    /// The domain with a given name or UUID, as requested over the HTTP API.
+    Domain(String),
+}
+
+impl fmt::Display for WakeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakeTarget::Mac(mac) => write!(f, "MAC {}", mac),
+            WakeTarget::Domain(domain) => write!(f, "domain {}", domain),
+        }
+    }
+}
+
+/// A request to wake a VM.
+///
+/// This carries everything the policy needs to know about where the request
+/// came from and which credentials it presented.
+#[derive(Debug)]
+pub(crate) struct WakeRequest {
+    /// The VM to wake.
+    pub(crate) target: WakeTarget,
+    /// The SecureOn password carried by the magic packet, if any.
+    pub(crate) password: Option<SecureOnPassword>,
+    /// Where the request was received from.
+    pub(crate) source: PacketSource,
+}
+
+/// Filter on the source address of wake requests.
+///
+/// Denied networks take precedence over allowed ones. IPv4-mapped IPv6
+/// addresses, as received on dual-stack sockets, are matched as IPv4.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SourceFilter {
+    /// Networks requests may come from; empty allows any source that is not denied.
+    pub(crate) allow: Vec<IpNet>,
+    /// Networks requests are never accepted from.
+    pub(crate) deny: Vec<IpNet>,
+}
+
+impl SourceFilter {
+    /// Returns whether requests from the given address pass the filter.
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// What to do with the managed save image of a domain that is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ManagedSave {
+    /// Restore the saved memory state, as `virsh start` does.
+    #[default]
+    Restore,
+    /// Discard the saved memory state and boot the domain from scratch.
+    Discard,
+}
+
+/// What a wake request does to a VM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum WakeAction {
+    /// Start the VM from any startable state.
+    #[default]
+    Start,
+    /// Only resume paused or suspended VMs, never boot shut off ones.
+    Resume,
+}
+
+/// How a resolved VM is started, depending on the state it is in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct StartOptions {
+    /// What the wake request does to the VM.
+    pub(crate) action: WakeAction,
+    /// What to do with a managed save image of a shut off domain.
+    pub(crate) managed_save: ManagedSave,
+    /// Whether domains that crashed are restarted.
+    pub(crate) restart_crashed: bool,
+}
+
+/// Rules applying to a single VM.
+///
+/// These come from a `[[vm]]` table of the gateway configuration, or from the
+/// `<wol:policy>` element in the metadata of the domain itself.
+#[derive(Debug, Clone)]
+pub(crate) struct VmPolicy {
+    /// Whether the VM may be woken at all.
+    pub(crate) enabled: bool,
+    /// SecureOn password required for this VM, overriding the global password.
+    pub(crate) password: Option<SecureOnPassword>,
+    /// Source filter applied on top of the global one for this VM.
+    pub(crate) sources: SourceFilter,
+    /// What a wake request does to the VM.
+    pub(crate) action: Option<WakeAction>,
+    /// What to do with a managed save image, overriding the global setting.
+    pub(crate) managed_save: Option<ManagedSave>,
+    /// Whether the VM is restarted after a crash, overriding the global setting.
+    pub(crate) restart_crashed: Option<bool>,
+}
+
+impl Default for VmPolicy {
+    fn default() -> Self {
+        VmPolicy {
+            enabled: true,
+            password: None,
+            sources: SourceFilter::default(),
+            action: None,
+            managed_save: None,
+            restart_crashed: None,
+        }
+    }
+}
+
+/// Selects domains by name or UUID, in opt-in mode or among domains sharing a
+/// MAC address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DomainSelector {
+    /// The domain with this UUID.
+    Uuid(Uuid),
+    /// Domains whose name matches this glob, where `*` matches any number of
+    /// characters and `?` matches a single one.
+    Name(String),
+}
+
+impl DomainSelector {
+    /// Parses a selector, treating anything that is not a UUID as a name glob.
+    pub(crate) fn parse(selector: &str) -> Self {
+        match Uuid::parse_str(selector) {
+            Ok(uuid) => DomainSelector::Uuid(uuid),
+            Err(_) => DomainSelector::Name(selector.to_string()),
+        }
+    }
+
+    /// Returns whether the selector matches the given domain.
+    pub(crate) fn matches(&self, vm_name: &str, vm_uuid: &Uuid) -> bool {
+        match self {
+            DomainSelector::Uuid(uuid) => uuid == vm_uuid,
+            DomainSelector::Name(glob) => glob_matches(glob, vm_name),
+        }
+    }
+}
+
+/// Matches a name against a glob supporting the `*` and `?` wildcards.
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Position after the last `*` in the glob and in the name it was matched at,
+    // to backtrack to when the rest of the glob does not match
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut g, mut n) = (0, 0);
+
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                g += 1;
+                backtrack = Some((g, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_g, star_n)) => {
+                    g = star_g;
+                    n = star_n + 1;
+                    backtrack = Some((star_g, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Settings of the opt-in mode, in which only selected domains may be woken.
+///
+/// Domains always have to opt in through a `<wol:policy>` element in their
+/// metadata, this mode narrows them down further. A domain is selected if any
+/// of these apply:
+/// - It matches one of the configured name globs or UUIDs
+/// - It has a `[[vm]]` table in the gateway configuration
+/// - Its title or description contains the marker
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OptIn {
+    /// Domains selected by name glob or UUID.
+    pub(crate) domains: Vec<DomainSelector>,
+    /// Text selecting a domain when found in its title or description.
+    pub(crate) marker: String,
+}
+
+/// What to do when several domains share the MAC address of a wake request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum DuplicateMacs {
+    /// Refuse the request, since the wrong VM may be started.
+    #[default]
+    Refuse,
+    /// Wake the domain matched by the first of these selectors that matches
+    /// any of the domains, refusing the request if it matches several.
+    Prefer(Vec<DomainSelector>),
+    /// Wake all the domains.
+    All,
+}
+
+/// Rules deciding whether a wake request may start a VM.
+#[derive(Debug, Default)]
+pub(crate) struct Policy {
+    /// SecureOn password required for every VM without a per-VM password.
+    pub(crate) password: Option<SecureOnPassword>,
+    /// Source filter applied to every request received over IP.
+    pub(crate) sources: SourceFilter,
+    /// How VMs without per-VM start options are started.
+    pub(crate) start: StartOptions,
+    /// Per-VM rules keyed by domain name or UUID.
+    pub(crate) vms: HashMap<String, VmPolicy>,
+    /// Explicit MAC address to domain name or UUID mappings, keyed by lowercase MAC.
+    pub(crate) mappings: HashMap<String, String>,
+    /// Opt-in mode settings; without them, every domain that opted in through
+    /// its metadata may be woken.
+    pub(crate) opt_in: Option<OptIn>,
+    /// Client keys for authenticated wake requests.
+    pub(crate) auth: AuthSettings,
+    /// Whether wake requests only report what they would do to a VM.
+    pub(crate) dry_run: bool,
+    /// What to do when several domains share a MAC address.
+    pub(crate) duplicate_macs: DuplicateMacs,
+}
+
+impl Policy {
+    /// Returns the domain explicitly mapped to a MAC address, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac` - The MAC address to look up (case-insensitive)
+    ///
+    /// # Returns
+    ///
+    /// The mapped domain name or UUID, which takes precedence over XML discovery
+    pub(crate) fn mapped_domain(&self, mac: &str) -> Option<&str> {
+        self.mappings.get(&mac.to_lowercase()).map(String::as_str)
+    }
+
+    /// Checks the source of a request against the global source filter.
+    ///
+    /// This only needs the packet source, so it is checked before any libvirt
+    /// work is done. Raw Ethernet frames carry no IP address and pass this check,
+    /// their listeners are scoped by interface instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SourceNotAllowed` if the source address is denied or not allowed.
+    pub(crate) fn check_source(&self, source: &PacketSource) -> Result<(), WolGatewayError> {
+        match source.ip() {
+            Some(ip) if !self.sources.permits(ip) => {
+                Err(WolGatewayError::SourceNotAllowed(ip.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Selects the domains to wake among several sharing a MAC address.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac` - The MAC address the domains share
+    /// * `candidates` - The names and UUIDs of the domains owning the MAC address
+    ///
+    /// # Returns
+    ///
+    /// The indices of the selected candidates, all of them for a single one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AmbiguousMac` listing the candidates if several domains share
+    /// the MAC address and duplicates are refused, or no single domain is
+    /// preferred.
+    pub(crate) fn select_duplicates(
+        &self,
+        mac: &str,
+        candidates: &[(String, Uuid)],
+    ) -> Result<Vec<usize>, WolGatewayError> {
+        if candidates.len() <= 1 {
+            return Ok((0..candidates.len()).collect());
+        }
+
+        let preferred = match &self.duplicate_macs {
+            DuplicateMacs::Refuse => None,
+            DuplicateMacs::All => return Ok((0..candidates.len()).collect()),
+            DuplicateMacs::Prefer(selectors) => selectors.iter().find_map(|selector| {
+                let matching: Vec<usize> = candidates
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (name, uuid))| selector.matches(name, uuid))
+                    .map(|(i, _)| i)
+                    .collect();
+                (!matching.is_empty()).then_some(matching)
+            }),
+        };
+
+        match preferred {
+            Some(preferred) if preferred.len() == 1 => Ok(preferred),
+            _ => Err(WolGatewayError::AmbiguousMac(
+                mac.to_string(),
+                candidates.iter().map(|(name, _)| name.clone()).collect(),
+            )),
+        }
+    }
+
+    /// Returns the rules for a VM, looked up by name first and UUID second.
+    fn vm(&self, vm_name: &str, vm_uuid: &Uuid) -> Option<&VmPolicy> {
+        self.vms
+            .get(vm_name)
+            .or_else(|| self.vms.get(&vm_uuid.to_string()))
+    }
+
+    /// Checks whether a VM opted in to waking and, when opt-in mode is enabled,
+    /// is selected.
+    ///
+    /// A VM without a policy in its metadata is never woken. Whether the policy
+    /// enables the VM is left to `authorize`. The title and description of the
+    /// domain are only fetched when no other selector matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_name` - The name of the resolved libvirt domain
+    /// * `vm_uuid` - The UUID of the resolved libvirt domain
+    /// * `metadata` - The policy from the domain metadata, if any
+    /// * `annotations` - Fetches the title and description of the domain
+    ///
+    /// # Errors
+    ///
+    /// Returns `VmNotWakeable` if the VM has no policy in its metadata or opt-in
+    /// mode is enabled and the VM is not selected, or any error returned by
+    /// `annotations`.
+    pub(crate) fn check_wakeable(
+        &self,
+        vm_name: &str,
+        vm_uuid: &Uuid,
+        metadata: Option<&VmPolicy>,
+        annotations: impl FnOnce() -> Result<Vec<String>, WolGatewayError>,
+    ) -> Result<(), WolGatewayError> {
+        if metadata.is_none() {
+            return Err(WolGatewayError::VmNotWakeable(vm_name.to_string()));
+        }
+
+        let Some(opt_in) = &self.opt_in else {
+            return Ok(());
+        };
+
+        let selected = opt_in
+            .domains
+            .iter()
+            .any(|selector| selector.matches(vm_name, vm_uuid))
+            || self.vm(vm_name, vm_uuid).is_some()
+            || annotations()?
+                .iter()
+                .any(|text| text.contains(&opt_in.marker));
+
+        if selected {
+            Ok(())
+        } else {
+            Err(WolGatewayError::VmNotWakeable(vm_name.to_string()))
+        }
+    }
+
+    /// Returns how the given VM is started, applying its per-VM overrides.
+    ///
+    /// Only the gateway configuration decides this, the domain metadata cannot.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_name` - The name of the resolved libvirt domain
+    /// * `vm_uuid` - The UUID of the resolved libvirt domain
+    pub(crate) fn start_options(&self, vm_name: &str, vm_uuid: &Uuid) -> StartOptions {
+        let rules = self.vm(vm_name, vm_uuid);
+
+        StartOptions {
+            action: rules.and_then(|vm| vm.action).unwrap_or(self.start.action),
+            managed_save: rules
+                .and_then(|vm| vm.managed_save)
+                .unwrap_or(self.start.managed_save),
+            restart_crashed: rules
+                .and_then(|vm| vm.restart_crashed)
+                .unwrap_or(self.start.restart_crashed),
+        }
+    }
+
+    /// Checks whether a wake request may start the given VM.
+    ///
+    /// The rules from the gateway configuration and from the domain metadata
+    /// both apply: either can disable the VM or restrict its sources, and a
+    /// password from the configuration takes precedence over one from the metadata.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The wake request being evaluated
+    /// * `vm_name` - The name of the resolved libvirt domain
+    /// * `vm_uuid` - The UUID of the resolved libvirt domain
+    /// * `metadata` - The policy from the domain metadata, if any
+    ///
+    /// # Errors
+    ///
+    /// Returns `SourceNotAllowed` if the request came from an address rejected by
+    /// the global or the VM's source filters, or is a raw Ethernet frame for a VM
+    /// with an allow list, `VmDisabled` if waking the VM is disabled, or
+    /// `PasswordMismatch` if the VM requires a SecureOn password and the request
+    /// carried none or a different one.
+    pub(crate) fn authorize(
+        &self,
+        request: &WakeRequest,
+        vm_name: &str,
+        vm_uuid: &Uuid,
+        metadata: Option<&VmPolicy>,
+    ) -> Result<(), WolGatewayError> {
+        self.check_source(&request.source)?;
+
+        let rules = [self.vm(vm_name, vm_uuid), metadata];
+        let mut rules = rules.iter().flatten();
+
+        // Raw Ethernet frames carry no address to match an allow list against,
+        // so a VM restricted to some networks is never woken by them
+        let permitted = match request.source.ip() {
+            Some(ip) => rules.clone().all(|vm| vm.sources.permits(ip)),
+            None => rules.clone().all(|vm| vm.sources.allow.is_empty()),
+        };
+        if !permitted {
+            return Err(WolGatewayError::SourceNotAllowed(
+                request
+                    .source
+                    .ip()
+                    .map_or_else(|| request.source.to_string(), |ip| ip.to_string()),
+            ));
+        }
+
+        if rules.clone().any(|vm| !vm.enabled) {
+            return Err(WolGatewayError::VmDisabled(vm_name.to_string()));
+        }
+
+        let required = rules
+            .find_map(|vm| vm.password.as_ref())
+            .or(self.password.as_ref());
+
+        match required {
+            Some(required)
+                if !request
+                    .password
+                    .as_ref()
+                    .is_some_and(|password| password.matches(required)) =>
+            {
+                Err(WolGatewayError::PasswordMismatch(vm_name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Deduplication and rate limiting of wake requests.
+//!
+//! Wake-on-LAN tools usually send a burst of several identical magic packets,
+//! and every one of them would otherwise cost an index lookup and a libvirt
+//! start attempt. This module collapses repeated requests for the same MAC
+//! address within a short window into one, whoever sends them, and limits the
+//! number of packets accepted from each sender with a token bucket, so a flood
+//! cannot hammer libvirtd or spam the logs. A request that fails is forgotten
+//! again, so it does not suppress retries.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::server::PacketSource;
+use crate::wakeonlan::{mac_to_string, MacAddress};
+
+/// Maximum number of recent requests or sources tracked at once.
+const MAX_TRACKED: usize = 4096;
+
+/// Settings for deduplication and rate limiting.
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    /// Window in which repeated requests for the same MAC address are collapsed.
+    ///
+    /// A zero window disables deduplication.
+    pub(crate) dedup_window: Duration,
+    /// Packets per second accepted from each sender in the long run.
+    ///
+    /// A rate of zero disables rate limiting.
+    pub(crate) rate: f64,
+    /// Number of packets a sender may send in a burst before being limited.
+    pub(crate) burst: u32,
+}
+
+/// Token bucket of a single sender.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens currently available, one is taken per accepted packet.
+    tokens: f64,
+    /// When the tokens were last refilled.
+    refilled_at: Instant,
+    /// Whether packets from the source are currently being dropped.
+    limited: bool,
+}
+
+/// The outcome of checking a packet against the source rate limit.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RateDecision {
+    /// The packet is accepted.
+    Accept,
+    /// The packet is the first one dropped since the source exceeded its limit.
+    StartLimiting,
+    /// The packet is dropped, and earlier ones from the source were as well.
+    Limited,
+}
+
+/// The sender of a wake request, as far as rate limiting is concerned.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Sender {
+    /// A source IP address; the port is ignored, since WOL tools send every
+    /// packet of a burst from a new socket.
+    Ip(IpAddr),
+    /// A source MAC address on a listening interface.
+    Ethernet {
+        /// Name of the interface the frame was received on.
+        interface: String,
+        /// Source MAC address of the frame, if known.
+        mac: Option<MacAddress>,
+    },
+}
+
+impl Sender {
+    /// Returns the sender of a request received from the given source.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated as their IPv4 address.
+    pub(crate) fn new(source: &PacketSource) -> Self {
+        match source {
+            PacketSource::Udp(addr) | PacketSource::Http(addr) => {
+                Sender::Ip(addr.ip().to_canonical())
+            }
+            PacketSource::Ethernet { interface, mac } => Sender::Ethernet {
+                interface: interface.clone(),
+                mac: *mac,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sender::Ip(ip) => write!(f, "{}", ip),
+            Sender::Ethernet {
+                interface,
+                mac: Some(mac),
+            } => write!(f, "{} on {}", mac_to_string(mac), interface),
+            Sender::Ethernet {
+                interface,
+                mac: None,
+            } => write!(f, "unknown MAC on {}", interface),
+        }
+    }
+}
+
+/// Identifies repeated wake requests: requests for the same MAC address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RequestKey {
+    /// The lowercase target MAC address.
+    mac: String,
+}
+
+impl RequestKey {
+    /// Creates the key of a wake request.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac` - The target MAC address of the request
+    pub(crate) fn new(mac: &str) -> Self {
+        RequestKey {
+            mac: mac.to_lowercase(),
+        }
+    }
+}
+
+/// A map holding at most `MAX_TRACKED` entries.
+///
+/// When it is full, the entry inserted first is evicted. A flood of packets from
+/// spoofed source addresses thereby costs constant time per packet and bounded
+/// memory, instead of growing the map or scanning it for stale entries.
+#[derive(Debug)]
+struct BoundedMap<K, V> {
+    /// The entries, each with the sequence number it was inserted with.
+    entries: HashMap<K, (u64, V)>,
+    /// Keys in insertion order, with their sequence numbers. Keys that were
+    /// removed or inserted again since are skipped on eviction.
+    order: VecDeque<(u64, K)>,
+    /// Sequence number of the next inserted entry.
+    next: u64,
+}
+
+impl<K, V> Default for BoundedMap<K, V> {
+    fn default() -> Self {
+        BoundedMap {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> BoundedMap<K, V> {
+    /// Returns the value of a key, if present.
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    /// Returns the value of a key, inserting it first if it is not present.
+    fn get_or_insert_with(&mut self, key: K, value: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            self.make_room();
+        }
+
+        match self.entries.entry(key) {
+            Entry::Occupied(entry) => &mut entry.into_mut().1,
+            Entry::Vacant(entry) => {
+                let sequence = self.next;
+                self.next += 1;
+                self.order.push_back((sequence, entry.key().clone()));
+                &mut entry.insert((sequence, value())).1
+            }
+        }
+    }
+
+    /// Inserts or replaces the value of a key, making it the newest entry.
+    fn insert(&mut self, key: K, value: V) {
+        self.entries.remove(&key);
+        self.get_or_insert_with(key, || value);
+    }
+
+    /// Removes a key, if present.
+    fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    /// Evicts the oldest entries until one more fits.
+    ///
+    /// Skipped keys are dropped from the insertion order as well, which is
+    /// kept at most twice as long as the limit.
+    fn make_room(&mut self) {
+        while self.entries.len() >= MAX_TRACKED || self.order.len() >= 2 * MAX_TRACKED {
+            let Some((sequence, key)) = self.order.pop_front() else {
+                break;
+            };
+            if self.entries.get(&key).is_some_and(|(s, _)| *s == sequence) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Tracks recent wake requests to deduplicate and rate limit them.
+#[derive(Debug, Default)]
+pub(crate) struct RequestLimiter {
+    /// When a request was last acted upon.
+    recent: BoundedMap<RequestKey, Instant>,
+    /// Token buckets keyed by sender.
+    buckets: BoundedMap<Sender, TokenBucket>,
+}
+
+impl RequestLimiter {
+    /// Creates a limiter without any tracked requests.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a token from the bucket of a sender.
+    ///
+    /// Packets received over IP are limited per source address, raw Ethernet
+    /// frames per listening interface and source MAC address.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The current rate limit settings
+    /// * `sender` - The sender of the packet
+    /// * `now` - The time the packet is processed at
+    ///
+    /// # Returns
+    ///
+    /// Whether the packet is accepted, and if not, whether it is the first one
+    /// dropped since the sender exceeded its limit
+    pub(crate) fn check_rate(
+        &mut self,
+        limits: &Limits,
+        sender: &Sender,
+        now: Instant,
+    ) -> RateDecision {
+        if limits.rate <= 0.0 {
+            return RateDecision::Accept;
+        }
+
+        let burst = f64::from(limits.burst);
+        let bucket = self
+            .buckets
+            .get_or_insert_with(sender.clone(), || TokenBucket {
+                tokens: burst,
+                refilled_at: now,
+                limited: false,
+            });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * limits.rate).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            RateDecision::Accept
+        } else if bucket.limited {
+            RateDecision::Limited
+        } else {
+            bucket.limited = true;
+            RateDecision::StartLimiting
+        }
+    }
+
+    /// Records a request unless an identical one was recorded within the
+    /// deduplication window.
+    ///
+    /// Requests are collapsed per target MAC address, whoever sends them. The
+    /// recorded request should be forgotten if it fails, so that a request that
+    /// is denied, e.g. for a wrong password, does not suppress a valid one.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The current deduplication settings
+    /// * `key` - The target MAC address of the request
+    /// * `now` - The time the request is processed at
+    ///
+    /// # Returns
+    ///
+    /// `true` if the request should be acted upon, `false` if it is a duplicate
+    pub(crate) fn check_duplicate(
+        &mut self,
+        limits: &Limits,
+        key: &RequestKey,
+        now: Instant,
+    ) -> bool {
+        if limits.dedup_window.is_zero() {
+            return true;
+        }
+
+        let window = limits.dedup_window;
+        match self.recent.get(key) {
+            Some(seen) if now.saturating_duration_since(*seen) < window => false,
+            _ => {
+                self.recent.insert(key.clone(), now);
+                true
+            }
+        }
+    }
+
+    /// Forgets a recorded request, so that a retry is acted upon right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The target MAC address of the failed request
+    pub(crate) fn forget(&mut self, key: &RequestKey) {
+        self.recent.remove(key);
+    }
+
+    /// Returns the number of tracked requests and senders.
+    #[cfg(test)]
+    pub(crate) fn tracked(&self) -> (usize, usize) {
+        (self.recent.entries.len(), self.buckets.entries.len())
+    }
+}
@@ -0,0 +1,69 @@
+//! The `send` subcommand, emitting magic packets.
+//!
+//! This makes testing the gateway possible without installing `wakeonlan` or
+//! `etherwake`. Packets are built with the inverse of the parser the gateway
+//! uses, so whatever is sent here is accepted by the listeners.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::info;
+use tokio::net::UdpSocket;
+
+use crate::error::WolGatewayError;
+use crate::wakeonlan::{build_magic_packet, mac_to_string};
+use crate::SendArgs;
+
+/// Address magic packets are sent to by default, where the gateway listens by default.
+const DEFAULT_TARGET: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Address magic packets are sent to by default with `--broadcast`.
+const DEFAULT_BROADCAST_TARGET: IpAddr = IpAddr::V4(Ipv4Addr::BROADCAST);
+
+/// Sends magic packets as described by the `send` subcommand arguments.
+///
+/// # Arguments
+///
+/// * `args` - The target MAC address, destination and repetition settings
+///
+/// # Errors
+///
+/// Returns `SocketBindError` if no local UDP socket could be bound, or
+/// `UdpSendError` if enabling broadcasts or sending a packet failed.
+pub(crate) async fn run(args: &SendArgs) -> Result<(), WolGatewayError> {
+    let ip = args.ip.unwrap_or(if args.broadcast {
+        DEFAULT_BROADCAST_TARGET
+    } else {
+        DEFAULT_TARGET
+    });
+    let target = SocketAddr::new(ip, args.port);
+
+    let local = match ip {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|e| WolGatewayError::SocketBindError(local, e))?;
+    if args.broadcast {
+        socket
+            .set_broadcast(true)
+            .map_err(WolGatewayError::UdpSendError)?;
+    }
+
+    let packet = build_magic_packet(&args.mac, args.password.as_ref());
+    let mac = mac_to_string(&args.mac);
+
+    for i in 0..args.count {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_millis(args.interval)).await;
+        }
+        socket
+            .send_to(&packet, target)
+            .await
+            .map_err(WolGatewayError::UdpSendError)?;
+        info!("Sent magic packet for MAC {} to {}", mac, target);
+    }
+
+    Ok(())
+}
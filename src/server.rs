@@ -1,89 +1,409 @@
 //! Wake-on-LAN server module for handling incoming WOL packets and managing virtual machines.
 
 use crate::{
-    error::WolGatewayError, libvirt::find_and_start_vm_by_mac, wakeonlan::WakeOnLanPacket, Cli,
+    api::{self, ApiRequest},
+    auth::{self, NonceCache, TokenAction, WakeToken},
+    config::Config,
+    connection::LibvirtConnection,
+    error::WolGatewayError,
+    ethernet::EthernetListener,
+    mac_index::MacIndex,
+    metrics::{self, Outcome},
+    policy::{Policy, WakeRequest, WakeTarget},
+    rate_limit::{Limits, RateDecision, RequestKey, RequestLimiter, Sender},
+    systemd, udp,
+    wakeonlan::{mac_to_string, MacAddress, WakeOnLanPacket},
+    worker::{Job, LibvirtWorker, StatusJob, WakeJob},
+    Cli,
 };
 use log::{debug, error, info, warn};
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use virt::connect::Connect;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::Interval;
 
-/// Maximum expected size for a WOL packet (102 bytes minimum + 6 bytes password).
-const WOL_BUFFER_SIZE: usize = 108;
+/// Size of the receive buffer, large enough for padded WOL packets.
+///
+/// Datagrams and frames that do not fit are truncated by the kernel. Their
+/// trailer is then longer than any SecureOn password and ignored as padding.
+const WOL_BUFFER_SIZE: usize = 1500;
+
+/// Maximum number of received packets and API requests waiting to be processed.
+const PACKET_QUEUE_SIZE: usize = 64;
+
+/// Time queued wake requests are given to finish on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of wake requests rejected because of their source address.
+static DENIED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+/// Describes where a packet or API request was received from.
+#[derive(Debug, Clone)]
+pub(crate) enum PacketSource {
+    /// A UDP datagram sent from the given address.
+    Udp(SocketAddr),
+    /// An HTTP API request from the given address.
+    Http(SocketAddr),
+    /// A raw Ethernet frame received on an interface.
+    Ethernet {
+        /// Name of the interface the frame was received on.
+        interface: String,
+        /// Source MAC address of the frame, if known.
+        mac: Option<MacAddress>,
+    },
+}
+
+impl fmt::Display for PacketSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketSource::Udp(addr) => write!(f, "{}", addr),
+            PacketSource::Http(addr) => write!(f, "{} (HTTP API)", addr),
+            PacketSource::Ethernet {
+                interface,
+                mac: Some(mac),
+            } => write!(f, "{} on {}", mac_to_string(mac), interface),
+            PacketSource::Ethernet {
+                interface,
+                mac: None,
+            } => write!(f, "unknown MAC on {}", interface),
+        }
+    }
+}
+
+impl PacketSource {
+    /// Returns the source IP address, if the packet was received over IP.
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PacketSource::Udp(addr) | PacketSource::Http(addr) => Some(addr.ip()),
+            PacketSource::Ethernet { .. } => None,
+        }
+    }
+}
+
+/// Something received by one of the listeners, waiting to be processed.
+#[derive(Debug)]
+pub(crate) enum Received {
+    /// A packet received by a UDP or raw Ethernet listener.
+    Packet(ReceivedPacket),
+    /// A request received by the HTTP API.
+    Api(ApiRequest),
+}
+
+/// A packet received by one of the listeners, waiting to be processed.
+#[derive(Debug)]
+pub(crate) struct ReceivedPacket {
+    /// Raw packet data, truncated to `WOL_BUFFER_SIZE` bytes.
+    data: Vec<u8>,
+    /// Where the packet was received from.
+    source: PacketSource,
+}
 
 /// Starts the WOL gateway server that listens for Wake-on-LAN packets and manages VMs.
 ///
-/// This function establishes a connection to libvirt, binds a UDP socket to listen for
-/// Wake-on-LAN packets, and processes incoming packets by attempting to start the
-/// corresponding virtual machines identified by MAC address.
+/// This function establishes a connection to libvirt, binds a UDP socket and/or raw
+/// Ethernet sockets to listen for Wake-on-LAN packets, and processes incoming packets
+/// by attempting to start the corresponding virtual machines identified by MAC address.
 ///
 /// # Arguments
 ///
-/// * `args` - CLI arguments containing the libvirt URI and listen address configuration
+/// * `args` - CLI arguments, including the optional configuration file path
 ///
 /// # Behavior
 ///
-/// The function runs in an infinite loop, processing incoming UDP packets:
-/// 1. Validates each packet as a proper WOL magic packet
-/// 2. Extracts the target MAC address from valid packets
-/// 3. Searches for a VM with a matching MAC address in libvirt
-/// 4. Attempts to start the VM if found
+/// Every listener runs in its own task and forwards received packets to a single
+/// processing loop, which reloads the configuration on SIGHUP and runs until
+/// SIGTERM or SIGINT is received or all listeners have stopped. For each packet,
+/// the loop:
+/// 1. Drops packets from denied or rate limited sources, and all packets if
+///    authentication is required, as magic packets carry no token
+/// 2. Validates the packet as a proper WOL magic packet
+/// 3. Extracts the target MAC address from the packet
+/// 4. Drops repeated requests for the same MAC address within the dedup window
+/// 5. Queues a wake request for the libvirt worker
+///
+/// Requests to the HTTP API are handed to the same loop, pass the same source
+/// filters and rate limits, and are answered once the worker has carried them out.
+///
+/// The libvirt worker runs on its own thread, so a slow domain start never stalls
+/// packet reception. For each request, it looks up the VM with a matching MAC
+/// address in the MAC address index and attempts to start it if found. Between
+/// requests, it checks the libvirt connection every keepalive interval and
+/// reconnects with exponential backoff if libvirtd went away.
+///
+/// On shutdown, the listeners are stopped first, then the worker is given
+/// `SHUTDOWN_TIMEOUT` to finish the queued wake requests, including a VM start in
+/// progress, before it closes the libvirt connection.
 ///
 /// # Errors
 ///
-/// The function will log errors and exit early on:
+/// Returns the error that prevented the gateway from starting:
+/// - Unreadable or invalid configuration, including no listener being enabled
 /// - Failed libvirt connection
-/// - Invalid listen address parsing
-/// - UDP socket binding failures
-/// - Critical UDP receive errors
+/// - Failed initial MAC address index build
+/// - UDP socket binding or socket activation failures
+/// - Raw Ethernet socket creation or binding failures
+/// - Metrics or HTTP API listener binding failures
+/// - Failure to install signal handlers or start the libvirt worker thread
 ///
-/// Non-critical errors (invalid packets, VM not found) are logged but don't stop the server.
-pub(crate) async fn serve(args: Cli) {
-    info!("Attempting to connect to libvirt URI: {}", args.libvirt_uri);
+/// Once started, it returns `ListenersStopped` if all listeners have stopped, or
+/// `ShutdownTimeout` if the queued wake requests did not finish in time.
+/// Critical receive errors stop the affected listener only. Non-critical errors
+/// (invalid packets, VM not found) are logged but don't stop the server.
+pub(crate) async fn serve(args: Cli) -> Result<(), WolGatewayError> {
+    // Resolve the configuration from the configuration file and the CLI flags
+    let mut config = Config::load(&args)?;
+
+    if config.policy.dry_run {
+        warn!("Dry run enabled, wake requests are resolved but no VM is started");
+    }
+
+    info!(
+        "Attempting to connect to libvirt URI: {}",
+        config.libvirt_uri
+    );
 
     // Establish libvirt connection
-    let conn = match Connect::open(Some(&args.libvirt_uri)) {
-        Ok(conn) => {
-            let hostname = conn.get_hostname().unwrap_or_else(|_| "N/A".to_string());
-            info!("Successfully connected to libvirt host: {}", hostname);
-            conn
+    let mut connection = LibvirtConnection::open(&config.libvirt_uri)?;
+
+    // Build the MAC address index of all defined domains
+    let mut index = MacIndex::new(config.index_max_age);
+    connection.get().and_then(|conn| index.rebuild(conn))?;
+
+    let (tx, mut rx) = mpsc::channel(PACKET_QUEUE_SIZE);
+
+    // Listener tasks, aborted on shutdown
+    let mut listeners = JoinSet::new();
+
+    // Sockets passed by systemd replace the configured listen addresses
+    let activated = systemd::listen_sockets()?;
+
+    if config.udp && !activated.is_empty() {
+        for socket in activated {
+            let socket = udp::from_activated(socket, &config.multicast)?;
+            if let Ok(address) = socket.local_addr() {
+                info!(
+                    "Listening for WOL packets on {} (socket activated)",
+                    address
+                );
+            }
+            listeners.spawn(receive_udp(socket, tx.clone()));
         }
-        Err(e) => {
-            error!("{}", WolGatewayError::LibvirtConnectError(e));
-            return;
+    } else if config.udp {
+        // Bind UDP sockets for receiving WOL packets
+        for &address in &config.addresses {
+            // An IPv6 wildcard listener would take the port from IPv4 listeners
+            let v6_only = config
+                .addresses
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == address.port());
+            let socket = udp::bind(address, v6_only, &config.multicast)?;
+            info!("Listening for WOL packets on {}", address);
+            listeners.spawn(receive_udp(socket, tx.clone()));
         }
-    };
+    } else if !activated.is_empty() {
+        warn!("Ignoring sockets passed by systemd, the UDP listeners are disabled");
+    }
 
-    // Parse the listen address
-    let listen_addr: SocketAddr = match args.address.parse() {
-        Ok(addr) => addr,
-        Err(e) => {
-            error!("{}", WolGatewayError::AddressParseError(e));
-            return;
+    // Bind raw Ethernet sockets for receiving WOL frames
+    for interface in &config.interfaces {
+        let listener = EthernetListener::bind(interface)?;
+        info!("Listening for WOL frames on interface {}", interface);
+        listeners.spawn(receive_ethernet(listener, tx.clone()));
+    }
+
+    // Serve Prometheus metrics if enabled
+    if let Some(address) = config.metrics_address {
+        let listener = metrics::bind(address).await?;
+        info!("Serving metrics on http://{}/metrics", address);
+        listeners.spawn(metrics::serve(listener));
+    }
+
+    // Serve the HTTP API if enabled
+    if let Some(address) = config.api_address {
+        let listener = api::bind(address).await?;
+        info!("Serving the HTTP API on http://{}", address);
+        listeners.spawn(api::serve(listener, tx.clone()));
+    }
+
+    // Only the listener tasks keep the channel open from here on
+    drop(tx);
+
+    // Reload the configuration on SIGHUP, shut down on SIGTERM and SIGINT
+    let mut sighup = signal(SignalKind::hangup()).map_err(WolGatewayError::SignalHandlerError)?;
+    let mut sigterm =
+        signal(SignalKind::terminate()).map_err(WolGatewayError::SignalHandlerError)?;
+    let mut sigint =
+        signal(SignalKind::interrupt()).map_err(WolGatewayError::SignalHandlerError)?;
+
+    // Deduplication and rate limiting state, the settings live in the configuration
+    let mut limiter = RequestLimiter::new();
+
+    // Failed wake requests are forgotten by the limiter, so retries are not deduplicated
+    let (failed_tx, mut failed_rx) = mpsc::unbounded_channel();
+
+    // Nonces of accepted authentication tokens, the keys live in the configuration
+    let mut nonces = NonceCache::new();
+
+    // Hand the libvirt connection and the index over to the worker thread
+    let worker = LibvirtWorker::spawn(connection, index, config.keepalive_interval)?;
+
+    // Ping the systemd watchdog from the processing loop, so a stuck loop is restarted
+    let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+
+    systemd::notify_ready(&format!("Connected to {}", config.libvirt_uri));
+
+    // Main packet processing loop, until a signal asks to stop or all listeners died
+    let stop_signal = loop {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(Received::Packet(packet)) => {
+                    debug!(
+                        "Received {} bytes from {}",
+                        packet.data.len(),
+                        packet.source
+                    );
+                    metrics::record_packet();
+
+                    // Process the received packet
+                    handle_packet(
+                        &worker,
+                        &mut limiter,
+                        &failed_tx,
+                        &config.limits,
+                        &config.policy,
+                        packet,
+                    );
+                }
+                Some(Received::Api(request)) => {
+                    handle_api_request(
+                        &worker,
+                        &mut limiter,
+                        &mut nonces,
+                        &config.limits,
+                        &config.policy,
+                        request,
+                    );
+                }
+                None => break None,
+            },
+            Some(key) = failed_rx.recv() => limiter.forget(&key),
+            _ = sighup.recv() => reload_config(&args, &mut config),
+            _ = tick(&mut watchdog) => systemd::notify_watchdog(),
+            _ = sigterm.recv() => break Some("SIGTERM"),
+            _ = sigint.recv() => break Some("SIGINT"),
         }
     };
 
-    // Bind UDP socket for receiving WOL packets
-    let socket = match UdpSocket::bind(listen_addr).await {
-        Ok(socket) => socket,
+    match stop_signal {
+        Some(name) => info!("Received {}, shutting down", name),
+        None => error!("All listeners have stopped, shutting down"),
+    }
+    systemd::notify_stopping();
+
+    // Stop receiving, packets and API requests that were received but not
+    // processed are dropped
+    listeners.abort_all();
+    rx.close();
+    let mut unprocessed = 0;
+    while rx.try_recv().is_ok() {
+        unprocessed += 1;
+    }
+    if unprocessed > 0 {
+        warn!(
+            "Dropped {} unprocessed packets and API requests",
+            unprocessed
+        );
+    }
+
+    // Let the worker finish the queued requests without blocking the runtime.
+    // On timeout, the worker is left behind and ends with the process.
+    let stopped = match worker.shutdown() {
+        Ok(stopped) => match tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(WolGatewayError::WorkerStopped),
+            Err(_) => Err(WolGatewayError::ShutdownTimeout(SHUTDOWN_TIMEOUT)),
+        },
+        Err(e) => Err(e),
+    };
+
+    if stop_signal.is_none() {
+        if let Err(e) = stopped {
+            error!("{}", e);
+        }
+        return Err(WolGatewayError::ListenersStopped);
+    }
+
+    stopped?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Re-reads the configuration and swaps in the new policy if it is valid.
+///
+/// Packets are processed one at a time, so every packet is evaluated entirely
+/// against either the old or the new policy. An invalid configuration is logged
+/// and the current one is kept.
+///
+/// # Arguments
+///
+/// * `args` - CLI arguments, including the configuration file path
+/// * `config` - The live configuration to update
+fn reload_config(args: &Cli, config: &mut Config) {
+    info!("Received SIGHUP, reloading configuration");
+    systemd::notify_reloading();
+
+    match Config::load(args) {
+        Ok(new) => {
+            config.apply_reload(new);
+            info!("Configuration reloaded");
+        }
         Err(e) => {
-            error!("{}", WolGatewayError::SocketBindError(e));
-            return;
+            error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            );
         }
-    };
-    info!("Listening for WOL packets on {}", listen_addr);
+    }
+
+    systemd::notify_ready(&format!("Connected to {}", config.libvirt_uri));
+}
 
+/// Waits for the next tick of an interval, or forever without one.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Receives UDP datagrams and forwards them to the processing loop.
+///
+/// # Arguments
+///
+/// * `socket` - The bound UDP socket to receive from
+/// * `tx` - Channel to the packet processing loop
+async fn receive_udp(socket: UdpSocket, tx: mpsc::Sender<Received>) {
     // Buffer to hold incoming packet data
     let mut buf = [0_u8; WOL_BUFFER_SIZE];
 
-    // Main packet processing loop
     loop {
         match socket.recv_from(&mut buf).await {
             Ok((len, src_addr)) => {
-                debug!("Received {} bytes from {}", len, src_addr);
-
-                // Process the received packet
-                handle_packet(&conn, &buf[..len]).await;
+                let packet = ReceivedPacket {
+                    data: buf[..len].to_vec(),
+                    source: PacketSource::Udp(src_addr),
+                };
+                if tx.send(Received::Packet(packet)).await.is_err() {
+                    return;
+                }
             }
             Err(e) => {
                 error!(
@@ -96,30 +416,304 @@ pub(crate) async fn serve(args: Cli) {
     }
 }
 
-/// Handles a single incoming packet by parsing it as a WOL packet and starting the target VM.
+/// Receives raw Ethernet WOL frames and forwards them to the processing loop.
 ///
 /// # Arguments
 ///
-/// * `conn` - Reference to the libvirt connection
-/// * `packet` - Raw packet data received from UDP socket
-async fn handle_packet(conn: &Connect, packet: &[u8]) {
-    match WakeOnLanPacket::parse(packet) {
+/// * `listener` - The bound raw Ethernet listener to receive from
+/// * `tx` - Channel to the packet processing loop
+async fn receive_ethernet(listener: EthernetListener, tx: mpsc::Sender<Received>) {
+    // Buffer to hold incoming frame payloads
+    let mut buf = [0_u8; WOL_BUFFER_SIZE];
+
+    loop {
+        match listener.recv_from(&mut buf).await {
+            Ok((len, src_mac)) => {
+                let packet = ReceivedPacket {
+                    data: buf[..len].to_vec(),
+                    source: PacketSource::Ethernet {
+                        interface: listener.interface().to_string(),
+                        mac: src_mac,
+                    },
+                };
+                if tx.send(Received::Packet(packet)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Critical receive error on interface {}: {}",
+                    listener.interface(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Handles a single incoming packet by parsing it as a WOL packet and queueing the
+/// wake request for the libvirt worker.
+///
+/// This never blocks on libvirt, so the listeners keep being drained while VMs boot.
+///
+/// # Arguments
+///
+/// * `worker` - The libvirt worker that resolves and starts the target VM
+/// * `limiter` - Recent requests used for deduplication and rate limiting
+/// * `failed` - Channel the keys of failed wake requests are sent back on, to
+///   be forgotten by the limiter
+/// * `limits` - The current deduplication and rate limiting settings
+/// * `policy` - Reference to the wake policy the packet has to satisfy
+/// * `packet` - The packet received by a listener
+fn handle_packet(
+    worker: &LibvirtWorker,
+    limiter: &mut RequestLimiter,
+    failed: &mpsc::UnboundedSender<RequestKey>,
+    limits: &Limits,
+    policy: &Arc<Policy>,
+    packet: ReceivedPacket,
+) {
+    // Reject packets from denied sources before spending any work on them
+    if let Err(e) = policy.check_source(&packet.source) {
+        record_denied(&packet.source, &e);
+        return;
+    }
+
+    let now = Instant::now();
+
+    let sender = Sender::new(&packet.source);
+    match limiter.check_rate(limits, &sender, now) {
+        RateDecision::Accept => {}
+        RateDecision::StartLimiting => {
+            warn!(
+                "Rate limit exceeded by {}, dropping packets until it slows down",
+                sender
+            );
+            metrics::record_outcome(Outcome::RateLimited);
+            return;
+        }
+        RateDecision::Limited => {
+            debug!("Dropping rate limited packet from {}", sender);
+            metrics::record_outcome(Outcome::RateLimited);
+            return;
+        }
+    }
+
+    // Magic packets cannot carry an authentication token
+    if policy.auth.required {
+        debug!("Dropping unauthenticated WOL packet from {}", packet.source);
+        metrics::record_error(&WolGatewayError::AuthenticationRequired);
+        metrics::record_outcome(Outcome::Unauthenticated);
+        return;
+    }
+
+    match WakeOnLanPacket::parse(&packet.data) {
         Ok(wol) => {
             let mac_address_str = wol.target_mac_string();
+            let key = RequestKey::new(&mac_address_str);
+
+            if !limiter.check_duplicate(limits, &key, now) {
+                debug!(
+                    "Ignoring repeated WOL packet for MAC {} from {}",
+                    mac_address_str, packet.source
+                );
+                metrics::record_outcome(Outcome::Duplicate);
+                return;
+            }
+
             info!("Received valid WOL packet for MAC: {}", mac_address_str);
 
-            // Attempt to find and start the VM with the target MAC address
-            match find_and_start_vm_by_mac(conn, &mac_address_str).await {
-                Ok(()) => {
-                    info!("Successfully started VM with MAC: {}", mac_address_str);
+            let (reply, result) = oneshot::channel();
+            let job = Job::Wake(WakeJob {
+                request: WakeRequest {
+                    target: WakeTarget::Mac(mac_address_str),
+                    password: wol.password().copied(),
+                    source: packet.source,
+                },
+                policy: Arc::clone(policy),
+                reply: Some(reply),
+            });
+
+            // The worker finds and starts the VM with the target MAC address
+            if let Err(e) = worker.submit(job) {
+                warn!("{}", e);
+                metrics::record_error(&e);
+                metrics::record_outcome(Outcome::Dropped);
+                limiter.forget(&key);
+                return;
+            }
+
+            // Only a request that succeeded suppresses retries
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                if !matches!(result.await, Ok(Ok(_))) {
+                    let _ = failed.send(key);
                 }
+            });
+        }
+        Err(e) => {
+            warn!("Received invalid WOL packet: {}", e);
+            metrics::record_error(&e);
+            metrics::record_outcome(Outcome::Invalid);
+        }
+    }
+}
+
+/// Handles a single HTTP API request by queueing it for the libvirt worker.
+///
+/// API requests pass the same source filters and rate limits as packets, but
+/// are never deduplicated, as every request is answered. The token of a wake or
+/// state request is verified here, before the worker resolves the VM. Rejected
+/// requests are answered with the reason right away.
+///
+/// # Arguments
+///
+/// * `worker` - The libvirt worker that carries out the request
+/// * `limiter` - Recent requests used for rate limiting
+/// * `nonces` - Nonces of accepted tokens used to reject replayed requests
+/// * `limits` - The current rate limiting settings
+/// * `policy` - Reference to the wake policy the request has to satisfy
+/// * `request` - The request received by the HTTP API
+fn handle_api_request(
+    worker: &LibvirtWorker,
+    limiter: &mut RequestLimiter,
+    nonces: &mut NonceCache,
+    limits: &Limits,
+    policy: &Arc<Policy>,
+    request: ApiRequest,
+) {
+    if let Err(e) = policy.check_source(request.source()) {
+        record_denied(request.source(), &e);
+        request.reject(e);
+        return;
+    }
+
+    let sender = Sender::new(request.source());
+    if limiter.check_rate(limits, &sender, Instant::now()) != RateDecision::Accept {
+        debug!("Rejecting rate limited API request from {}", sender);
+        metrics::record_outcome(Outcome::RateLimited);
+        request.reject(WolGatewayError::RateLimited(sender.to_string()));
+        return;
+    }
+
+    let job = match request {
+        ApiRequest::Wake {
+            request,
+            token,
+            reply,
+        } => {
+            let authenticated = authenticate(
+                nonces,
+                policy,
+                token.as_ref(),
+                TokenAction::Wake,
+                &request.target,
+            );
+            match authenticated {
+                Ok(Some(client)) => debug!("Authenticated wake request from client {}", client),
+                Ok(None) => {}
                 Err(e) => {
-                    warn!("Failed to start VM for MAC {}: {}", mac_address_str, e);
+                    warn!("Rejected wake request from {}: {}", request.source, e);
+                    metrics::record_error(&e);
+                    metrics::record_outcome(Outcome::Unauthenticated);
+                    let _ = reply.send(Err(e));
+                    return;
                 }
             }
+
+            info!(
+                "Received wake request for {} from {}",
+                request.target, request.source
+            );
+            Job::Wake(WakeJob {
+                request,
+                policy: Arc::clone(policy),
+                reply: Some(reply),
+            })
         }
-        Err(e) => {
-            warn!("Received invalid WOL packet: {}", e);
+        ApiRequest::Status {
+            domain,
+            source,
+            token,
+            reply,
+        } => {
+            let target = WakeTarget::Domain(domain.clone());
+            let authenticated =
+                authenticate(nonces, policy, token.as_ref(), TokenAction::State, &target);
+            match authenticated {
+                Ok(Some(client)) => debug!("Authenticated state request from client {}", client),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Rejected state request from {}: {}", source, e);
+                    metrics::record_error(&e);
+                    let _ = reply.send(Err(e));
+                    return;
+                }
+            }
+
+            Job::Status(StatusJob {
+                domain,
+                policy: Arc::clone(policy),
+                reply,
+            })
         }
+    };
+
+    if let Err(e) = worker.submit(job) {
+        warn!("{}", e);
+        metrics::record_error(&e);
+        metrics::record_outcome(Outcome::Dropped);
+    }
+}
+
+/// Verifies the token of an API request.
+///
+/// # Arguments
+///
+/// * `nonces` - Nonces of accepted tokens used to reject replayed requests
+/// * `policy` - Reference to the policy holding the authentication settings
+/// * `token` - The token carried by the request, if any
+/// * `action` - What the request asks for
+/// * `target` - The VM the request is aimed at
+///
+/// # Returns
+///
+/// The name of the authenticated client, or `None` if the request carries no
+/// token and authentication is not required.
+///
+/// # Errors
+///
+/// Returns `AuthenticationRequired` if a token is required but missing, or
+/// `AuthenticationFailed` if the token was rejected.
+fn authenticate<'a>(
+    nonces: &mut NonceCache,
+    policy: &Policy,
+    token: Option<&'a WakeToken>,
+    action: TokenAction,
+    target: &WakeTarget,
+) -> Result<Option<&'a str>, WolGatewayError> {
+    match token {
+        Some(token) => nonces
+            .verify(&policy.auth, token, action, target, auth::unix_time())
+            .map(|()| Some(token.client.as_str())),
+        None if policy.auth.required => Err(WolGatewayError::AuthenticationRequired),
+        None => Ok(None),
     }
 }
+
+/// Logs and counts a wake request rejected because of its source address.
+///
+/// # Arguments
+///
+/// * `source` - Where the rejected request was received from
+/// * `error` - The reason the request was rejected
+pub(crate) fn record_denied(source: &PacketSource, error: &WolGatewayError) {
+    let total = DENIED_PACKETS.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!(
+        "Denied WOL packet from {}: {} (total denied: {})",
+        source, error, total
+    );
+    metrics::record_error(error);
+    metrics::record_outcome(Outcome::Denied);
+}
@@ -0,0 +1,91 @@
+//! Integration with systemd socket activation and the notification protocol.
+//!
+//! With a matching `.socket` unit, systemd binds the privileged WOL port and
+//! passes the sockets in `LISTEN_FDS`, so the gateway needs no capabilities.
+//! Readiness and status are reported with `sd_notify`, and the main loop pings
+//! the service watchdog when `WatchdogSec=` is set. Outside of systemd, all of
+//! this is a no-op.
+
+use std::time::Duration;
+
+use listenfd::ListenFd;
+use log::{debug, warn};
+use sd_notify::NotifyState;
+
+use crate::error::WolGatewayError;
+
+/// Takes over the UDP sockets passed by systemd, if any.
+///
+/// # Returns
+///
+/// The passed sockets, or an empty list if the gateway was not socket activated
+///
+/// # Errors
+///
+/// Returns `SocketActivationError` if a passed file descriptor is not a UDP socket.
+pub(crate) fn listen_sockets() -> Result<Vec<std::net::UdpSocket>, WolGatewayError> {
+    let mut fds = ListenFd::from_env();
+
+    (0..fds.len())
+        .filter_map(|index| fds.take_udp_socket(index).transpose())
+        .collect::<Result<_, _>>()
+        .map_err(WolGatewayError::SocketActivationError)
+}
+
+/// Returns the interval to ping the watchdog at, if systemd enabled it.
+///
+/// Pings are sent at half the watchdog timeout, as `sd_watchdog_enabled(3)`
+/// recommends.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+        Some(Duration::from_micros(usec) / 2)
+    } else {
+        None
+    }
+}
+
+/// Tells systemd that the gateway has started up.
+///
+/// # Arguments
+///
+/// * `status` - Human-readable status shown by `systemctl status`
+pub(crate) fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Updates the status shown by `systemctl status`.
+pub(crate) fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Tells systemd that the configuration is being reloaded.
+///
+/// Must be followed by `notify_ready` once the reload is complete.
+pub(crate) fn notify_reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(e) => warn!("Failed to read the monotonic clock: {}", e),
+    }
+}
+
+/// Tells systemd that the gateway is shutting down.
+pub(crate) fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Pings the systemd watchdog.
+pub(crate) fn notify_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Sends a notification to systemd, logging failures.
+///
+/// Without `NOTIFY_SOCKET` in the environment, nothing is sent.
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("Failed to notify systemd: {}", e);
+    } else {
+        debug!("Notified systemd: {:?}", state);
+    }
+}
@@ -1,6 +1,29 @@
 #[cfg(test)]
 use crate::error::WolGatewayError;
 
+/// Loads a configuration from command line flags and configuration file contents.
+///
+/// # Arguments
+///
+/// * `flags` - Command line flags, without the program name
+/// * `toml` - The contents of the configuration file
+#[cfg(test)]
+fn try_load_config(flags: &[&str], toml: &str) -> Result<crate::config::Config, WolGatewayError> {
+    use clap::Parser;
+
+    let args = crate::Cli::try_parse_from(
+        std::iter::once("wol-libvirt-gateway").chain(flags.iter().copied()),
+    )
+    .unwrap();
+    crate::config::Config::from_toml(&args, toml)
+}
+
+/// Loads a configuration like `try_load_config`, panicking if it is invalid.
+#[cfg(test)]
+fn load_config(flags: &[&str], toml: &str) -> crate::config::Config {
+    try_load_config(flags, toml).unwrap()
+}
+
 #[test]
 fn test_valid_wol_packet_without_password() {
     let mut packet = vec![0xFF; 6]; // Sync stream
@@ -13,6 +36,7 @@ fn test_valid_wol_packet_without_password() {
 
     let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
     assert_eq!(wol.target_mac_string(), "aa:bb:cc:dd:ee:ff");
+    assert_eq!(wol.password(), None);
 }
 
 #[test]
@@ -31,6 +55,10 @@ fn test_valid_wol_packet_with_6_byte_password() {
 
     let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
     assert_eq!(wol.target_mac_string(), "12:34:56:78:9a:bc");
+    assert_eq!(
+        wol.password(),
+        Some(&crate::wakeonlan::SecureOnPassword::Full(password))
+    );
 }
 
 #[test]
@@ -49,6 +77,101 @@ fn test_valid_wol_packet_with_4_byte_password() {
 
     let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
     assert_eq!(wol.target_mac_string(), "00:11:22:33:44:55");
+    assert_eq!(
+        wol.password(),
+        Some(&crate::wakeonlan::SecureOnPassword::Short(password_4byte))
+    );
+
+    // The length is part of the password, zero padding does not make them equal
+    assert_ne!(
+        wol.password(),
+        Some(&crate::wakeonlan::SecureOnPassword::Full([
+            0xAA, 0xBB, 0xCC, 0xDD, 0x00, 0x00
+        ]))
+    );
+}
+
+#[test]
+fn test_padded_packet() {
+    let mut packet = vec![0xFF; 6]; // Sync stream
+    let mac = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    // Add MAC address 16 times
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+
+    // A 5-byte trailer is neither a 4 nor a 6-byte password, but padding
+    packet.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05]);
+    let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
+    assert_eq!(wol.target_mac_string(), "00:11:22:33:44:55");
+    assert_eq!(wol.password(), None);
+
+    // Longer padding is ignored instead of taking a 6-byte prefix as password
+    packet.extend_from_slice(&[0x06; 64]);
+    let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
+    assert_eq!(wol.target_mac_string(), "00:11:22:33:44:55");
+    assert_eq!(wol.password(), None);
+}
+
+#[test]
+fn test_secureon_password_string_parsing() {
+    use crate::wakeonlan::SecureOnPassword;
+
+    assert_eq!(
+        crate::wakeonlan::parse_secureon_password_string("11:22:33:44:55:66").unwrap(),
+        SecureOnPassword::Full([0x11, 0x22, 0x33, 0x44, 0x55, 0x66])
+    );
+    assert_eq!(
+        crate::wakeonlan::parse_secureon_password_string("192.168.1.1").unwrap(),
+        SecureOnPassword::Short([192, 168, 1, 1])
+    );
+    assert!(crate::wakeonlan::parse_secureon_password_string("192.168.1.256").is_err());
+    assert!(crate::wakeonlan::parse_secureon_password_string("11:22:33").is_err());
+}
+
+#[test]
+fn test_policy_password_enforcement() {
+    use crate::wakeonlan::SecureOnPassword;
+
+    let policy = load_config(
+        &[
+            "--password",
+            "11:22:33:44:55:66",
+            "--vm-password",
+            "special=10.0.0.1",
+        ],
+        "",
+    )
+    .policy;
+    let uuid = uuid::Uuid::nil();
+
+    let mut request = crate::policy::WakeRequest {
+        target: crate::policy::WakeTarget::Mac("aa:bb:cc:dd:ee:ff".to_string()),
+        password: Some(SecureOnPassword::Full([0x11, 0x22, 0x33, 0x44, 0x55, 0x66])),
+        source: crate::server::PacketSource::Udp("127.0.0.1:40000".parse().unwrap()),
+    };
+    assert!(policy.authorize(&request, "regular", &uuid, None).is_ok());
+    assert!(matches!(
+        policy.authorize(&request, "special", &uuid, None),
+        Err(WolGatewayError::PasswordMismatch(_))
+    ));
+
+    request.password = Some(SecureOnPassword::Short([10, 0, 0, 1]));
+    assert!(policy.authorize(&request, "special", &uuid, None).is_ok());
+
+    // A 6-byte password does not match a 4-byte one padded with zeros
+    request.password = Some(SecureOnPassword::Full([10, 0, 0, 1, 0, 0]));
+    assert!(matches!(
+        policy.authorize(&request, "special", &uuid, None),
+        Err(WolGatewayError::PasswordMismatch(_))
+    ));
+
+    request.password = None;
+    assert!(matches!(
+        policy.authorize(&request, "regular", &uuid, None),
+        Err(WolGatewayError::PasswordMismatch(_))
+    ));
 }
 
 #[test]
@@ -205,3 +328,2218 @@ fn test_domain_xml_self_closing_mac_tag() {
     assert_eq!(macs.len(), 1);
     assert_eq!(macs[0], "aa:bb:cc:dd:ee:ff");
 }
+
+#[test]
+fn test_config_defaults_and_cli_precedence() {
+    let config = load_config(&[], "");
+    assert_eq!(config.addresses, vec!["127.0.0.1:9".parse().unwrap()]);
+    assert_eq!(config.libvirt_uri, "qemu:///system");
+    assert!(config.udp);
+    assert!(config.interfaces.is_empty());
+
+    let toml = r#"
+        [listen]
+        address = "0.0.0.0:9"
+        interfaces = ["br0"]
+
+        [libvirt]
+        uri = "qemu:///session"
+        keepalive_interval = 10
+    "#;
+    let config = load_config(&[], toml);
+    assert_eq!(config.addresses, vec!["0.0.0.0:9".parse().unwrap()]);
+    assert_eq!(config.libvirt_uri, "qemu:///session");
+    assert_eq!(config.interfaces, vec!["br0".to_string()]);
+    assert_eq!(config.keepalive_interval.as_secs(), 10);
+
+    let config = load_config(
+        &[
+            "--address",
+            "127.0.0.1:9009",
+            "--libvirt-uri",
+            "test:///default",
+        ],
+        toml,
+    );
+    assert_eq!(config.addresses, vec!["127.0.0.1:9009".parse().unwrap()]);
+    assert_eq!(config.libvirt_uri, "test:///default");
+}
+
+#[test]
+fn test_config_validation_errors() {
+    let cases = [
+        "[listen]\nudp = false",
+        "[listen]\naddress = \"not an address\"",
+        "[security]\nallowed_sources = [\"10.0.0.0/33\"]",
+        "[[vm]]\ndomain = \"a\"\n[[vm]]\ndomain = \"a\"",
+        "[mappings]\n\"not-a-mac\" = \"a\"",
+        "[libvirt]\nkeepalive_interval = 0",
+    ];
+
+    for toml in cases {
+        assert!(try_load_config(&[], toml).is_err(), "{}", toml);
+    }
+
+    assert!(matches!(
+        try_load_config(&[], "[unknown]\nkey = 1"),
+        Err(WolGatewayError::ConfigParseError(_))
+    ));
+}
+
+#[test]
+fn test_config_policy() {
+    let toml = r#"
+        [security]
+        allowed_sources = ["192.168.1.0/24", "::1"]
+
+        [[vm]]
+        domain = "disabled"
+        enabled = false
+
+        [mappings]
+        "52:54:00:AB:CD:EF" = "mapped"
+    "#;
+    let policy = load_config(&[], toml).policy;
+    let uuid = uuid::Uuid::nil();
+
+    assert_eq!(policy.mapped_domain("52:54:00:ab:cd:ef"), Some("mapped"));
+    assert_eq!(policy.mapped_domain("52:54:00:00:00:00"), None);
+
+    let mut request = crate::policy::WakeRequest {
+        target: crate::policy::WakeTarget::Mac("52:54:00:ab:cd:ef".to_string()),
+        password: None,
+        source: crate::server::PacketSource::Udp("192.168.1.20:40000".parse().unwrap()),
+    };
+    assert!(policy.authorize(&request, "regular", &uuid, None).is_ok());
+    assert!(matches!(
+        policy.authorize(&request, "disabled", &uuid, None),
+        Err(WolGatewayError::VmDisabled(_))
+    ));
+
+    request.source = crate::server::PacketSource::Udp("[::1]:40000".parse().unwrap());
+    assert!(policy.authorize(&request, "regular", &uuid, None).is_ok());
+
+    request.source = crate::server::PacketSource::Udp("10.0.0.1:40000".parse().unwrap());
+    assert!(matches!(
+        policy.authorize(&request, "regular", &uuid, None),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+}
+
+#[test]
+fn test_source_filter() {
+    let toml = r#"
+        [security]
+        allowed_sources = ["192.168.1.0/24"]
+
+        [[vm]]
+        domain = "restricted"
+        allowed_sources = ["10.1.0.0/16"]
+        denied_sources = ["10.1.2.0/24"]
+    "#;
+    let policy = load_config(&["--allow", "10.0.0.0/8", "--deny", "10.0.0.66"], toml).policy;
+    let udp = |addr: &str| crate::server::PacketSource::Udp(addr.parse().unwrap());
+
+    // The command line list replaces the one from the file, and deny wins over allow
+    assert!(policy.check_source(&udp("10.0.0.1:9")).is_ok());
+    assert!(policy.check_source(&udp("192.168.1.20:9")).is_err());
+    assert!(matches!(
+        policy.check_source(&udp("10.0.0.66:9")),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+
+    // IPv4-mapped IPv6 addresses from dual-stack sockets are matched as IPv4
+    assert!(policy.check_source(&udp("[::ffff:10.0.0.1]:9")).is_ok());
+    assert!(policy.check_source(&udp("[::ffff:10.0.0.66]:9")).is_err());
+    assert!(policy.check_source(&udp("[2001:db8::1]:9")).is_err());
+
+    // Raw Ethernet frames carry no IP address and are not filtered
+    let ethernet = crate::server::PacketSource::Ethernet {
+        interface: "br0".to_string(),
+        mac: None,
+    };
+    assert!(policy.check_source(&ethernet).is_ok());
+
+    // Per-VM filters only narrow the global one for that VM
+    let uuid = uuid::Uuid::nil();
+    let mut request = crate::policy::WakeRequest {
+        target: crate::policy::WakeTarget::Mac("52:54:00:ab:cd:ef".to_string()),
+        password: None,
+        source: udp("10.1.1.1:9"),
+    };
+    assert!(policy
+        .authorize(&request, "restricted", &uuid, None)
+        .is_ok());
+    request.source = udp("10.1.2.1:9");
+    assert!(policy
+        .authorize(&request, "restricted", &uuid, None)
+        .is_err());
+    assert!(policy.authorize(&request, "other", &uuid, None).is_ok());
+    request.source = udp("10.2.0.1:9");
+    assert!(matches!(
+        policy.authorize(&request, "restricted", &uuid, None),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+
+    // Raw Ethernet frames never wake a VM with an allow list, from either source
+    request.source = ethernet;
+    assert!(matches!(
+        policy.authorize(&request, "restricted", &uuid, None),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+    assert!(policy.authorize(&request, "other", &uuid, None).is_ok());
+    let mut metadata = crate::policy::VmPolicy::default();
+    metadata.sources.deny = vec!["10.1.2.0/24".parse().unwrap()];
+    assert!(policy
+        .authorize(&request, "other", &uuid, Some(&metadata))
+        .is_ok());
+    metadata.sources.allow = vec!["10.1.0.0/16".parse().unwrap()];
+    assert!(matches!(
+        policy.authorize(&request, "other", &uuid, Some(&metadata)),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+}
+
+#[test]
+fn test_rate_limit_per_source() {
+    use crate::rate_limit::{Limits, RateDecision, RequestLimiter, Sender};
+    use crate::server::PacketSource;
+    use std::time::{Duration, Instant};
+
+    let limits = Limits {
+        dedup_window: Duration::ZERO,
+        rate: 2.0,
+        burst: 3,
+    };
+    let mut limiter = RequestLimiter::new();
+    let now = Instant::now();
+    let udp = |addr: &str| Sender::new(&PacketSource::Udp(addr.parse().unwrap()));
+    let flooder = udp("192.168.1.20:40000");
+
+    // A burst is accepted, then packets are dropped and only the first drop is reported
+    for _ in 0..3 {
+        assert_eq!(
+            limiter.check_rate(&limits, &flooder, now),
+            RateDecision::Accept
+        );
+    }
+    assert_eq!(
+        limiter.check_rate(&limits, &flooder, now),
+        RateDecision::StartLimiting
+    );
+    assert_eq!(
+        limiter.check_rate(&limits, &flooder, now),
+        RateDecision::Limited
+    );
+
+    // Other ports and IPv4-mapped IPv6 addresses share the bucket, other
+    // sources have their own
+    assert_eq!(
+        limiter.check_rate(&limits, &udp("192.168.1.20:40001"), now),
+        RateDecision::Limited
+    );
+    assert_eq!(
+        limiter.check_rate(&limits, &udp("[::ffff:192.168.1.20]:40000"), now),
+        RateDecision::Limited
+    );
+    assert_eq!(
+        limiter.check_rate(&limits, &udp("192.168.1.21:40000"), now),
+        RateDecision::Accept
+    );
+
+    // Raw Ethernet frames are limited per interface and source MAC address
+    let ethernet = |interface: &str, mac: [u8; 6]| {
+        Sender::new(&PacketSource::Ethernet {
+            interface: interface.to_string(),
+            mac: Some(mac),
+        })
+    };
+    let frames = ethernet("br0", [0x52, 0x54, 0x00, 0x00, 0x00, 0x01]);
+    assert_eq!(frames.to_string(), "52:54:00:00:00:01 on br0");
+    for _ in 0..3 {
+        assert_eq!(
+            limiter.check_rate(&limits, &frames, now),
+            RateDecision::Accept
+        );
+    }
+    assert_eq!(
+        limiter.check_rate(&limits, &frames, now),
+        RateDecision::StartLimiting
+    );
+    assert_eq!(
+        limiter.check_rate(
+            &limits,
+            &ethernet("br1", [0x52, 0x54, 0x00, 0x00, 0x00, 0x01]),
+            now
+        ),
+        RateDecision::Accept
+    );
+    assert_eq!(
+        limiter.check_rate(
+            &limits,
+            &ethernet("br0", [0x52, 0x54, 0x00, 0x00, 0x00, 0x02]),
+            now
+        ),
+        RateDecision::Accept
+    );
+
+    // Tokens refill at the configured rate
+    let later = now + Duration::from_millis(500);
+    assert_eq!(
+        limiter.check_rate(&limits, &flooder, later),
+        RateDecision::Accept
+    );
+    assert_eq!(
+        limiter.check_rate(&limits, &flooder, later),
+        RateDecision::StartLimiting
+    );
+
+    // A rate of zero disables the limit
+    let unlimited = Limits {
+        rate: 0.0,
+        ..limits
+    };
+    assert_eq!(
+        limiter.check_rate(&unlimited, &flooder, later),
+        RateDecision::Accept
+    );
+}
+
+#[test]
+fn test_dedup_window_per_mac() {
+    use crate::rate_limit::{Limits, RequestKey, RequestLimiter};
+    use std::time::{Duration, Instant};
+
+    let limits = Limits {
+        dedup_window: Duration::from_secs(5),
+        rate: 0.0,
+        burst: 1,
+    };
+    let mut limiter = RequestLimiter::new();
+    let now = Instant::now();
+    let mac = "52:54:00:ab:cd:ef";
+    let key = RequestKey::new(mac);
+
+    assert!(limiter.check_duplicate(&limits, &key, now));
+    assert!(!limiter.check_duplicate(&limits, &key, now + Duration::from_secs(4)));
+
+    // The case of the MAC address does not matter
+    let same = RequestKey::new(&mac.to_uppercase());
+    assert!(!limiter.check_duplicate(&limits, &same, now));
+
+    // Other MAC addresses are not suppressed by the burst
+    let other_mac = RequestKey::new("52:54:00:00:00:01");
+    assert!(limiter.check_duplicate(&limits, &other_mac, now));
+
+    // A failed request is forgotten, so a retry is acted upon
+    limiter.forget(&key);
+    assert!(limiter.check_duplicate(&limits, &key, now + Duration::from_secs(1)));
+
+    // Duplicates do not extend the window
+    assert!(limiter.check_duplicate(&limits, &key, now + Duration::from_secs(6)));
+
+    let disabled = Limits {
+        dedup_window: Duration::ZERO,
+        ..limits
+    };
+    assert!(limiter.check_duplicate(&disabled, &key, now));
+}
+
+#[test]
+fn test_limiter_maps_are_bounded() {
+    use crate::rate_limit::{Limits, RateDecision, RequestKey, RequestLimiter, Sender};
+    use crate::server::PacketSource;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    let limits = Limits {
+        dedup_window: Duration::from_secs(5),
+        rate: 2.0,
+        burst: 3,
+    };
+    let mut limiter = RequestLimiter::new();
+    let now = Instant::now();
+    let key = |n: u32| {
+        let [_, a, b, c] = n.to_be_bytes();
+        RequestKey::new(&format!("52:54:00:{a:02x}:{b:02x}:{c:02x}"))
+    };
+
+    // A flood from spoofed sources evicts the oldest entries instead of growing the maps
+    for n in 0..10_000 {
+        let ip = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n));
+        let sender = Sender::new(&PacketSource::Udp(SocketAddr::new(ip, 9)));
+        assert_eq!(
+            limiter.check_rate(&limits, &sender, now),
+            RateDecision::Accept
+        );
+        assert!(limiter.check_duplicate(&limits, &key(n), now));
+    }
+    let (recent, buckets) = limiter.tracked();
+    assert!(recent <= 4096, "{recent} requests tracked");
+    assert!(buckets <= 4096, "{buckets} sources tracked");
+
+    // The newest requests are still deduplicated, the oldest were evicted
+    assert!(!limiter.check_duplicate(&limits, &key(9_999), now));
+    assert!(limiter.check_duplicate(&limits, &key(0), now));
+}
+
+#[test]
+fn test_config_limits() {
+    let limits = load_config(&[], "").limits;
+    assert_eq!(limits.dedup_window.as_secs(), 5);
+    assert_eq!(limits.burst, 10);
+
+    let toml = "[limits]\ndedup_window = 0\nrate_limit = 0.5\nrate_burst = 2";
+    let limits = load_config(&[], toml).limits;
+    assert!(limits.dedup_window.is_zero());
+    assert_eq!(limits.rate, 0.5);
+    assert_eq!(limits.burst, 2);
+
+    let limits = load_config(&["--rate-limit", "20"], toml).limits;
+    assert_eq!(limits.rate, 20.0);
+
+    for toml in ["[limits]\nrate_limit = -1.0", "[limits]\nrate_burst = 0"] {
+        assert!(matches!(
+            try_load_config(&[], toml),
+            Err(WolGatewayError::ConfigError(_))
+        ));
+    }
+}
+
+#[test]
+fn test_state_reason_decoding() {
+    use crate::libvirt::{CrashedReason, DomainState, PausedReason, ShutoffReason, StateReason};
+
+    let reason = StateReason::new(&DomainState::Shutoff, 1);
+    assert_eq!(reason, StateReason::Shutoff(ShutoffReason::Shutdown));
+    assert!(!reason.is_crash());
+    assert_eq!(reason.to_string(), "guest shut down normally");
+
+    let reason = StateReason::new(&DomainState::Shutoff, 3);
+    assert_eq!(reason, StateReason::Shutoff(ShutoffReason::Crashed));
+    assert!(reason.is_crash());
+
+    // Crashed domains kept for inspection are paused or crashed
+    assert!(StateReason::new(&DomainState::Paused, 10).is_crash());
+    assert!(!StateReason::new(&DomainState::Paused, 1).is_crash());
+    assert_eq!(
+        StateReason::new(&DomainState::Crashed, 1),
+        StateReason::Crashed(CrashedReason::Panicked)
+    );
+    assert!(StateReason::new(&DomainState::Crashed, 0).is_crash());
+
+    // The same code means different things in different states
+    assert_eq!(
+        StateReason::new(&DomainState::Paused, 5),
+        StateReason::Paused(PausedReason::IoError)
+    );
+    assert_eq!(
+        StateReason::new(&DomainState::Running, 5),
+        StateReason::Other(5)
+    );
+    assert_eq!(
+        StateReason::new(&DomainState::Shutoff, 42),
+        StateReason::Shutoff(ShutoffReason::Unknown)
+    );
+}
+
+#[test]
+fn test_config_start_options() {
+    use crate::policy::{ManagedSave, StartOptions, WakeAction};
+
+    let uuid = uuid::Uuid::nil();
+    let policy = load_config(&[], "").policy;
+    assert_eq!(
+        policy.start_options("vm", &uuid),
+        StartOptions {
+            action: WakeAction::Start,
+            managed_save: ManagedSave::Restore,
+            restart_crashed: false,
+        }
+    );
+
+    let toml = r#"
+        [start]
+        managed_save = "discard"
+
+        [[vm]]
+        domain = "stateful"
+        managed_save = "restore"
+        restart_crashed = true
+
+        [[vm]]
+        domain = "paused"
+        action = "resume"
+    "#;
+    let policy = load_config(&[], toml).policy;
+    assert_eq!(
+        policy.start_options("vm", &uuid).managed_save,
+        ManagedSave::Discard
+    );
+    assert_eq!(
+        policy.start_options("stateful", &uuid),
+        StartOptions {
+            action: WakeAction::Start,
+            managed_save: ManagedSave::Restore,
+            restart_crashed: true,
+        }
+    );
+
+    assert_eq!(
+        policy.start_options("paused", &uuid).action,
+        WakeAction::Resume
+    );
+
+    let policy = load_config(&["--restart-crashed"], "").policy;
+    assert!(policy.start_options("vm", &uuid).restart_crashed);
+
+    assert!(matches!(
+        try_load_config(&["--restart-crashed"], "[start]\nmanaged_save = \"keep\""),
+        Err(WolGatewayError::ConfigError(_))
+    ));
+}
+
+#[test]
+fn test_config_reload_swaps_policy() {
+    let mut config = load_config(&[], "");
+    let new = load_config(
+        &[],
+        "[listen]\naddress = \"0.0.0.0:9\"\n[[vm]]\ndomain = \"vm\"\nenabled = false",
+    );
+
+    config.apply_reload(new);
+
+    // The policy is swapped, while the listener address needs a restart
+    let request = crate::policy::WakeRequest {
+        target: crate::policy::WakeTarget::Mac("aa:bb:cc:dd:ee:ff".to_string()),
+        password: None,
+        source: crate::server::PacketSource::Udp("127.0.0.1:40000".parse().unwrap()),
+    };
+    assert!(matches!(
+        config
+            .policy
+            .authorize(&request, "vm", &uuid::Uuid::nil(), None),
+        Err(WolGatewayError::VmDisabled(_))
+    ));
+    assert_eq!(config.addresses, vec!["127.0.0.1:9".parse().unwrap()]);
+}
+
+#[test]
+fn test_metrics_encoding() {
+    use crate::metrics::{encode_metrics, record_error, record_outcome, Outcome};
+
+    record_outcome(Outcome::Invalid);
+    record_error(&WolGatewayError::VmNotFound(
+        "aa:bb:cc:dd:ee:ff".to_string(),
+    ));
+
+    let encoded = encode_metrics().unwrap();
+    assert!(encoded.contains("wol_packets_total{outcome=\"invalid\"}"));
+    assert!(encoded.contains("wol_errors_total{kind=\"VmNotFound\"}"));
+    assert!(encoded.contains("wol_lookup_duration_seconds_bucket"));
+    assert!(encoded.contains("wol_libvirt_connected"));
+    assert!(encoded.ends_with("# EOF\n"));
+}
+
+#[test]
+fn test_metrics_http_listener() {
+    use std::io::{Read, Write};
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let listener = runtime
+        .block_on(crate::metrics::bind("127.0.0.1:0".parse().unwrap()))
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    runtime.spawn(crate::metrics::serve(listener));
+
+    let get = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("application/openmetrics-text"));
+    assert!(response.contains("wol_packets_received_total"));
+
+    assert!(get("/").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn test_metadata_policy_parsing() {
+    use crate::domain_xml::get_policy;
+
+    // libvirt returns the element with the namespace declared on it
+    let xml = r#"<wol:policy xmlns:wol="https://github.com/brunoproduit/wol-libvirt-gateway" enabled="yes" password="11:22:33:44:55:66" allow="10.0.0.0/8, fd00::/8" deny="10.0.0.1/32"/>"#;
+    let policy = get_policy("vm", xml).unwrap();
+    assert!(policy.enabled);
+    assert_eq!(policy.action, None);
+    assert_eq!(
+        policy.password,
+        Some(crate::wakeonlan::SecureOnPassword::Full([
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66
+        ]))
+    );
+    assert_eq!(policy.sources.allow.len(), 2);
+    assert!(!policy.sources.permits("10.0.0.1".parse().unwrap()));
+    assert!(policy.sources.permits("10.0.0.2".parse().unwrap()));
+
+    // Only an explicit "yes" or "true" enables the VM
+    let policy = get_policy(
+        "vm",
+        r#"<policy xmlns="https://github.com/brunoproduit/wol-libvirt-gateway"/>"#,
+    )
+    .unwrap();
+    assert!(!policy.enabled);
+    assert_eq!(policy.password, None);
+    assert!(policy.sources.allow.is_empty());
+    for (enabled, expected) in [
+        ("yes", true),
+        ("true", true),
+        ("no", false),
+        ("false", false),
+    ] {
+        let xml = format!(r#"<policy enabled="{enabled}"/>"#);
+        assert_eq!(get_policy("vm", &xml).unwrap().enabled, expected, "{xml}");
+    }
+
+    for xml in [
+        r#"<policy enabled="maybe"/>"#,
+        r#"<policy password="11:22"/>"#,
+        r#"<policy allow="10.0.0.0/33"/>"#,
+        "<policy",
+    ] {
+        assert!(
+            matches!(
+                get_policy("vm", xml),
+                Err(WolGatewayError::MetadataPolicyError(..))
+            ),
+            "{xml} should be rejected"
+        );
+    }
+}
+
+#[test]
+fn test_metadata_policy_merge() {
+    use crate::policy::{Policy, VmPolicy, WakeAction};
+    use crate::wakeonlan::SecureOnPassword;
+
+    let uuid = uuid::Uuid::nil();
+    let mut policy = Policy {
+        password: Some(SecureOnPassword::Full([0x01; 6])),
+        ..Policy::default()
+    };
+    let request = |password: Option<SecureOnPassword>, source: &str| crate::policy::WakeRequest {
+        target: crate::policy::WakeTarget::Mac("aa:bb:cc:dd:ee:ff".to_string()),
+        password,
+        source: crate::server::PacketSource::Udp(source.parse().unwrap()),
+    };
+
+    let metadata = VmPolicy {
+        password: Some(SecureOnPassword::Full([0x02; 6])),
+        sources: crate::policy::SourceFilter {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: Vec::new(),
+        },
+        ..VmPolicy::default()
+    };
+
+    // The metadata password replaces the global one, its sources restrict the VM
+    assert!(policy
+        .authorize(
+            &request(Some(SecureOnPassword::Full([0x02; 6])), "10.0.0.1:9"),
+            "vm",
+            &uuid,
+            Some(&metadata)
+        )
+        .is_ok());
+    assert!(matches!(
+        policy.authorize(
+            &request(Some(SecureOnPassword::Full([0x01; 6])), "10.0.0.1:9"),
+            "vm",
+            &uuid,
+            Some(&metadata)
+        ),
+        Err(WolGatewayError::PasswordMismatch(_))
+    ));
+    assert!(matches!(
+        policy.authorize(
+            &request(Some(SecureOnPassword::Full([0x02; 6])), "192.168.0.1:9"),
+            "vm",
+            &uuid,
+            Some(&metadata)
+        ),
+        Err(WolGatewayError::SourceNotAllowed(_))
+    ));
+
+    // The gateway configuration takes precedence, and can disable the VM
+    policy.vms.insert(
+        "vm".to_string(),
+        VmPolicy {
+            password: Some(SecureOnPassword::Full([0x03; 6])),
+            action: Some(WakeAction::Resume),
+            ..VmPolicy::default()
+        },
+    );
+    assert!(policy
+        .authorize(
+            &request(Some(SecureOnPassword::Full([0x03; 6])), "10.0.0.1:9"),
+            "vm",
+            &uuid,
+            Some(&metadata)
+        )
+        .is_ok());
+    assert_eq!(policy.start_options("vm", &uuid).action, WakeAction::Resume);
+
+    let disabled = VmPolicy {
+        enabled: false,
+        ..VmPolicy::default()
+    };
+    assert!(matches!(
+        policy.authorize(
+            &request(Some(SecureOnPassword::Full([0x03; 6])), "10.0.0.1:9"),
+            "vm",
+            &uuid,
+            Some(&disabled)
+        ),
+        Err(WolGatewayError::VmDisabled(_))
+    ));
+}
+
+#[test]
+fn test_domain_selector() {
+    use crate::policy::DomainSelector;
+
+    let uuid: uuid::Uuid = "3e3fce45-4f53-4fa7-bb32-11f34168b82b".parse().unwrap();
+    let other = uuid::Uuid::nil();
+
+    let selector = DomainSelector::parse("3E3FCE45-4F53-4FA7-BB32-11F34168B82B");
+    assert_eq!(selector, DomainSelector::Uuid(uuid));
+    assert!(selector.matches("any", &uuid));
+    assert!(!selector.matches("any", &other));
+
+    for (glob, name, expected) in [
+        ("web-*", "web-1", true),
+        ("web-*", "web-", true),
+        ("web-*", "db-1", false),
+        ("*-prod", "api-prod", true),
+        ("*-prod", "api-prod-old", false),
+        ("vm?", "vm1", true),
+        ("vm?", "vm12", false),
+        ("*a*b*", "xxaxxbxx", true),
+        ("*a*b", "xxaxxbxxa", false),
+        ("build", "build", true),
+        ("build", "builder", false),
+        ("*", "", true),
+    ] {
+        assert_eq!(
+            DomainSelector::parse(glob).matches(name, &other),
+            expected,
+            "{glob} against {name}"
+        );
+    }
+}
+
+#[test]
+fn test_opt_in_mode() {
+    let uuid = uuid::Uuid::nil();
+    let no_annotations = || Ok(Vec::new());
+    let metadata = crate::policy::VmPolicy::default();
+    let opted_in = Some(&metadata);
+
+    // Without opt-in mode every domain that opted in through its metadata may
+    // be woken, without reading annotations
+    let policy = load_config(&[], "").policy;
+    assert!(policy
+        .check_wakeable("any", &uuid, opted_in, || unreachable!())
+        .is_ok());
+
+    // Domains without a policy in their metadata are never woken
+    for args in [&[][..], &["--opt-in"], &["--wakeable", "*"]] {
+        let policy = load_config(args, "[[vm]]\ndomain = \"any\"").policy;
+        assert!(matches!(
+            policy.check_wakeable("any", &uuid, None, || Ok(vec!["[wol]".to_string()])),
+            Err(WolGatewayError::VmNotWakeable(_))
+        ));
+    }
+
+    let toml = r#"
+        [security]
+        wakeable = ["web-*"]
+
+        [[vm]]
+        domain = "build"
+    "#;
+    let policy = load_config(&[], toml).policy;
+    assert!(policy
+        .check_wakeable("web-1", &uuid, opted_in, no_annotations)
+        .is_ok());
+    assert!(policy
+        .check_wakeable("build", &uuid, opted_in, no_annotations)
+        .is_ok());
+    assert!(policy
+        .check_wakeable("marked", &uuid, opted_in, || Ok(vec![
+            "Build runner [wol]".to_string()
+        ]))
+        .is_ok());
+
+    // The metadata opt-in alone does not select a domain in opt-in mode
+    assert!(matches!(
+        policy.check_wakeable("db-1", &uuid, opted_in, || Ok(vec!["Database".to_string()])),
+        Err(WolGatewayError::VmNotWakeable(_))
+    ));
+
+    // The flag enables opt-in mode with only markers selecting domains
+    let policy = load_config(&["--opt-in"], "[security]\nmarker = \"#wake\"").policy;
+    assert!(matches!(
+        policy.check_wakeable("web-1", &uuid, opted_in, || Ok(vec!["[wol]".to_string()])),
+        Err(WolGatewayError::VmNotWakeable(_))
+    ));
+    assert!(policy
+        .check_wakeable("web-1", &uuid, opted_in, || Ok(vec!["#wake".to_string()]))
+        .is_ok());
+
+    assert!(matches!(
+        try_load_config(&["--opt-in"], "[security]\nmarker = \"\""),
+        Err(WolGatewayError::ConfigError(_))
+    ));
+}
+
+#[test]
+fn test_config_multiple_listeners() {
+    let toml = r#"
+        [listen]
+        address = ["127.0.0.1:9", "[::]:9", "0.0.0.0:7"]
+        multicast = ["ff02::1", "ff02::1%br0"]
+    "#;
+    let config = load_config(&[], toml);
+    assert_eq!(
+        config.addresses,
+        vec![
+            "127.0.0.1:9".parse().unwrap(),
+            "[::]:9".parse().unwrap(),
+            "0.0.0.0:7".parse().unwrap()
+        ]
+    );
+    assert_eq!(config.multicast.len(), 2);
+    assert_eq!(config.multicast[0].interface, None);
+    assert_eq!(config.multicast[1].to_string(), "ff02::1%br0");
+
+    let config = load_config(
+        &["--address", "[::]:9,127.0.0.1:9009", "-a", "[::1]:9"],
+        toml,
+    );
+    assert_eq!(config.addresses.len(), 3);
+
+    for toml in [
+        "[listen]\naddress = []",
+        "[listen]\naddress = [\"[::]:9\", \"[::]:9\"]",
+        "[listen]\nmulticast = [\"ff02::1\"]",
+        "[listen]\naddress = \"[::]:9\"\nmulticast = [\"fe80::1\"]",
+        "[listen]\naddress = \"[::]:9\"\nmulticast = [\"224.0.0.1\"]",
+    ] {
+        assert!(
+            matches!(
+                try_load_config(&[], toml),
+                Err(WolGatewayError::ConfigError(_))
+            ),
+            "{toml} should be rejected"
+        );
+    }
+}
+
+#[test]
+fn test_udp_dual_stack_listener() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        // Skip on hosts without IPv6
+        let Ok(socket) = crate::udp::bind("[::]:0".parse().unwrap(), false, &[]) else {
+            return;
+        };
+        let port = socket.local_addr().unwrap().port();
+
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"wake", ("127.0.0.1", port)).unwrap();
+
+        let mut buf = [0_u8; 8];
+        let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"wake");
+        assert_eq!(
+            source.ip().to_canonical(),
+            sender.local_addr().unwrap().ip()
+        );
+
+        // A v6-only listener leaves the port free for an IPv4 listener
+        let socket = crate::udp::bind("[::]:0".parse().unwrap(), true, &[]).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert!(crate::udp::bind(([127, 0, 0, 1], port).into(), false, &[]).is_ok());
+    });
+}
+
+#[test]
+fn test_systemd_notify() {
+    use std::os::unix::net::UnixDatagram;
+
+    // Not socket activated and not supervised by systemd
+    assert!(crate::systemd::listen_sockets().unwrap().is_empty());
+
+    let path = std::env::temp_dir().join(format!("wol-notify-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "30000000");
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+
+    crate::systemd::notify_ready("Connected to test:///default");
+    crate::systemd::notify_watchdog();
+    let interval = crate::systemd::watchdog_interval();
+
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::env::remove_var("WATCHDOG_USEC");
+    std::env::remove_var("WATCHDOG_PID");
+
+    let mut buf = [0_u8; 256];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buf[..len]).unwrap(),
+        "READY=1\nSTATUS=Connected to test:///default\n"
+    );
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(), "WATCHDOG=1\n");
+    let _ = std::fs::remove_file(&path);
+
+    // Pings are sent at half the watchdog timeout
+    assert_eq!(interval, Some(std::time::Duration::from_secs(15)));
+}
+
+#[test]
+fn test_exit_codes() {
+    use std::io::{Error, ErrorKind};
+
+    let address = "127.0.0.1:9".parse().unwrap();
+    let config_error = try_load_config(&[], "[listen]\nudp = false").err().unwrap();
+
+    let cases = [
+        (config_error, 78),
+        (
+            WolGatewayError::ConfigReadError(Error::from(ErrorKind::NotFound)),
+            66,
+        ),
+        (
+            WolGatewayError::LibvirtUnavailable("qemu:///system".to_string()),
+            69,
+        ),
+        (
+            WolGatewayError::SocketBindError(address, Error::from(ErrorKind::PermissionDenied)),
+            77,
+        ),
+        (
+            WolGatewayError::SocketBindError(address, Error::from(ErrorKind::AddrInUse)),
+            71,
+        ),
+        (
+            WolGatewayError::RawSocketError(Error::from(ErrorKind::PermissionDenied)),
+            77,
+        ),
+        (WolGatewayError::ListenersStopped, 74),
+        (
+            WolGatewayError::ShutdownTimeout(std::time::Duration::from_secs(30)),
+            75,
+        ),
+        (WolGatewayError::WorkerStopped, 70),
+    ];
+
+    for (error, code) in cases {
+        assert_eq!(error.exit_code(), code, "{}", error);
+    }
+}
+
+#[test]
+fn test_api_http_listener() {
+    use crate::api::ApiRequest;
+    use crate::libvirt::{BootMode, DomainState, ResolvedVm, StartAction, StateReason, VmStatus};
+    use crate::policy::WakeTarget;
+    use crate::server::Received;
+    use crate::wakeonlan::SecureOnPassword;
+    use std::io::{Read, Write};
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let listener = runtime
+        .block_on(crate::api::bind("127.0.0.1:0".parse().unwrap()))
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+    runtime.spawn(crate::api::serve(listener, tx));
+
+    // Stand in for the processing loop and the libvirt worker
+    runtime.spawn(async move {
+        while let Some(received) = rx.recv().await {
+            let Received::Api(request) = received else {
+                continue;
+            };
+            match request {
+                ApiRequest::Wake { request, reply, .. } => {
+                    let result = match request.target {
+                        WakeTarget::Domain(name)
+                            if request.password == Some(SecureOnPassword::Full([0x11; 6])) =>
+                        {
+                            Ok(vec![ResolvedVm {
+                                name,
+                                uuid: uuid::Uuid::nil(),
+                                state: DomainState::Shutoff,
+                                action: StartAction::Boot(BootMode::Start),
+                            }])
+                        }
+                        WakeTarget::Domain(name) => Err(WolGatewayError::PasswordMismatch(name)),
+                        WakeTarget::Mac(mac) => Err(WolGatewayError::VmNotFound(mac)),
+                    };
+                    let _ = reply.send(result);
+                }
+                ApiRequest::Status {
+                    domain,
+                    token,
+                    reply,
+                    ..
+                } => {
+                    // The locked domain stands in for required authentication
+                    let authenticated = match token {
+                        Some(token) => {
+                            token.client == "guacamole"
+                                && token.timestamp == 1_700_000_000
+                                && token.nonce == "n1"
+                                && token.signature == [0xab, 0xcd]
+                        }
+                        None => domain != "locked",
+                    };
+                    let result = if authenticated {
+                        Ok(VmStatus {
+                            name: domain,
+                            uuid: uuid::Uuid::nil(),
+                            state: DomainState::Shutoff,
+                            reason: StateReason::new(&DomainState::Shutoff, 1),
+                        })
+                    } else {
+                        Err(WolGatewayError::AuthenticationRequired)
+                    };
+                    let _ = reply.send(result);
+                }
+            }
+        }
+    });
+
+    let send_with_headers = |method: &str, path: &str, headers: &str, body: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let send = |method: &str, path: &str, body: &str| send_with_headers(method, path, "", body);
+
+    // MAC addresses are normalized before they are resolved
+    let response = send("POST", "/wake/52:54:00:AB:CD:EF", "");
+    assert!(response.starts_with("HTTP/1.1 404"));
+    assert!(response.contains("application/json"));
+    assert!(response.contains(r#""error":"VmNotFound""#));
+    assert!(response.contains("52:54:00:ab:cd:ef"));
+
+    let response = send("POST", "/wake/not-a-mac", "");
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains(r#""error":"WakeOnLanParseError""#));
+
+    let response = send(
+        "POST",
+        "/domains/web/start",
+        r#"{"password": "11:11:11:11:11:11"}"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#""name":"web""#));
+    assert!(response.contains(r#""uuid":"00000000-0000-0000-0000-000000000000""#));
+    assert!(response.contains(r#""action":"start""#));
+
+    let response = send("POST", "/domains/web/start", "");
+    assert!(response.starts_with("HTTP/1.1 403"));
+    assert!(response.contains(r#""error":"PasswordMismatch""#));
+
+    let response = send("POST", "/domains/web/start", r#"{"secret": "x"}"#);
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains(r#""error":"InvalidApiRequest""#));
+
+    // Authentication tokens are only accepted as a whole
+    let response = send("POST", "/domains/web/start", r#"{"client": "guacamole"}"#);
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains("must be given together"));
+
+    let response = send("GET", "/domains/web/state", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#""state":"shut off""#));
+
+    // Path segments are percent-decoded
+    let response = send("GET", "/domains/web%20server%2F1/state", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#""name":"web server/1""#));
+    for path in [
+        "/domains/web%2/state",
+        "/domains/web%zz/state",
+        "/domains/%ff/state",
+    ] {
+        let response = send("GET", path, "");
+        assert!(response.starts_with("HTTP/1.1 400"), "{path}");
+        assert!(response.contains("Invalid path segment"), "{path}");
+    }
+    assert!(response.contains(r#""reason":"guest shut down normally""#));
+
+    // State requests carry their token in headers
+    let response = send("GET", "/domains/locked/state", "");
+    assert!(response.starts_with("HTTP/1.1 401"));
+    assert!(response.contains(r#""error":"AuthenticationRequired""#));
+
+    let token = "X-Wol-Client: guacamole\r\nX-Wol-Timestamp: 1700000000\r\nX-Wol-Nonce: n1\r\n";
+    let response = send_with_headers(
+        "GET",
+        "/domains/locked/state",
+        &format!("{}X-Wol-Hmac: abcd\r\n", token),
+        "",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#""name":"locked""#));
+
+    let response = send_with_headers("GET", "/domains/locked/state", token, "");
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains("must be given together"));
+
+    let response = send_with_headers(
+        "GET",
+        "/domains/locked/state",
+        &format!("{}X-Wol-Hmac: not hex\r\n", token),
+        "",
+    );
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.contains("Invalid hmac"));
+
+    let response = send("GET", "/domains/web/start", "");
+    assert!(response.starts_with("HTTP/1.1 405"));
+    assert!(response.contains("allow: POST"));
+
+    assert!(send("GET", "/", "").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn test_api_status_codes() {
+    use crate::api::status_code;
+    use hyper::StatusCode;
+
+    let cases = [
+        (
+            WolGatewayError::VmNotFound("aa:bb:cc:dd:ee:ff".to_string()),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            WolGatewayError::DomainNotFound("web".to_string()),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            WolGatewayError::VmNotWakeable("web".to_string()),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            WolGatewayError::SourceNotAllowed("10.0.0.1".to_string()),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            WolGatewayError::RateLimited("10.0.0.1".to_string()),
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+        (
+            WolGatewayError::AuthenticationFailed("Replayed nonce for client a".to_string()),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            WolGatewayError::CrashedNotRestarted("web".to_string()),
+            StatusCode::CONFLICT,
+        ),
+        (
+            WolGatewayError::WakeupUnsupported("web".to_string()),
+            StatusCode::NOT_IMPLEMENTED,
+        ),
+        (
+            WolGatewayError::WorkerQueueFull("domain web".to_string()),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            WolGatewayError::ConfigError("invalid".to_string()),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    ];
+
+    for (error, status) in cases {
+        assert_eq!(status_code(&error), status, "{}", error);
+    }
+}
+
+#[test]
+fn test_wake_token_verification() {
+    use crate::auth::{NonceCache, TokenAction, WakeToken};
+    use crate::policy::WakeTarget;
+    use hmac::Mac;
+
+    let toml = r#"
+        [security]
+        require_auth = true
+        auth_window = 30
+
+        [[client]]
+        name = "guacamole"
+        key = "0123456789abcdef"
+    "#;
+    let auth = load_config(&[], toml).policy.auth.clone();
+    assert!(auth.required);
+
+    let sign = |message: &str| {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"0123456789abcdef").unwrap();
+        mac.update(message.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+    let token = |timestamp: u64, nonce: &str, message: &str| WakeToken {
+        client: "guacamole".to_string(),
+        timestamp,
+        nonce: nonce.to_string(),
+        signature: sign(message),
+    };
+    let mac = WakeTarget::Mac("52:54:00:ab:cd:ef".to_string());
+    let now = 1_700_000_000;
+    let mut nonces = NonceCache::new();
+
+    let valid = token(now, "n1", "mac:52:54:00:ab:cd:ef\n1700000000\nn1");
+    assert!(nonces
+        .verify(&auth, &valid, TokenAction::Wake, &mac, now)
+        .is_ok());
+
+    // The same token cannot be replayed
+    assert!(matches!(
+        nonces.verify(&auth, &valid, TokenAction::Wake, &mac, now + 1),
+        Err(WolGatewayError::AuthenticationFailed(_))
+    ));
+
+    // Clock skew within the window is tolerated, outside of it tokens expire
+    let skewed = token(now + 20, "n2", "mac:52:54:00:ab:cd:ef\n1700000020\nn2");
+    assert!(nonces
+        .verify(&auth, &skewed, TokenAction::Wake, &mac, now)
+        .is_ok());
+    let expired = token(now - 31, "n3", "mac:52:54:00:ab:cd:ef\n1699999969\nn3");
+    assert!(nonces
+        .verify(&auth, &expired, TokenAction::Wake, &mac, now)
+        .is_err());
+
+    // The signature covers the target, and only known clients are accepted
+    let other = WakeTarget::Domain("52:54:00:ab:cd:ef".to_string());
+    let forged = token(now, "n4", "mac:52:54:00:ab:cd:ef\n1700000000\nn4");
+    assert!(nonces
+        .verify(&auth, &forged, TokenAction::Wake, &other, now)
+        .is_err());
+    let unknown = WakeToken {
+        client: "unknown".to_string(),
+        ..token(now, "n5", "mac:52:54:00:ab:cd:ef\n1700000000\nn5")
+    };
+    assert!(nonces
+        .verify(&auth, &unknown, TokenAction::Wake, &mac, now)
+        .is_err());
+
+    // A rejected token does not use up its nonce
+    assert!(nonces
+        .verify(&auth, &forged, TokenAction::Wake, &mac, now)
+        .is_ok());
+
+    // State tokens sign an extra line and cannot be used to wake the VM, or the other way round
+    let web = WakeTarget::Domain("web".to_string());
+    let state = token(now, "n6", "state\ndomain:web\n1700000000\nn6");
+    assert!(nonces
+        .verify(&auth, &state, TokenAction::Wake, &web, now)
+        .is_err());
+    assert!(nonces
+        .verify(&auth, &state, TokenAction::State, &web, now)
+        .is_ok());
+    let wake = token(now, "n7", "domain:web\n1700000000\nn7");
+    assert!(nonces
+        .verify(&auth, &wake, TokenAction::State, &web, now)
+        .is_err());
+
+    let invalid = [
+        "[security]\nrequire_auth = true",
+        "[[client]]\nname = \"short\"\nkey = \"too short\"",
+        "[[client]]\nname = \"\"\nkey = \"0123456789abcdef\"",
+        "[security]\nauth_window = 0",
+    ];
+    for toml in invalid {
+        assert!(try_load_config(&[], toml).is_err(), "{}", toml);
+    }
+}
+
+#[test]
+fn test_send_magic_packets() {
+    use clap::Parser;
+
+    let mac = crate::wakeonlan::parse_mac_address_string("52:54:00:AB:CD:EF").unwrap();
+
+    // Built packets are accepted by the parser, with the password as given
+    let packet = crate::wakeonlan::build_magic_packet(&mac, None);
+    assert_eq!(packet.len(), 102);
+    let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
+    assert_eq!(wol.target_mac_string(), "52:54:00:ab:cd:ef");
+    assert_eq!(wol.password(), None);
+
+    let password = crate::wakeonlan::parse_secureon_password_string("192.168.1.10").unwrap();
+    let packet = crate::wakeonlan::build_magic_packet(&mac, Some(&password));
+    assert_eq!(packet.len(), 106);
+    let wol = crate::wakeonlan::WakeOnLanPacket::parse(&packet).unwrap();
+    assert_eq!(wol.password(), Some(&password));
+
+    let password = crate::wakeonlan::parse_secureon_password_string("11:22:33:44:55:66").unwrap();
+    let packet = crate::wakeonlan::build_magic_packet(&mac, Some(&password));
+    assert_eq!(packet.len(), 108);
+
+    let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = receiver.local_addr().unwrap().port().to_string();
+    let args = crate::Cli::try_parse_from([
+        "wol-libvirt-gateway",
+        "send",
+        "52:54:00:ab:cd:ef",
+        "--port",
+        &port,
+        "--count",
+        "2",
+        "--interval",
+        "0",
+    ])
+    .unwrap();
+    let Some(crate::Command::Send(send)) = args.command else {
+        panic!("send subcommand not parsed");
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(crate::send::run(&send)).unwrap();
+
+    let mut buf = [0_u8; 128];
+    for _ in 0..2 {
+        let len = receiver.recv(&mut buf).unwrap();
+        let wol = crate::wakeonlan::WakeOnLanPacket::parse(&buf[..len]).unwrap();
+        assert_eq!(wol.target_mac_string(), "52:54:00:ab:cd:ef");
+    }
+
+    // Gateway options cannot be mixed with the subcommand
+    assert!(crate::Cli::try_parse_from([
+        "wol-libvirt-gateway",
+        "--port",
+        "9009",
+        "send",
+        "52:54:00:ab:cd:ef",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_list_output_formats() {
+    use crate::libvirt::{DomainState, DomainSummary};
+
+    let domains = [
+        DomainSummary {
+            name: "build".to_string(),
+            uuid: uuid::Uuid::parse_str("6f2b7a0c-3e4d-4b5a-9c8d-1e2f3a4b5c6d").unwrap(),
+            state: DomainState::Shutoff,
+            macs: vec![
+                "52:54:00:12:34:56".to_string(),
+                "52:54:00:ab:cd:ef".to_string(),
+            ],
+        },
+        DomainSummary {
+            name: "web, clone".to_string(),
+            uuid: uuid::Uuid::parse_str("0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d").unwrap(),
+            state: DomainState::Running,
+            macs: vec!["52:54:00:12:34:56".to_string()],
+        },
+        DomainSummary {
+            name: "empty".to_string(),
+            uuid: uuid::Uuid::parse_str("11111111-2222-4333-8444-555555555555").unwrap(),
+            state: DomainState::Paused,
+            macs: vec![],
+        },
+    ];
+
+    assert_eq!(
+        crate::list::duplicate_macs(&domains),
+        vec![(
+            "52:54:00:12:34:56".to_string(),
+            vec!["build".to_string(), "web, clone".to_string()]
+        )]
+    );
+
+    assert_eq!(
+        crate::list::format_table(&domains),
+        "NAME        UUID                                  STATE     MACS\n\
+         build       6f2b7a0c-3e4d-4b5a-9c8d-1e2f3a4b5c6d  shut off  52:54:00:12:34:56, 52:54:00:ab:cd:ef\n\
+         web, clone  0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d  running   52:54:00:12:34:56\n\
+         empty       11111111-2222-4333-8444-555555555555  paused    -\n"
+    );
+
+    assert_eq!(
+        crate::list::format_csv(&domains),
+        "name,uuid,state,macs\n\
+         build,6f2b7a0c-3e4d-4b5a-9c8d-1e2f3a4b5c6d,shut off,52:54:00:12:34:56 52:54:00:ab:cd:ef\n\
+         \"web, clone\",0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d,running,52:54:00:12:34:56\n\
+         empty,11111111-2222-4333-8444-555555555555,paused,\n"
+    );
+
+    let json: serde_json::Value =
+        serde_json::from_str(&crate::list::format_json(&domains)).unwrap();
+    assert_eq!(json[0]["name"], "build");
+    assert_eq!(json[0]["state"], "shut off");
+    assert_eq!(json[0]["macs"][1], "52:54:00:ab:cd:ef");
+    assert_eq!(json[2]["macs"].as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn test_start_action_planning() {
+    use crate::libvirt::{plan_start, BootMode, DomainState, StartAction, StateReason};
+    use crate::policy::{ManagedSave, StartOptions, WakeAction};
+    use clap::Parser;
+
+    let defaults = StartOptions::default();
+    let plan =
+        |state: DomainState, reason: i32, options: &StartOptions, saved: bool, active: bool| {
+            plan_start(
+                "vm",
+                state,
+                StateReason::new(&state, reason),
+                options,
+                || Ok(saved),
+                || Ok(active),
+            )
+        };
+
+    assert_eq!(
+        plan(DomainState::Shutoff, 1, &defaults, false, false).unwrap(),
+        StartAction::Boot(BootMode::Start)
+    );
+    assert_eq!(
+        plan(DomainState::Shutoff, 5, &defaults, true, false).unwrap(),
+        StartAction::Boot(BootMode::RestoreManagedSave)
+    );
+    let discard = StartOptions {
+        managed_save: ManagedSave::Discard,
+        ..defaults
+    };
+    assert_eq!(
+        plan(DomainState::Shutoff, 5, &discard, true, false).unwrap(),
+        StartAction::Boot(BootMode::DiscardManagedSave)
+    );
+    assert_eq!(
+        plan(DomainState::Paused, 1, &defaults, false, true).unwrap(),
+        StartAction::Resume
+    );
+    assert_eq!(
+        plan(DomainState::Running, 1, &defaults, false, true).unwrap(),
+        StartAction::Nothing
+    );
+
+    // Guests suspended to RAM keep running in QEMU and cannot be woken up,
+    // suspended to disk they boot
+    assert!(matches!(
+        plan(DomainState::PmSuspended, 0, &defaults, false, true),
+        Err(WolGatewayError::WakeupUnsupported(_))
+    ));
+    assert_eq!(
+        plan(DomainState::PmSuspended, 0, &defaults, false, false).unwrap(),
+        StartAction::Boot(BootMode::Start)
+    );
+
+    // Crashed domains are only restarted when enabled, destroying active ones
+    assert!(matches!(
+        plan(DomainState::Crashed, 1, &defaults, false, true),
+        Err(WolGatewayError::CrashedNotRestarted(_))
+    ));
+    let restart = StartOptions {
+        restart_crashed: true,
+        ..defaults
+    };
+    assert_eq!(
+        plan(DomainState::Crashed, 1, &restart, false, true).unwrap(),
+        StartAction::DestroyAndBoot(BootMode::Start)
+    );
+    assert_eq!(
+        plan(DomainState::Shutoff, 3, &restart, false, false).unwrap(),
+        StartAction::Boot(BootMode::Start)
+    );
+
+    let resume = StartOptions {
+        action: WakeAction::Resume,
+        ..defaults
+    };
+    assert_eq!(
+        plan(DomainState::Shutoff, 1, &resume, false, false).unwrap(),
+        StartAction::ResumeOnly
+    );
+    assert_eq!(
+        plan(DomainState::Paused, 1, &resume, false, true).unwrap(),
+        StartAction::Resume
+    );
+
+    // Checking the managed save image only happens when booting
+    let failed = plan_start(
+        "vm",
+        DomainState::Paused,
+        StateReason::new(&DomainState::Paused, 1),
+        &defaults,
+        || Err(WolGatewayError::ConfigError("unexpected".to_string())),
+        || Ok(true),
+    );
+    assert_eq!(failed.unwrap(), StartAction::Resume);
+
+    assert_eq!(
+        StartAction::DestroyAndBoot(BootMode::RestoreManagedSave).to_string(),
+        "be destroyed after its crash and restore its managed save image"
+    );
+
+    // Dry runs are enabled on the command line or in the configuration file
+    let config = load_config(&[], "");
+    assert!(!config.policy.dry_run);
+    let config = load_config(&[], "[start]\ndry_run = true");
+    assert!(config.policy.dry_run);
+    let config = load_config(&["--dry-run"], "");
+    assert!(config.policy.dry_run);
+
+    // The --no-* flags override booleans set in the configuration file
+    let config = load_config(&["--no-dry-run"], "[start]\ndry_run = true");
+    assert!(!config.policy.dry_run);
+    let config = load_config(&["--dry-run", "--no-dry-run"], "");
+    assert!(!config.policy.dry_run);
+    let config = load_config(&["--no-dry-run", "--dry-run"], "");
+    assert!(config.policy.dry_run);
+    let config = load_config(&["--no-restart-crashed"], "[start]\nrestart_crashed = true");
+    assert!(!config.policy.start.restart_crashed);
+    let config = load_config(&["--no-opt-in"], "[security]\nopt_in = true");
+    assert!(config.policy.opt_in.is_none());
+    let config = load_config(&["--udp"], "[listen]\nudp = false");
+    assert!(config.udp);
+    assert!(crate::Cli::try_parse_from([
+        "wol-libvirt-gateway",
+        "--no-opt-in",
+        "--wakeable",
+        "web-*",
+    ])
+    .is_err());
+
+    let args = crate::Cli::try_parse_from([
+        "wol-libvirt-gateway",
+        "check",
+        "52:54:00:AB:CD:EF",
+        "--password",
+        "10.0.0.1",
+        "--source",
+        "192.168.1.20",
+    ])
+    .unwrap();
+    let Some(crate::Command::Check(check)) = args.command else {
+        panic!("check subcommand not parsed");
+    };
+    assert_eq!(
+        check.source,
+        "192.168.1.20".parse::<std::net::IpAddr>().unwrap()
+    );
+    assert_eq!(
+        check.password,
+        Some(crate::wakeonlan::SecureOnPassword::Short([10, 0, 0, 1]))
+    );
+}
+
+#[test]
+fn test_duplicate_mac_selection() {
+    use crate::policy::{DomainSelector, DuplicateMacs, Policy};
+
+    let build = uuid::Uuid::parse_str("6f2b7a0c-3e4d-4b5a-9c8d-1e2f3a4b5c6d").unwrap();
+    let candidates = [
+        ("build".to_string(), build),
+        ("build-clone".to_string(), uuid::Uuid::nil()),
+        ("web-1".to_string(), uuid::Uuid::max()),
+    ];
+    let mac = "52:54:00:12:34:56";
+    let policy = |duplicate_macs| Policy {
+        duplicate_macs,
+        ..Policy::default()
+    };
+
+    // A single owner is always selected
+    let refuse = policy(DuplicateMacs::Refuse);
+    assert_eq!(
+        refuse.select_duplicates(mac, &candidates[..1]).unwrap(),
+        [0]
+    );
+    match refuse.select_duplicates(mac, &candidates) {
+        Err(e @ WolGatewayError::AmbiguousMac(..)) => assert_eq!(
+            e.to_string(),
+            "MAC address 52:54:00:12:34:56 is used by several domains: build, build-clone, web-1"
+        ),
+        other => panic!("unexpected selection: {:?}", other),
+    }
+
+    let all = policy(DuplicateMacs::All);
+    assert_eq!(all.select_duplicates(mac, &candidates).unwrap(), [0, 1, 2]);
+
+    // The first selector matching any domain decides, and must match only one
+    let prefer = |selectors: &[&str]| {
+        policy(DuplicateMacs::Prefer(
+            selectors.iter().map(|s| DomainSelector::parse(s)).collect(),
+        ))
+        .select_duplicates(mac, &candidates)
+    };
+    assert_eq!(prefer(&["web-*", "build"]).unwrap(), [2]);
+    assert_eq!(prefer(&["db", &build.to_string()]).unwrap(), [0]);
+    assert!(matches!(
+        prefer(&["build*"]),
+        Err(WolGatewayError::AmbiguousMac(..))
+    ));
+    assert!(matches!(
+        prefer(&["db"]),
+        Err(WolGatewayError::AmbiguousMac(..))
+    ));
+
+    assert_eq!(
+        crate::api::status_code(&WolGatewayError::AmbiguousMac(mac.to_string(), vec![])),
+        hyper::StatusCode::CONFLICT
+    );
+
+    let config = load_config(&[], "");
+    assert_eq!(config.policy.duplicate_macs, DuplicateMacs::Refuse);
+    let config = load_config(&[], "[start]\nduplicate_macs = \"all\"");
+    assert_eq!(config.policy.duplicate_macs, DuplicateMacs::All);
+
+    // Preferring domains implies the prefer mode
+    let config = load_config(&[], "[start]\nprefer = [\"build\"]");
+    assert_eq!(
+        config.policy.duplicate_macs,
+        DuplicateMacs::Prefer(vec![DomainSelector::Name("build".to_string())])
+    );
+    let config = load_config(&["--prefer", "web-*"], "[start]\nprefer = [\"build\"]");
+    assert_eq!(
+        config.policy.duplicate_macs,
+        DuplicateMacs::Prefer(vec![DomainSelector::Name("web-*".to_string())])
+    );
+
+    let invalid = [
+        "[start]\nduplicate_macs = \"first\"",
+        "[start]\nduplicate_macs = \"prefer\"",
+        "[start]\nduplicate_macs = \"all\"\nprefer = [\"build\"]",
+        "[start]\nprefer = [\"\"]",
+    ];
+    for toml in invalid {
+        assert!(
+            matches!(
+                try_load_config(&[], toml),
+                Err(WolGatewayError::ConfigError(_))
+            ),
+            "{}",
+            toml
+        );
+    }
+}
+
+/// URI of libvirt's built-in test driver, whose state is shared by all
+/// connections of the test process.
+#[cfg(test)]
+const TEST_LIBVIRT_URI: &str = "test:///default";
+
+/// Defines a domain on the test driver and brings it into the given state.
+///
+/// The domain opts in to waking through its metadata.
+///
+/// # Arguments
+///
+/// * `conn` - A connection to the test driver
+/// * `name` - The domain name
+/// * `mac` - MAC address of the single interface of the domain
+/// * `state` - `Shutoff`, `Running` or `Paused`
+#[cfg(test)]
+fn define_test_domain(
+    conn: &virt::connect::Connect,
+    name: &str,
+    mac: &str,
+    state: crate::libvirt::DomainState,
+) -> virt::domain::Domain {
+    use crate::libvirt::DomainState;
+
+    let xml = format!(
+        "<domain type='test'>
+           <name>{}</name>
+           <memory unit='MiB'>64</memory>
+           <os><type>hvm</type></os>
+           <metadata>
+             <wol:policy xmlns:wol='{}' enabled='yes'/>
+           </metadata>
+           <devices>
+             <interface type='network'>
+               <source network='default'/>
+               <mac address='{}'/>
+             </interface>
+           </devices>
+         </domain>",
+        name,
+        crate::domain_xml::POLICY_NAMESPACE,
+        mac
+    );
+    let domain = virt::domain::Domain::define_xml(conn, &xml).unwrap();
+    match state {
+        DomainState::Shutoff => {}
+        DomainState::Running => {
+            domain.create().unwrap();
+        }
+        DomainState::Paused => {
+            domain.create().unwrap();
+            domain.suspend().unwrap();
+        }
+        other => panic!("cannot define a test domain in state {}", other),
+    }
+    assert_eq!(domain_state(&domain), state);
+
+    domain
+}
+
+/// A file in the temporary directory with a unique name, removed when dropped.
+#[cfg(test)]
+struct TempFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    /// Creates a new file with the given contents.
+    fn new(contents: &str) -> Self {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "wol-libvirt-gateway-test-{}-{}-{}.xml",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed),
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos()
+            ));
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    file.write_all(contents.as_bytes()).unwrap();
+                    return TempFile(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => panic!("cannot create {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Returns the current state of a domain.
+#[cfg(test)]
+fn domain_state(domain: &virt::domain::Domain) -> crate::libvirt::DomainState {
+    let (state, _) = domain.get_state().unwrap();
+    crate::libvirt::DomainState::from(state)
+}
+
+/// Waits up to 10 seconds for a domain to reach a state.
+///
+/// # Returns
+///
+/// The state of the domain when it was reached or the time ran out.
+#[cfg(test)]
+fn wait_for_state(
+    domain: &virt::domain::Domain,
+    expected: crate::libvirt::DomainState,
+) -> crate::libvirt::DomainState {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        let state = domain_state(domain);
+        if state == expected || std::time::Instant::now() >= deadline {
+            return state;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "libvirt-tests"),
+    ignore = "needs the libvirt test driver"
+)]
+fn test_libvirt_find_and_start_by_mac() {
+    use crate::libvirt::{find_and_start_vm_by_mac, BootMode, DomainState, StartAction};
+    use crate::mac_index::MacIndex;
+    use crate::policy::{DuplicateMacs, Policy, VmPolicy, WakeRequest, WakeTarget};
+    use crate::server::PacketSource;
+
+    let conn = virt::connect::Connect::open(Some(TEST_LIBVIRT_URI)).unwrap();
+    let shutoff = define_test_domain(
+        &conn,
+        "wol-lookup-shutoff",
+        "52:54:00:00:01:01",
+        DomainState::Shutoff,
+    );
+    let saved = define_test_domain(
+        &conn,
+        "wol-lookup-saved",
+        "52:54:00:00:01:02",
+        DomainState::Running,
+    );
+    saved.managed_save(0).unwrap();
+    let twins = [
+        define_test_domain(
+            &conn,
+            "wol-lookup-twin-a",
+            "52:54:00:00:01:03",
+            DomainState::Shutoff,
+        ),
+        define_test_domain(
+            &conn,
+            "wol-lookup-twin-b",
+            "52:54:00:00:01:03",
+            DomainState::Shutoff,
+        ),
+    ];
+    let old_owner = define_test_domain(
+        &conn,
+        "wol-lookup-old-owner",
+        "52:54:00:00:01:04",
+        DomainState::Shutoff,
+    );
+    let new_owner = define_test_domain(
+        &conn,
+        "wol-lookup-new-owner",
+        "52:54:00:00:01:05",
+        DomainState::Shutoff,
+    );
+    let opted_out = define_test_domain(
+        &conn,
+        "wol-lookup-opted-out",
+        "52:54:00:00:01:08",
+        DomainState::Shutoff,
+    );
+    let set_policy = |policy: Option<&str>| {
+        opted_out
+            .set_metadata(
+                virt::sys::VIR_DOMAIN_METADATA_ELEMENT as i32,
+                policy,
+                policy.map(|_| "wol"),
+                Some(crate::domain_xml::POLICY_NAMESPACE),
+                0,
+            )
+            .unwrap();
+    };
+
+    let mut index = MacIndex::new(std::time::Duration::from_secs(300));
+    let request = |mac: &str| WakeRequest {
+        target: WakeTarget::Mac(mac.to_string()),
+        password: None,
+        source: PacketSource::Udp("127.0.0.1:9".parse().unwrap()),
+    };
+    let wake = |index: &mut MacIndex, mac: &str, policy: &Policy| {
+        find_and_start_vm_by_mac(&conn, index, mac, &request(mac), policy)
+    };
+
+    // A dry run resolves the VM and plans its start without touching it
+    let dry_run = Policy {
+        dry_run: true,
+        ..Policy::default()
+    };
+    let vms = wake(&mut index, "52:54:00:00:01:01", &dry_run).unwrap();
+    assert_eq!(vms.len(), 1);
+    assert_eq!(vms[0].name, "wol-lookup-shutoff");
+    assert_eq!(vms[0].state, DomainState::Shutoff);
+    assert_eq!(vms[0].action, StartAction::Boot(BootMode::Start));
+    assert_eq!(domain_state(&shutoff), DomainState::Shutoff);
+
+    let policy = Policy::default();
+    let vms = wake(&mut index, "52:54:00:00:01:01", &policy).unwrap();
+    assert_eq!(vms[0].action, StartAction::Boot(BootMode::Start));
+    assert_eq!(domain_state(&shutoff), DomainState::Running);
+
+    // A running VM is left alone
+    let vms = wake(&mut index, "52:54:00:00:01:01", &policy).unwrap();
+    assert_eq!(vms[0].state, DomainState::Running);
+    assert_eq!(vms[0].action, StartAction::Nothing);
+
+    // VMs without a policy in their metadata, or with a disabled one, are refused
+    set_policy(None);
+    assert!(matches!(
+        wake(&mut index, "52:54:00:00:01:08", &policy),
+        Err(WolGatewayError::VmNotWakeable(_))
+    ));
+    set_policy(Some("<policy/>"));
+    assert!(matches!(
+        wake(&mut index, "52:54:00:00:01:08", &policy),
+        Err(WolGatewayError::VmDisabled(_))
+    ));
+    set_policy(Some("<policy enabled='no'/>"));
+    assert!(matches!(
+        wake(&mut index, "52:54:00:00:01:08", &policy),
+        Err(WolGatewayError::VmDisabled(_))
+    ));
+    assert_eq!(domain_state(&opted_out), DomainState::Shutoff);
+
+    let vms = wake(&mut index, "52:54:00:00:01:02", &policy).unwrap();
+    assert_eq!(
+        vms[0].action,
+        StartAction::Boot(BootMode::RestoreManagedSave)
+    );
+    assert_eq!(domain_state(&saved), DomainState::Running);
+    assert!(!saved.has_managed_save(0).unwrap());
+
+    // Shared MAC addresses are refused unless configured otherwise
+    assert!(matches!(
+        wake(&mut index, "52:54:00:00:01:03", &policy),
+        Err(WolGatewayError::AmbiguousMac(..))
+    ));
+    assert!(twins
+        .iter()
+        .all(|twin| domain_state(twin) == DomainState::Shutoff));
+
+    // A clone the request may not wake does not make the MAC address ambiguous
+    let disabled_twin = Policy {
+        dry_run: true,
+        vms: [(
+            "wol-lookup-twin-b".to_string(),
+            VmPolicy {
+                enabled: false,
+                ..VmPolicy::default()
+            },
+        )]
+        .into(),
+        ..Policy::default()
+    };
+    let vms = wake(&mut index, "52:54:00:00:01:03", &disabled_twin).unwrap();
+    let names: Vec<_> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    assert_eq!(names, ["wol-lookup-twin-a"]);
+
+    let all = Policy {
+        duplicate_macs: DuplicateMacs::All,
+        ..Policy::default()
+    };
+    let vms = wake(&mut index, "52:54:00:00:01:03", &all).unwrap();
+    let names: Vec<_> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    assert_eq!(names, ["wol-lookup-twin-a", "wol-lookup-twin-b"]);
+    assert!(twins
+        .iter()
+        .all(|twin| domain_state(twin) == DomainState::Running));
+
+    assert!(matches!(
+        wake(&mut index, "52:54:00:00:01:ff", &policy),
+        Err(WolGatewayError::VmNotFound(_))
+    ));
+
+    // A domain defined after the index was built is found on a miss
+    define_test_domain(
+        &conn,
+        "wol-lookup-late",
+        "52:54:00:00:01:07",
+        DomainState::Shutoff,
+    );
+    let vms = wake(&mut index, "52:54:00:00:01:07", &dry_run).unwrap();
+    assert_eq!(vms[0].name, "wol-lookup-late");
+
+    // A MAC address moved to another domain is resolved to its new owner once
+    // the index is rebuilt in the background
+    let vms = wake(&mut index, "52:54:00:00:01:04", &dry_run).unwrap();
+    assert_eq!(vms[0].name, "wol-lookup-old-owner");
+    for (domain, from, to) in [
+        (&old_owner, "52:54:00:00:01:04", "52:54:00:00:01:06"),
+        (&new_owner, "52:54:00:00:01:05", "52:54:00:00:01:04"),
+    ] {
+        let xml = domain.get_xml_desc(0).unwrap().replace(from, to);
+        virt::domain::Domain::define_xml(&conn, &xml).unwrap();
+    }
+    index.rebuild(&conn).unwrap();
+    let vms = wake(&mut index, "52:54:00:00:01:04", &dry_run).unwrap();
+    let names: Vec<_> = vms.iter().map(|vm| vm.name.as_str()).collect();
+    assert_eq!(names, ["wol-lookup-new-owner"]);
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "libvirt-tests"),
+    ignore = "needs the libvirt test driver"
+)]
+fn test_libvirt_crashed_and_suspended_vms() {
+    use crate::libvirt::{find_and_start_vm_by_mac, BootMode, DomainState, StartAction};
+    use crate::mac_index::MacIndex;
+    use crate::policy::{Policy, StartOptions, WakeRequest, WakeTarget};
+    use crate::server::PacketSource;
+
+    // Domains can only be put into these states through a custom test driver
+    // file, which also gives the connection private state
+    let domain = |name: &str, mac: &str, runstate: u32| {
+        format!(
+            "<domain type='test' xmlns:test='http://libvirt.org/schemas/domain/test/1.0'>
+               <name>{}</name>
+               <memory unit='MiB'>64</memory>
+               <os><type>hvm</type></os>
+               <metadata>
+                 <wol:policy xmlns:wol='{}' enabled='yes'/>
+               </metadata>
+               <devices>
+                 <interface type='network'>
+                   <source network='default'/>
+                   <mac address='{}'/>
+                 </interface>
+               </devices>
+               <test:runstate>{}</test:runstate>
+             </domain>",
+            name,
+            crate::domain_xml::POLICY_NAMESPACE,
+            mac,
+            runstate
+        )
+    };
+    let node = format!(
+        "<node>
+           <network>
+             <name>default</name>
+             <bridge name='virbr0'/>
+             <ip address='192.168.122.1' netmask='255.255.255.0'/>
+           </network>
+           {}
+           {}
+         </node>",
+        domain(
+            "wol-crashed",
+            "52:54:00:00:03:01",
+            DomainState::Crashed as u32
+        ),
+        domain(
+            "wol-suspended",
+            "52:54:00:00:03:02",
+            DomainState::PmSuspended as u32
+        ),
+    );
+    let file = TempFile::new(&node);
+    let uri = format!("test://{}", file.0.display());
+    let conn = virt::connect::Connect::open(Some(&uri)).unwrap();
+    drop(file);
+
+    let crashed = virt::domain::Domain::lookup_by_name(&conn, "wol-crashed").unwrap();
+    let suspended = virt::domain::Domain::lookup_by_name(&conn, "wol-suspended").unwrap();
+    assert_eq!(domain_state(&crashed), DomainState::Crashed);
+    assert_eq!(domain_state(&suspended), DomainState::PmSuspended);
+
+    let mut index = MacIndex::new(std::time::Duration::from_secs(300));
+    let request = |mac: &str| WakeRequest {
+        target: WakeTarget::Mac(mac.to_string()),
+        password: None,
+        source: PacketSource::Udp("127.0.0.1:9".parse().unwrap()),
+    };
+    let mut wake = |mac: &str, policy: &Policy| {
+        find_and_start_vm_by_mac(&conn, &mut index, mac, &request(mac), policy)
+    };
+
+    // A crashed VM is kept for inspection unless restarting it is enabled
+    let policy = Policy::default();
+    assert!(matches!(
+        wake("52:54:00:00:03:01", &policy),
+        Err(WolGatewayError::CrashedNotRestarted(_))
+    ));
+    assert_eq!(domain_state(&crashed), DomainState::Crashed);
+
+    let restart = Policy {
+        start: StartOptions {
+            restart_crashed: true,
+            ..StartOptions::default()
+        },
+        ..Policy::default()
+    };
+    let vms = wake("52:54:00:00:03:01", &restart).unwrap();
+    assert_eq!(vms[0].state, DomainState::Crashed);
+    assert_eq!(vms[0].action, StartAction::DestroyAndBoot(BootMode::Start));
+    assert_eq!(domain_state(&crashed), DomainState::Running);
+
+    // A guest suspended to RAM cannot be woken up, not even in a dry run
+    let dry_run = Policy {
+        dry_run: true,
+        ..Policy::default()
+    };
+    for policy in [&dry_run, &policy] {
+        match wake("52:54:00:00:03:02", policy) {
+            Err(WolGatewayError::WakeupUnsupported(vm)) => assert_eq!(vm, "wol-suspended"),
+            other => panic!("unexpected wakeup result: {:?}", other),
+        }
+    }
+    assert_eq!(domain_state(&suspended), DomainState::PmSuspended);
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "libvirt-tests"),
+    ignore = "needs the libvirt test driver"
+)]
+fn test_libvirt_serve_wakes_vms() {
+    use crate::libvirt::DomainState;
+    use crate::wakeonlan::{build_magic_packet, parse_mac_address_string, SecureOnPassword};
+    use clap::Parser;
+
+    let conn = virt::connect::Connect::open(Some(TEST_LIBVIRT_URI)).unwrap();
+    let shutoff = define_test_domain(
+        &conn,
+        "wol-serve-shutoff",
+        "52:54:00:00:02:01",
+        DomainState::Shutoff,
+    );
+    let paused = define_test_domain(
+        &conn,
+        "wol-serve-paused",
+        "52:54:00:00:02:02",
+        DomainState::Paused,
+    );
+    let saved = define_test_domain(
+        &conn,
+        "wol-serve-saved",
+        "52:54:00:00:02:03",
+        DomainState::Running,
+    );
+    saved.managed_save(0).unwrap();
+    assert_eq!(domain_state(&saved), DomainState::Shutoff);
+    let running = define_test_domain(
+        &conn,
+        "wol-serve-running",
+        "52:54:00:00:02:04",
+        DomainState::Running,
+    );
+    let locked = define_test_domain(
+        &conn,
+        "wol-serve-locked",
+        "52:54:00:00:02:05",
+        DomainState::Shutoff,
+    );
+
+    // Ports that were just free, the gateway binds them right away
+    let address = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let api_address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let args = crate::Cli::try_parse_from([
+        "wol-libvirt-gateway",
+        "--libvirt-uri",
+        TEST_LIBVIRT_URI,
+        "--address",
+        &address.to_string(),
+        "--api-address",
+        &api_address.to_string(),
+        "--dedup-window",
+        "0",
+        "--rate-limit",
+        "0",
+        "--vm-password",
+        "wol-serve-locked=11:22:33:44:55:66",
+    ])
+    .unwrap();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let gateway = runtime.spawn(crate::server::serve(args));
+
+    // The API listener is bound after the UDP listeners
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::net::TcpStream::connect(api_address).is_err() {
+        assert!(!gateway.is_finished(), "the gateway did not start");
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the gateway did not start in time"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let send = |mac: &str, password: Option<&SecureOnPassword>| {
+        let mac = parse_mac_address_string(mac).unwrap();
+        socket
+            .send_to(&build_magic_packet(&mac, password), address)
+            .unwrap();
+    };
+
+    // Packets are processed in order, so the request without the password is
+    // done once the VMs woken after it are running
+    send("52:54:00:00:02:05", None);
+    send("52:54:00:00:02:04", None);
+    send("52:54:00:00:02:03", None);
+    send("52:54:00:00:02:02", None);
+    send("52:54:00:00:02:01", None);
+    assert_eq!(
+        wait_for_state(&shutoff, DomainState::Running),
+        DomainState::Running
+    );
+    assert_eq!(
+        wait_for_state(&paused, DomainState::Running),
+        DomainState::Running
+    );
+    assert_eq!(
+        wait_for_state(&saved, DomainState::Running),
+        DomainState::Running
+    );
+    assert!(!saved.has_managed_save(0).unwrap());
+    assert_eq!(domain_state(&running), DomainState::Running);
+    assert_eq!(domain_state(&locked), DomainState::Shutoff);
+
+    send(
+        "52:54:00:00:02:05",
+        Some(&SecureOnPassword::Full([
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+        ])),
+    );
+    assert_eq!(
+        wait_for_state(&locked, DomainState::Running),
+        DomainState::Running
+    );
+
+    runtime.shutdown_background();
+}
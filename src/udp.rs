@@ -0,0 +1,135 @@
+//! UDP listeners for Wake-on-LAN datagrams.
+//!
+//! The gateway can listen on several addresses at once, over IPv4 and IPv6.
+//! IPv6 has no broadcast, so WOL tools send magic packets to the all-nodes
+//! multicast group `ff02::1` instead, which IPv6 wildcard listeners can join.
+
+use std::fmt;
+use std::net::{Ipv6Addr, SocketAddr};
+
+use log::info;
+use nix::net::if_::if_nametoindex;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::error::WolGatewayError;
+
+/// An IPv6 multicast group joined by the UDP listeners.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MulticastGroup {
+    /// The multicast address, e.g. `ff02::1`.
+    pub(crate) group: Ipv6Addr,
+    /// Interface to join the group on; the kernel picks one if unset.
+    pub(crate) interface: Option<String>,
+}
+
+impl fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.interface {
+            Some(interface) => write!(f, "{}%{}", self.group, interface),
+            None => write!(f, "{}", self.group),
+        }
+    }
+}
+
+/// Binds a UDP listener.
+///
+/// IPv6 wildcard listeners join the given multicast groups, and receive IPv4
+/// datagrams as well unless `v6_only` is set.
+///
+/// # Arguments
+///
+/// * `address` - The address and port to listen on
+/// * `v6_only` - Whether an IPv6 listener only receives IPv6 datagrams
+/// * `groups` - Multicast groups joined by IPv6 wildcard listeners
+///
+/// # Errors
+///
+/// Returns `SocketBindError` if the socket could not be created or bound,
+/// `InterfaceNotFound` if the interface of a multicast group does not exist, or
+/// `MulticastJoinError` if a multicast group could not be joined.
+pub(crate) fn bind(
+    address: SocketAddr,
+    v6_only: bool,
+    groups: &[MulticastGroup],
+) -> Result<UdpSocket, WolGatewayError> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )
+    .map_err(|e| WolGatewayError::SocketBindError(address, e))?;
+
+    // Set explicitly, the default depends on the net.ipv6.bindv6only sysctl
+    if address.is_ipv6() {
+        socket
+            .set_only_v6(v6_only)
+            .map_err(|e| WolGatewayError::SocketBindError(address, e))?;
+    }
+
+    socket
+        .set_nonblocking(true)
+        .map_err(|e| WolGatewayError::SocketBindError(address, e))?;
+    socket
+        .bind(&address.into())
+        .map_err(|e| WolGatewayError::SocketBindError(address, e))?;
+
+    join_groups(SockRef::from(&socket), address, groups)?;
+
+    UdpSocket::from_std(socket.into()).map_err(|e| WolGatewayError::SocketBindError(address, e))
+}
+
+/// Takes over a UDP socket bound by systemd.
+///
+/// If the socket is bound to the IPv6 wildcard address, it joins the given
+/// multicast groups.
+///
+/// # Arguments
+///
+/// * `socket` - The socket passed by systemd
+/// * `groups` - Multicast groups joined by IPv6 wildcard listeners
+///
+/// # Errors
+///
+/// Returns `SocketActivationError` if the socket cannot be used, or the same
+/// errors as `bind` if a multicast group could not be joined.
+pub(crate) fn from_activated(
+    socket: std::net::UdpSocket,
+    groups: &[MulticastGroup],
+) -> Result<UdpSocket, WolGatewayError> {
+    let address = socket
+        .local_addr()
+        .map_err(WolGatewayError::SocketActivationError)?;
+    socket
+        .set_nonblocking(true)
+        .map_err(WolGatewayError::SocketActivationError)?;
+
+    join_groups(SockRef::from(&socket), address, groups)?;
+
+    UdpSocket::from_std(socket).map_err(WolGatewayError::SocketActivationError)
+}
+
+/// Joins the multicast groups if the socket is bound to the IPv6 wildcard address.
+fn join_groups(
+    socket: SockRef,
+    address: SocketAddr,
+    groups: &[MulticastGroup],
+) -> Result<(), WolGatewayError> {
+    if address.ip() != Ipv6Addr::UNSPECIFIED {
+        return Ok(());
+    }
+
+    for group in groups {
+        let index = match &group.interface {
+            Some(interface) => if_nametoindex(interface.as_str())
+                .map_err(|_| WolGatewayError::InterfaceNotFound(interface.clone()))?,
+            None => 0,
+        };
+        socket
+            .join_multicast_v6(&group.group, index)
+            .map_err(|e| WolGatewayError::MulticastJoinError(group.group, e))?;
+        info!("Joined multicast group {} on {}", group, address);
+    }
+
+    Ok(())
+}
@@ -1,12 +1,13 @@
 //! Wake-on-LAN packet parsing utilities.
 //!
-//! This module provides functionality to parse Wake-on-LAN (WOL) magic packets,
+//! This module provides functionality to parse and build Wake-on-LAN (WOL) magic packets,
 //! which are used to remotely wake up network devices. A valid WOL packet consists
 //! of 6 bytes of 0xFF followed by 16 repetitions of the target MAC address,
 //! optionally followed by a 4 or 6-byte password.
 
 use crate::error::WolGatewayError;
 use std::string::String;
+use subtle::ConstantTimeEq;
 
 /// Minimum size of a valid WOL packet in bytes (6 sync bytes + 16 * 6 MAC bytes).
 pub(crate) const WOL_PACKET_MIN_SIZE: usize = 102;
@@ -14,9 +15,44 @@ pub(crate) const WOL_PACKET_MIN_SIZE: usize = 102;
 /// Length of a MAC address in bytes.
 const MAC_ADDR_LEN: usize = 6;
 
+/// Length of a short SecureOn password in bytes.
+const SECUREON_SHORT_LEN: usize = 4;
+
+/// Length of a full SecureOn password in bytes.
+const SECUREON_LEN: usize = 6;
+
 /// Type alias for a 6-byte MAC address.
 pub(crate) type MacAddress = [u8; 6];
 
+/// A SecureOn password, either 4 or 6 bytes long.
+///
+/// The length is part of the password, so the 4-byte password "a.b.c.d" does
+/// not match the 6-byte password "a:b:c:d:00:00".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum SecureOnPassword {
+    /// A 4-byte password, usually written in dotted decimal notation.
+    Short([u8; SECUREON_SHORT_LEN]),
+    /// A 6-byte password, usually written as colon-separated hex bytes.
+    Full([u8; SECUREON_LEN]),
+}
+
+impl SecureOnPassword {
+    /// Returns the password bytes as carried by a magic packet.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            SecureOnPassword::Short(bytes) => bytes,
+            SecureOnPassword::Full(bytes) => bytes,
+        }
+    }
+
+    /// Checks whether two passwords are equal in constant time, so the time
+    /// taken does not reveal how much of a guessed password was right.
+    pub(crate) fn matches(&self, other: &SecureOnPassword) -> bool {
+        // Slices of different lengths compare unequal, the length is not secret
+        self.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+}
+
 /// Represents a parsed Wake-on-LAN magic packet.
 ///
 /// A WOL packet contains a synchronization stream of 6 0xFF bytes,
@@ -28,8 +64,8 @@ pub(crate) struct WakeOnLanPacket {
     _sync_stream: [u8; 6],
     /// Array of 16 identical MAC addresses.
     mac_addresses: [MacAddress; 16],
-    /// Optional 6-byte password (4-byte passwords are padded with zeros).
-    _password: Option<[u8; 6]>,
+    /// Optional 4 or 6-byte password.
+    password: Option<SecureOnPassword>,
 }
 
 /// Converts MAC address bytes to a colon-separated hexadecimal string.
@@ -48,6 +84,30 @@ pub(crate) fn mac_to_string(mac: &MacAddress) -> String {
         .join(":")
 }
 
+/// Builds a magic packet waking the device with the given MAC address.
+///
+/// This is the inverse of `WakeOnLanPacket::parse`: 6 bytes of 0xFF, followed by
+/// 16 repetitions of the MAC address and the SecureOn password, if any.
+///
+/// # Arguments
+///
+/// * `mac` - The target MAC address
+/// * `password` - An optional SecureOn password
+///
+/// # Returns
+///
+/// The raw packet, `WOL_PACKET_MIN_SIZE` bytes long plus the password
+pub(crate) fn build_magic_packet(mac: &MacAddress, password: Option<&SecureOnPassword>) -> Vec<u8> {
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    if let Some(password) = password {
+        packet.extend_from_slice(password.as_bytes());
+    }
+    packet
+}
+
 /// Parses a MAC address string and returns a MacAddress.
 ///
 /// # Arguments
@@ -88,6 +148,49 @@ pub(crate) fn parse_mac_address_string(mac_str: &str) -> Result<MacAddress, WolG
     Ok(mac)
 }
 
+/// Parses a SecureOn password string and returns a SecureOnPassword.
+///
+/// Two notations are accepted, matching common WOL tools such as `etherwake`:
+/// - 6-byte passwords as colon-separated hex bytes, e.g. "11:22:33:44:55:66"
+/// - 4-byte passwords in dotted decimal notation, e.g. "192.168.1.1"
+///
+/// # Arguments
+///
+/// * `password_str` - A string representation of the password
+///
+/// # Returns
+///
+/// `Result<SecureOnPassword, WolGatewayError>` if the string is valid, error otherwise
+pub(crate) fn parse_secureon_password_string(
+    password_str: &str,
+) -> Result<SecureOnPassword, WolGatewayError> {
+    if password_str.contains(':') {
+        return parse_mac_address_string(password_str).map(SecureOnPassword::Full);
+    }
+
+    let parts: Vec<&str> = password_str.split('.').collect();
+
+    if parts.len() != SECUREON_SHORT_LEN {
+        return Err(WolGatewayError::WakeOnLanParseError(format!(
+            "Invalid SecureOn password format '{}': expected xx:xx:xx:xx:xx:xx or a.b.c.d",
+            password_str
+        )));
+    }
+
+    let mut password = [0_u8; SECUREON_SHORT_LEN];
+
+    for (i, part) in parts.iter().enumerate() {
+        password[i] = part.parse::<u8>().map_err(|_| {
+            WolGatewayError::WakeOnLanParseError(format!(
+                "Invalid SecureOn password part '{}': each part must be a number from 0 to 255",
+                part
+            ))
+        })?;
+    }
+
+    Ok(SecureOnPassword::Short(password))
+}
+
 impl WakeOnLanPacket {
     /// Parses a raw packet and attempts to construct a `WakeOnLanPacket`.
     ///
@@ -95,7 +198,10 @@ impl WakeOnLanPacket {
     /// - At least 102 bytes long
     /// - Starts with 6 bytes of 0xFF (sync stream)
     /// - Contains 16 identical repetitions of a MAC address
-    /// - Optionally contains a 4 or 6-byte password at the end
+    /// - Optionally contains a 4 or 6-byte SecureOn password at the end
+    ///
+    /// Trailers of any other length are padding added by some senders and
+    /// are ignored, such packets carry no password.
     ///
     /// # Arguments
     ///
@@ -166,10 +272,28 @@ impl WakeOnLanPacket {
             // Copy the MAC into our array
             mac_addresses[i].copy_from_slice(mac_chunk);
         }
+
+        // A 4 or 6-byte trailer is the SecureOn password, anything else padding
+        let trailer = packet.get(WOL_PACKET_MIN_SIZE..).unwrap_or_default();
+        let password = match trailer.len() {
+            0 => None,
+            SECUREON_SHORT_LEN => {
+                let mut password = [0_u8; SECUREON_SHORT_LEN];
+                password.copy_from_slice(trailer);
+                Some(SecureOnPassword::Short(password))
+            }
+            SECUREON_LEN => {
+                let mut password = [0_u8; SECUREON_LEN];
+                password.copy_from_slice(trailer);
+                Some(SecureOnPassword::Full(password))
+            }
+            _ => None,
+        };
+
         Ok(WakeOnLanPacket {
             _sync_stream: sync_bytes,
             mac_addresses,
-            _password: None,
+            password,
         })
     }
 
@@ -181,4 +305,13 @@ impl WakeOnLanPacket {
     pub(crate) fn target_mac_string(&self) -> String {
         mac_to_string(&self.mac_addresses[0])
     }
+
+    /// Returns the SecureOn password carried by the packet, if any.
+    ///
+    /// # Returns
+    ///
+    /// The 4 or 6-byte password
+    pub(crate) fn password(&self) -> Option<&SecureOnPassword> {
+        self.password.as_ref()
+    }
 }
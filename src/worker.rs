@@ -0,0 +1,361 @@
+//! Dedicated thread running all blocking libvirt calls.
+//!
+//! The `virt` bindings are blocking, and starting a domain can take several
+//! seconds. Running those calls on the tokio runtime would stall packet
+//! reception while a VM boots, so the libvirt connection and the MAC index are
+//! owned by a worker thread instead. The processing loop hands wake requests to
+//! the worker through a bounded queue and keeps draining the listeners. Wake
+//! requests carry a channel the result is sent back on, to answer HTTP API
+//! clients and to stop deduplicating magic packets whose request failed.
+
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use tokio::sync::oneshot;
+use virt::connect::Connect;
+
+use crate::connection::LibvirtConnection;
+use crate::error::WolGatewayError;
+use crate::libvirt::{
+    find_and_start_vm_by_mac, find_and_start_vm_by_name, get_vm_status, ResolvedVm, VmStatus,
+};
+use crate::mac_index::MacIndex;
+use crate::metrics::{self, Outcome};
+use crate::policy::{Policy, WakeRequest, WakeTarget};
+
+/// Maximum number of wake requests waiting for the worker.
+const WORKER_QUEUE_SIZE: usize = 32;
+
+/// Channel the result of a job is sent back on.
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, WolGatewayError>>;
+
+/// A job handed to the worker.
+#[derive(Debug)]
+pub(crate) enum Job {
+    /// Wake the VM targeted by a request.
+    Wake(WakeJob),
+    /// Read the state of a VM.
+    Status(StatusJob),
+}
+
+impl Job {
+    /// Describes what the job is about, for logging.
+    fn target(&self) -> String {
+        match self {
+            Job::Wake(job) => job.request.target.to_string(),
+            Job::Status(job) => format!("state of domain {}", job.domain),
+        }
+    }
+
+    /// Sends an error back to whoever waits for the job, if anyone.
+    fn reject(self, error: WolGatewayError) {
+        // The receiver is gone if the client has already disconnected
+        match self {
+            Job::Wake(WakeJob {
+                reply: Some(reply), ..
+            }) => {
+                let _ = reply.send(Err(error));
+            }
+            Job::Wake(_) => {}
+            Job::Status(job) => {
+                let _ = job.reply.send(Err(error));
+            }
+        }
+    }
+}
+
+/// A wake request handed to the worker.
+#[derive(Debug)]
+pub(crate) struct WakeJob {
+    /// The request to resolve and authorize.
+    pub(crate) request: WakeRequest,
+    /// The policy that was live when the request was received.
+    pub(crate) policy: Arc<Policy>,
+    /// Where to send the result, if anyone waits for it.
+    pub(crate) reply: Option<Reply<Vec<ResolvedVm>>>,
+}
+
+/// A request for the state of a VM handed to the worker.
+#[derive(Debug)]
+pub(crate) struct StatusJob {
+    /// The name or UUID of the domain.
+    pub(crate) domain: String,
+    /// The policy that was live when the request was received.
+    pub(crate) policy: Arc<Policy>,
+    /// Where to send the result.
+    pub(crate) reply: Reply<VmStatus>,
+}
+
+/// Handle to the thread running all libvirt calls.
+#[derive(Debug)]
+pub(crate) struct LibvirtWorker {
+    /// Queue of jobs waiting for the worker.
+    jobs: SyncSender<Job>,
+    /// The worker thread, joined on shutdown.
+    thread: JoinHandle<()>,
+}
+
+impl LibvirtWorker {
+    /// Starts the worker thread, handing it the libvirt connection and MAC index.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The managed libvirt connection
+    /// * `index` - The MAC address index used to resolve target VMs
+    /// * `keepalive_interval` - Interval between libvirt connection health checks
+    ///
+    /// # Errors
+    ///
+    /// Returns `WorkerSpawnError` if the thread could not be started.
+    pub(crate) fn spawn(
+        connection: LibvirtConnection,
+        index: MacIndex,
+        keepalive_interval: Duration,
+    ) -> Result<Self, WolGatewayError> {
+        let (jobs, queue) = sync_channel(WORKER_QUEUE_SIZE);
+
+        let thread = thread::Builder::new()
+            .name("libvirt-worker".to_string())
+            .spawn(move || run(connection, index, queue, keepalive_interval))
+            .map_err(WolGatewayError::WorkerSpawnError)?;
+
+        Ok(LibvirtWorker { jobs, thread })
+    }
+
+    /// Queues a job without waiting for the worker.
+    ///
+    /// A job that cannot be queued is answered with the same error that is
+    /// returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WorkerQueueFull` if too many requests are pending, or
+    /// `WorkerStopped` if the worker thread has exited.
+    pub(crate) fn submit(&self, job: Job) -> Result<(), WolGatewayError> {
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) => {
+                let target = job.target();
+                job.reject(WolGatewayError::WorkerQueueFull(target.clone()));
+                WolGatewayError::WorkerQueueFull(target)
+            }
+            TrySendError::Disconnected(job) => {
+                job.reject(WolGatewayError::WorkerStopped);
+                WolGatewayError::WorkerStopped
+            }
+        })
+    }
+
+    /// Stops the worker once all queued requests are processed.
+    ///
+    /// The worker is joined on a separate plain thread, so the caller can stop
+    /// waiting for it without anything blocking the async runtime or its
+    /// shutdown.
+    ///
+    /// # Returns
+    ///
+    /// A channel signalled once the worker has exited. It is closed without a
+    /// value if the worker panicked.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WorkerSpawnError` if the joining thread could not be started.
+    pub(crate) fn shutdown(self) -> Result<oneshot::Receiver<()>, WolGatewayError> {
+        let LibvirtWorker { jobs, thread } = self;
+        drop(jobs);
+
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        thread::Builder::new()
+            .name("libvirt-worker-join".to_string())
+            .spawn(move || {
+                if thread.join().is_err() {
+                    warn!("Libvirt worker panicked");
+                } else {
+                    let _ = stopped_tx.send(());
+                }
+            })
+            .map_err(WolGatewayError::WorkerSpawnError)?;
+
+        Ok(stopped_rx)
+    }
+}
+
+/// Processes jobs until the queue is closed and drained.
+///
+/// Between requests, the connection is checked every keepalive interval and
+/// re-established with exponential backoff if libvirtd went away, and the MAC
+/// index is rebuilt once it reaches its maximum age or was invalidated.
+fn run(
+    mut connection: LibvirtConnection,
+    mut index: MacIndex,
+    queue: Receiver<Job>,
+    keepalive_interval: Duration,
+) {
+    let mut next_check = Instant::now() + keepalive_interval;
+    // Earliest time to retry a failed background rebuild of the index
+    let mut refresh_retry = Instant::now();
+
+    loop {
+        let deadline = next_check.min(index.refresh_at().max(refresh_retry));
+        match queue.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Job::Wake(job)) => wake(&mut connection, &mut index, job),
+            Ok(Job::Status(job)) => status(&mut connection, &mut index, job),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if Instant::now() >= next_check {
+            connection.check_health();
+            if connection.take_reconnected() {
+                index.invalidate();
+            }
+            next_check = Instant::now() + keepalive_interval;
+        }
+
+        if Instant::now() >= index.refresh_at().max(refresh_retry) {
+            debug!("Rebuilding MAC address index in the background");
+            if let Err(e) =
+                connect(&mut connection, &mut index).and_then(|conn| index.rebuild(conn))
+            {
+                warn!("Failed to rebuild MAC address index: {}", e);
+                refresh_retry = Instant::now() + keepalive_interval;
+            }
+        }
+    }
+
+    connection.disconnect();
+    info!("Libvirt worker finished all queued requests and closed the connection");
+}
+
+/// Resolves, authorizes and starts the VM targeted by a single wake request.
+fn wake(connection: &mut LibvirtConnection, index: &mut MacIndex, job: WakeJob) {
+    let WakeJob {
+        request,
+        policy,
+        reply,
+    } = job;
+
+    let result = with_reconnect(connection, index, |conn, index| match &request.target {
+        WakeTarget::Mac(mac) => find_and_start_vm_by_mac(conn, index, mac, &request, &policy),
+        WakeTarget::Domain(domain) => {
+            find_and_start_vm_by_name(conn, domain, &request, &policy).map(|vm| vec![vm])
+        }
+    });
+
+    match &result {
+        Ok(vms) if policy.dry_run => {
+            for vm in vms {
+                info!(
+                    "Dry run for {}, VM {} would {}",
+                    request.target, vm.name, vm.action
+                );
+            }
+            metrics::record_outcome(Outcome::DryRun);
+        }
+        Ok(vms) => {
+            for vm in vms {
+                info!("Successfully started VM {} for {}", vm.name, request.target);
+            }
+            metrics::record_outcome(Outcome::Started);
+        }
+        Err(e @ WolGatewayError::SourceNotAllowed(_)) => {
+            crate::server::record_denied(&request.source, e);
+        }
+        Err(e) => {
+            warn!("Failed to start VM for {}: {}", request.target, e);
+            metrics::record_error(e);
+            metrics::record_outcome(match e {
+                WolGatewayError::VmNotFound(_) | WolGatewayError::DomainNotFound(_) => {
+                    Outcome::NotFound
+                }
+                WolGatewayError::AmbiguousMac(..)
+                | WolGatewayError::PasswordMismatch(_)
+                | WolGatewayError::VmDisabled(_)
+                | WolGatewayError::VmNotWakeable(_)
+                | WolGatewayError::CrashedNotRestarted(_)
+                | WolGatewayError::WakeupUnsupported(_)
+                | WolGatewayError::MetadataPolicyError(..) => Outcome::Rejected,
+                _ => Outcome::Failed,
+            });
+        }
+    }
+
+    if let Some(reply) = reply {
+        // The receiver is gone if the client has already disconnected
+        let _ = reply.send(result);
+    }
+}
+
+/// Reads the state of the VM requested over the HTTP API.
+fn status(connection: &mut LibvirtConnection, index: &mut MacIndex, job: StatusJob) {
+    let StatusJob {
+        domain,
+        policy,
+        reply,
+    } = job;
+
+    let result = with_reconnect(connection, index, |conn, _| {
+        get_vm_status(conn, &domain, &policy)
+    });
+
+    if let Err(e) = &result {
+        debug!("Failed to get state of domain {}: {}", domain, e);
+        metrics::record_error(e);
+    }
+
+    let _ = reply.send(result);
+}
+
+/// Runs a libvirt operation, retrying it once if the connection was lost.
+///
+/// A request failing because libvirtd just restarted would otherwise only be
+/// noticed by the next keepalive check, and the client's retries would fail as
+/// well until then. If the operation fails with a connection error, the
+/// connection is checked right away with `is_alive` and a cheap remote call.
+/// Only if it was found dead and could be re-established is the MAC index
+/// invalidated and the operation run again.
+///
+/// # Arguments
+///
+/// * `connection` - The managed libvirt connection
+/// * `index` - The MAC address index, invalidated after a reconnection
+/// * `operation` - The operation to run with the live connection
+fn with_reconnect<T>(
+    connection: &mut LibvirtConnection,
+    index: &mut MacIndex,
+    mut operation: impl FnMut(&Connect, &mut MacIndex) -> Result<T, WolGatewayError>,
+) -> Result<T, WolGatewayError> {
+    match connect(connection, index).and_then(|conn| operation(conn, index)) {
+        Err(e) if e.is_connection_error() => {
+            warn!("{}, checking the libvirt connection", e);
+            connection.check_health();
+            if !connection.take_reconnected() {
+                return Err(e);
+            }
+            index.invalidate();
+            info!("Reconnected to libvirt, retrying the request");
+            connect(connection, index).and_then(|conn| operation(conn, index))
+        }
+        result => result,
+    }
+}
+
+/// Returns the live connection, invalidating the MAC index if it had to be
+/// re-established.
+///
+/// # Errors
+///
+/// Returns `LibvirtUnavailable` if there is no live connection and none could
+/// be established.
+fn connect<'a>(
+    connection: &'a mut LibvirtConnection,
+    index: &mut MacIndex,
+) -> Result<&'a Connect, WolGatewayError> {
+    connection.get()?;
+    if connection.take_reconnected() {
+        index.invalidate();
+    }
+    connection.get()
+}